@@ -0,0 +1,179 @@
+use std::{
+    env,
+    net::{SocketAddr, TcpListener, TcpStream},
+    process::{Child, Command},
+    thread,
+    time::Duration,
+};
+
+use criterion::{criterion_group, BenchmarkId, Criterion, Throughput};
+use futures::future::join_all;
+use http_types::{Method, Request, Response, StatusCode, Url};
+use smol::{Async, Task};
+use web_jingzi::server::Server;
+
+const BODY_SIZE: usize = 64 * 1024;
+// requests at once, for throughput under load
+const CLIENTS: usize = 32;
+// executor threads of mirrors run by Server::run
+const THREADS: &[usize] = &[1, 2, 4, 8];
+// set to `origin listen threads` when the bench is run again as a mirror
+const WORKER: &str = "WEB_JINGZI_BENCH_WORKER";
+
+// a page of links back to origin, and a body not rewritten
+fn respond(req: Request, origin: SocketAddr) -> Response {
+    let mut resp = Response::new(StatusCode::Ok);
+    match req.url().path() {
+        "/page" => {
+            let link = format!("<a href=\"http://{}/page\">page</a>\n", origin);
+            let mut page = link.repeat(BODY_SIZE / link.len() + 1);
+            page.truncate(BODY_SIZE);
+            resp.insert_header("content-type", "text/html; charset=utf-8");
+            resp.set_body(page);
+        }
+        _ => {
+            resp.insert_header("content-type", "application/octet-stream");
+            resp.set_body(vec![0u8; BODY_SIZE]);
+        }
+    }
+    resp
+}
+
+// an origin on an ephemeral port, served by a thread of its own
+fn origin() -> SocketAddr {
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let listener = Async::<TcpListener>::bind(addr).unwrap();
+    let addr = listener.get_ref().local_addr().unwrap();
+    thread::spawn(move || {
+        smol::run(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let stream = async_dup::Arc::new(stream);
+                let task = Task::spawn(async move {
+                    let endpoint = |req| async move { Ok(respond(req, addr)) };
+                    let _ = async_h1::accept(stream, endpoint).await;
+                });
+                task.detach();
+            }
+        })
+    });
+    addr
+}
+
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|i| i.local_addr())
+        .unwrap()
+}
+
+fn wait_for(addr: SocketAddr) {
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// a mirror of origin as `mirror.test`, the address it listens on is returned
+// once it accepts connections
+fn proxy(origin: SocketAddr) -> SocketAddr {
+    let addr = free_addr();
+    let server = Server::builder()
+        .domain("mirror.test", &format!("http://{}", origin))
+        .listen(&addr.to_string())
+        .build()
+        .unwrap();
+    thread::spawn(move || smol::run(server.start()).unwrap());
+    wait_for(addr);
+    addr
+}
+
+// a mirror run by Server::run in a process of its own, tasks are run by every
+// thread of a process, only its own threads serve it there
+struct Worker {
+    child: Child,
+    addr: SocketAddr,
+}
+
+impl Worker {
+    fn spawn(origin: SocketAddr, threads: usize) -> Worker {
+        let addr = free_addr();
+        let child = Command::new(env::current_exe().unwrap())
+            .env(WORKER, format!("{} {} {}", origin, addr, threads))
+            .spawn()
+            .unwrap();
+        wait_for(addr);
+        Worker { child, addr }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// the mirror of a worker process, until it's killed
+fn work(spec: &str) {
+    let spec: Vec<_> = spec.split(' ').collect();
+    let mut builder = Server::builder()
+        .domain("mirror.test", &format!("http://{}", spec[0]))
+        .listen(spec[1]);
+    builder.config_mut().threads = Some(spec[2].parse().unwrap());
+    builder.build().unwrap().run().unwrap();
+}
+
+async fn get(proxy: SocketAddr, path: &str) {
+    let stream = Async::<TcpStream>::connect(proxy).await.unwrap();
+    let url = Url::parse(&format!("http://mirror.test{}", path)).unwrap();
+    let mut req = Request::new(Method::Get, url);
+    req.insert_header("accept-encoding", "identity");
+    let mut resp = async_h1::connect(stream, req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::Ok);
+    resp.body_bytes().await.unwrap();
+}
+
+fn bench(c: &mut Criterion) {
+    let proxy = proxy(origin());
+    let mut group = c.benchmark_group("proxy");
+    group.throughput(Throughput::Bytes(BODY_SIZE as u64));
+    for path in &["/page", "/blob"] {
+        let id = BenchmarkId::new("request", path);
+        group.bench_with_input(id, path, |b, path| b.iter(|| smol::run(get(proxy, path))));
+    }
+    group.throughput(Throughput::Elements(CLIENTS as u64));
+    for path in &["/page", "/blob"] {
+        let id = BenchmarkId::new(format!("{}_clients", CLIENTS), path);
+        group.bench_with_input(id, path, |b, path| {
+            b.iter(|| smol::run(join_all((0..CLIENTS).map(|_| get(proxy, path)))))
+        });
+    }
+    group.finish();
+}
+
+// requests at once to mirrors of more threads
+fn bench_threads(c: &mut Criterion) {
+    let origin = origin();
+    let mut group = c.benchmark_group("threads");
+    group.throughput(Throughput::Elements(CLIENTS as u64));
+    for threads in THREADS {
+        let worker = Worker::spawn(origin, *threads);
+        for path in &["/page", "/blob"] {
+            let id = BenchmarkId::new(format!("{}_threads", threads), path);
+            group.bench_with_input(id, path, |b, path| {
+                b.iter(|| smol::run(join_all((0..CLIENTS).map(|_| get(worker.addr, path)))))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench, bench_threads);
+
+fn main() {
+    if let Ok(spec) = env::var(WORKER) {
+        work(&spec);
+        return;
+    }
+    benches();
+    Criterion::default().configure_from_args().final_summary();
+}
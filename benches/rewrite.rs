@@ -0,0 +1,61 @@
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::io::{AsyncReadExt, Cursor};
+use web_jingzi::rewrite::{encoded_forms, Replacements, Rewriter};
+
+// text with a link to origin every 100 bytes or so
+fn page(size: usize) -> Vec<u8> {
+    let chunk = concat!(
+        "<p>lorem ipsum dolor sit amet</p>",
+        "<a href=\"https://www.example.com/a/b?c=d\">link</a>\n"
+    );
+    let mut page = chunk.repeat(size / chunk.len() + 1).into_bytes();
+    page.truncate(size);
+    page
+}
+
+// of domains like those of a config, in every encoded form
+fn replacements(domains: usize) -> Arc<Replacements> {
+    let mut pairs = encoded_forms("www.example.com", "www.mirror.test");
+    for i in 1..domains {
+        let from = format!("www{}.example.org", i);
+        let to = format!("www{}.mirror.test", i);
+        pairs.extend(encoded_forms(&from, &to));
+    }
+    Arc::new(Replacements::new(pairs))
+}
+
+fn rewrite(body: &[u8], replacements: Arc<Replacements>) -> Vec<u8> {
+    smol::run(async {
+        let mut rewriter = Rewriter::new(Cursor::new(body), replacements);
+        let mut rewritten = Vec::with_capacity(body.len());
+        rewriter.read_to_end(&mut rewritten).await.unwrap();
+        rewritten
+    })
+}
+
+fn bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rewrite");
+    for size in &[16 * 1024, 1024 * 1024] {
+        let body = page(*size);
+        group.throughput(Throughput::Bytes(*size as u64));
+        for domains in &[1, 100] {
+            let replacements = replacements(*domains);
+            let id = BenchmarkId::new(format!("{}_domains", domains), size);
+            group.bench_with_input(id, &body, |b, body| {
+                b.iter(|| rewrite(body, replacements.clone()))
+            });
+        }
+        // what streaming costs over one pass of the whole body
+        let replacements = replacements(1);
+        let id = BenchmarkId::new("whole", size);
+        group.bench_with_input(id, &body, |b, body| {
+            b.iter(|| replacements.replace_bytes(body))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
@@ -0,0 +1,71 @@
+#![no_main]
+
+mod common;
+
+use async_compression::futures::bufread::{
+    BrotliDecoder, BrotliEncoder, DeflateDecoder, DeflateEncoder, GzipDecoder, GzipEncoder,
+    ZstdDecoder, ZstdEncoder,
+};
+use futures::{
+    executor::block_on,
+    io::{AsyncBufRead, AsyncRead, AsyncReadExt, BufReader},
+};
+use libfuzzer_sys::fuzz_target;
+use web_jingzi::rewrite::Rewriter;
+
+use common::replacements;
+
+const ENCODINGS: &[&str] = &["gzip", "br", "deflate", "zstd"];
+// bytes read of a body that is not encoded by target, as it may decode to a bomb
+const MAX_DECODED: u64 = 16 * 1024 * 1024;
+
+fn decoder<'a, R>(encoding: &str, inner: R) -> Box<dyn AsyncRead + Unpin + 'a>
+where
+    R: AsyncBufRead + Unpin + 'a,
+{
+    match encoding {
+        "gzip" => Box::new(GzipDecoder::new(inner)),
+        "br" => Box::new(BrotliDecoder::new(inner)),
+        "deflate" => Box::new(DeflateDecoder::new(inner)),
+        _ => Box::new(ZstdDecoder::new(inner)),
+    }
+}
+
+fn encoder<'a, R>(encoding: &str, inner: R) -> Box<dyn AsyncRead + Unpin + 'a>
+where
+    R: AsyncBufRead + Unpin + 'a,
+{
+    match encoding {
+        "gzip" => Box::new(GzipEncoder::new(inner)),
+        "br" => Box::new(BrotliEncoder::new(inner)),
+        "deflate" => Box::new(DeflateEncoder::new(inner)),
+        _ => Box::new(ZstdEncoder::new(inner)),
+    }
+}
+
+// the first byte picks the encoding, and whether body is encoded first or
+// taken as what target sent
+fuzz_target!(|data: &[u8]| {
+    let (mode, body) = match data.split_first() {
+        Some((mode, body)) => (*mode, body),
+        None => return,
+    };
+    let encoding = ENCODINGS[(mode & 3) as usize];
+    let replacements = replacements();
+    if mode & 4 != 0 {
+        // decoded, rewritten and encoded again like a response, then decoded
+        // as a client would
+        let mut encoded = Vec::new();
+        block_on(encoder(encoding, body).read_to_end(&mut encoded)).unwrap();
+        let rewriter = Rewriter::new(decoder(encoding, &encoded[..]), replacements.clone());
+        let encoder = encoder(encoding, BufReader::new(rewriter));
+        let mut decoded = Vec::new();
+        block_on(decoder(encoding, BufReader::new(encoder)).read_to_end(&mut decoded)).unwrap();
+        assert_eq!(decoded, replacements.replace_bytes(body));
+    } else {
+        // errors, but no panics
+        let rewriter = Rewriter::new(decoder(encoding, body), replacements);
+        let mut decoded = Vec::new();
+        let _ = block_on(rewriter.take(MAX_DECODED).read_to_end(&mut decoded));
+    }
+});
@@ -0,0 +1,74 @@
+#![allow(dead_code)]
+
+use std::{
+    cell::Cell,
+    pin::Pin,
+    rc::Rc,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures::{
+    executor::block_on,
+    io::{AsyncRead, AsyncReadExt},
+};
+use web_jingzi::rewrite::{encoded_forms, Replacements};
+
+// body repeated, at most size bytes a read, like a body of target coming in
+// pieces, read counts the bytes given
+pub struct Chunks {
+    body: Vec<u8>,
+    pos: usize,
+    size: usize,
+    pub read: Rc<Cell<usize>>,
+}
+
+impl Chunks {
+    pub fn new(body: &[u8], repeat: usize, size: usize) -> Chunks {
+        Chunks {
+            body: body.repeat(repeat),
+            pos: 0,
+            size: size.max(1),
+            read: Rc::new(Cell::new(0)),
+        }
+    }
+}
+
+impl AsyncRead for Chunks {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let n = buf.len().min(self.size).min(self.body.len() - self.pos);
+        let pos = self.pos;
+        buf[..n].copy_from_slice(&self.body[pos..pos + n]);
+        self.pos += n;
+        self.read.set(self.read.get() + n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+// of the same length either way, so what a rewriter holds back is what it
+// read less what it gave
+pub fn replacements() -> Arc<Replacements> {
+    let mut pairs = encoded_forms("https://www.example.com", "https://mirror.test.xyz");
+    pairs.extend(encoded_forms("cdn.example.org", "cdn0.mirror.xyz"));
+    Arc::new(Replacements::new(pairs))
+}
+
+// all of reader in small reads, after each of them no more than held bytes
+// are read but not given
+pub fn read_all<R: AsyncRead + Unpin>(mut reader: R, read: &Cell<usize>, held: usize) -> Vec<u8> {
+    let mut all = Vec::new();
+    let mut buf = [0; 512];
+    loop {
+        let n = block_on(reader.read(&mut buf)).unwrap();
+        if n == 0 {
+            return all;
+        }
+        all.extend_from_slice(&buf[..n]);
+        let kept = read.get().saturating_sub(all.len());
+        assert!(kept <= held, "{} bytes held back", kept);
+    }
+}
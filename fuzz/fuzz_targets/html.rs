@@ -0,0 +1,39 @@
+#![no_main]
+
+mod common;
+
+use libfuzzer_sys::fuzz_target;
+use web_jingzi::html::HtmlRewriter;
+
+use common::{read_all, replacements, Chunks};
+
+// an unclosed tag of HtmlRewriter, and a chunk
+const HELD: usize = 64 * 1024 + 8 * 1024;
+
+// the first bytes are the size of reads, the times body is repeated, and
+// flags of stripping and json rewriting
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 3 {
+        return;
+    }
+    let (size, repeat, flags) = (data[0] as usize + 1, data[1] as usize + 1, data[2]);
+    let chunks = Chunks::new(&data[3..], repeat, size);
+    let read = chunks.read.clone();
+    let rewriter = HtmlRewriter::new(chunks, |url| {
+        if url.starts_with('/') && !url.starts_with("//") {
+            Some(format!("/prefix{}", url))
+        } else {
+            None
+        }
+    });
+    let mut rewriter = rewriter
+        .strip_integrity(flags & 1 != 0)
+        .strip_canonical(flags & 2 != 0);
+    if flags & 4 != 0 {
+        rewriter = rewriter.json_replacements(replacements());
+    }
+    // urls only get longer unless something is stripped, or json rewritten
+    // and held up to its end
+    let held = if flags & 7 == 0 { HELD } else { usize::MAX };
+    read_all(rewriter, &read, held);
+});
@@ -0,0 +1,33 @@
+#![no_main]
+
+mod common;
+
+use libfuzzer_sys::fuzz_target;
+use web_jingzi::rewrite::Rewriter;
+
+use common::{read_all, replacements, Chunks};
+
+// a chunk of Rewriter, and the tail of a match across chunks
+const HELD: usize = 8 * 1024 + 64;
+
+// the first bytes are the size of reads and the times body is repeated
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+    let (size, repeat, body) = (data[0] as usize + 1, data[1] as usize + 1, &data[2..]);
+    let replacements = replacements();
+    let whole = replacements.replace_bytes(&body.repeat(repeat));
+
+    // streaming finds what a pass over the whole body does
+    let chunks = Chunks::new(body, repeat, size);
+    let read = chunks.read.clone();
+    let rewriter = Rewriter::new(chunks, replacements.clone());
+    assert_eq!(read_all(rewriter, &read, HELD), whole);
+
+    // a line is held until it ends
+    let chunks = Chunks::new(body, repeat, size);
+    let read = chunks.read.clone();
+    let rewriter = Rewriter::by_line(chunks, replacements);
+    assert_eq!(read_all(rewriter, &read, usize::MAX), whole);
+});
@@ -0,0 +1,225 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use futures::io::AsyncRead;
+use http_types::{Body, Request, Response};
+use serde::Serialize;
+
+use crate::{
+    config::{AccessLogConfig, AccessLogFormat},
+    request_id::RequestId,
+};
+
+// origin of target, inserted into extensions of response
+pub struct Upstream(pub String);
+
+#[derive(Serialize)]
+pub struct Entry {
+    time: DateTime<Utc>,
+    client: String,
+    method: String,
+    host: String,
+    path: String,
+    target: String,
+    status: u16,
+    bytes: u64,
+    duration_ms: u128,
+    referer: String,
+    user_agent: String,
+    request_id: String,
+    #[serde(skip)]
+    start: Instant,
+}
+
+impl Entry {
+    pub fn new(req: &Request, client: SocketAddr) -> Entry {
+        let url = req.url();
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        let header = |name| {
+            req.header(name)
+                .map(|i| i.as_str().to_string())
+                .unwrap_or_default()
+        };
+        Entry {
+            time: Utc::now(),
+            client: client.ip().to_string(),
+            method: req.method().to_string(),
+            host: url.host_str().unwrap_or_default().to_string(),
+            path,
+            target: String::new(),
+            status: 0,
+            bytes: 0,
+            duration_ms: 0,
+            referer: header("referer"),
+            user_agent: header("user-agent"),
+            request_id: req
+                .ext()
+                .get::<RequestId>()
+                .map(|i| i.0.clone())
+                .unwrap_or_default(),
+            start: Instant::now(),
+        }
+    }
+}
+
+enum Output {
+    Stdout,
+    File { file: File, size: u64 },
+}
+
+pub struct AccessLog {
+    format: AccessLogFormat,
+    path: String,
+    rotate_size: Option<u64>,
+    rotate_keep: usize,
+    output: Mutex<Output>,
+}
+
+impl AccessLog {
+    pub fn new(config: &AccessLogConfig) -> Result<AccessLog> {
+        let output = if config.path == "stdout" {
+            Output::Stdout
+        } else {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&config.path)?;
+            let size = file.metadata()?.len();
+            Output::File { file, size }
+        };
+        Ok(AccessLog {
+            format: config.format,
+            path: config.path.clone(),
+            rotate_size: config.rotate_size,
+            rotate_keep: config.rotate_keep,
+            output: Mutex::new(output),
+        })
+    }
+
+    // log after the body of response was sent, or the client went away
+    pub fn record(self: &Arc<Self>, mut entry: Entry, resp: &mut Response) {
+        entry.status = resp.status().into();
+        if let Some(Upstream(target)) = resp.ext().get() {
+            entry.target = target.clone();
+        }
+        let body = resp.take_body();
+        let len = body.len();
+        let counter = Counter {
+            inner: body,
+            log: self.clone(),
+            entry: Some(entry),
+        };
+        let counter = async_std::io::BufReader::new(counter);
+        resp.set_body(Body::from_reader(counter, len));
+    }
+
+    fn log(&self, mut entry: Entry) {
+        entry.duration_ms = entry.start.elapsed().as_millis();
+        let line = match self.format {
+            AccessLogFormat::Combined => format!(
+                "{} - - [{}] \"{} {} HTTP/1.1\" {} {} \"{}\" \"{}\" {} {} {}\n",
+                entry.client,
+                entry.time.format("%d/%b/%Y:%H:%M:%S %z"),
+                entry.method,
+                entry.path,
+                entry.status,
+                entry.bytes,
+                entry.referer,
+                entry.user_agent,
+                if entry.target.is_empty() { "-" } else { entry.target.as_str() },
+                entry.duration_ms,
+                entry.request_id,
+            ),
+            AccessLogFormat::Json => match serde_json::to_string(&entry) {
+                Ok(line) => line + "\n",
+                Err(err) => {
+                    error!("access log error: {}", err);
+                    return;
+                }
+            },
+        };
+        if let Err(err) = self.write(line.as_bytes()) {
+            error!("access log error: {}", err);
+        }
+    }
+
+    fn write(&self, line: &[u8]) -> Result<()> {
+        let mut output = self.output.lock().unwrap();
+        match &mut *output {
+            Output::Stdout => std::io::stdout().write_all(line)?,
+            Output::File { file, size } => {
+                if let Some(rotate_size) = self.rotate_size {
+                    if *size + line.len() as u64 > rotate_size {
+                        *file = self.rotate()?;
+                        *size = 0;
+                    }
+                }
+                file.write_all(line)?;
+                *size += line.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    // access.log -> access.log.1 -> access.log.2 ...
+    fn rotate(&self) -> Result<File> {
+        for i in (1..self.rotate_keep).rev() {
+            let from = format!("{}.{}", self.path, i);
+            if fs::metadata(&from).is_ok() {
+                fs::rename(&from, format!("{}.{}", self.path, i + 1))?;
+            }
+        }
+        if self.rotate_keep > 0 {
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        Ok(file)
+    }
+}
+
+struct Counter<R> {
+    inner: R,
+    log: Arc<AccessLog>,
+    entry: Option<Entry>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Counter<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let n = match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(n)) => n,
+            other => return other,
+        };
+        if let Some(entry) = &mut self.entry {
+            entry.bytes += n as u64;
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<R> Drop for Counter<R> {
+    fn drop(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            self.log.log(entry);
+        }
+    }
+}
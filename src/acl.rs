@@ -0,0 +1,115 @@
+use std::net::IpAddr;
+
+use anyhow::{anyhow, Result};
+use http_types::{Request, Response, StatusCode};
+
+// `10.0.0.0/8`, `::1/128` or a single address
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    pub fn parse(s: &str) -> Result<Cidr> {
+        let (addr, prefix) = match s.find('/') {
+            Some(i) => (&s[..i], Some(s[i + 1..].parse()?)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse()?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            return Err(anyhow!("invalid cidr: {}", s));
+        }
+        Ok(Cidr { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) if self.addr.is_ipv4() => match v6.to_ipv4() {
+                Some(v4) => IpAddr::V4(v4),
+                None => return false,
+            },
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+pub struct Acl {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl Acl {
+    // None if both are empty
+    pub fn new(allow: &[String], deny: &[String]) -> Result<Option<Acl>> {
+        if allow.is_empty() && deny.is_empty() {
+            return Ok(None);
+        }
+        let parse = |list: &[String]| list.iter().map(|i| Cidr::parse(i)).collect::<Result<_>>();
+        Ok(Some(Acl {
+            allow: parse(allow)?,
+            deny: parse(deny)?,
+        }))
+    }
+
+    // deny takes precedence, everything is allowed if allow is empty
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|i| i.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|i| i.contains(ip))
+    }
+}
+
+pub struct Auth {
+    // values of Authorization accepted
+    accepted: Vec<String>,
+    basic: bool,
+}
+
+impl Auth {
+    // basic is a list of `user:password`, None if both are empty
+    pub fn new(basic: &[String], bearer: &[String]) -> Option<Auth> {
+        if basic.is_empty() && bearer.is_empty() {
+            return None;
+        }
+        let basic_accepted = basic
+            .iter()
+            .map(|i| format!("Basic {}", base64::encode(i)));
+        let bearer_accepted = bearer.iter().map(|i| format!("Bearer {}", i));
+        Some(Auth {
+            accepted: basic_accepted.chain(bearer_accepted).collect(),
+            basic: !basic.is_empty(),
+        })
+    }
+
+    // None if authorized, or a 401 response
+    pub fn check(&self, req: &Request) -> Option<Response> {
+        let authorization = req.header("authorization").map(|i| i.as_str().trim());
+        if let Some(authorization) = authorization {
+            if self.accepted.iter().any(|i| i == authorization) {
+                return None;
+            }
+        }
+        let mut resp = Response::new(StatusCode::Unauthorized);
+        let challenge = if self.basic {
+            "Basic realm=\"web-jingzi\""
+        } else {
+            "Bearer"
+        };
+        resp.insert_header("www-authenticate", challenge);
+        Some(resp)
+    }
+}
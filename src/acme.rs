@@ -0,0 +1,165 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::{Arc, Mutex, RwLock, Weak},
+    time::Duration,
+};
+
+use acme_lib::{
+    create_p384_key, order::NewOrder, persist::FilePersist, Certificate, Directory, DirectoryUrl,
+};
+use anyhow::{anyhow, Result};
+use async_native_tls::TlsAcceptor;
+use http_types::{Request, Response, StatusCode};
+
+use crate::{
+    config::{AcmeConfig, Config},
+    tls,
+};
+
+const CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+// after a failed order
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+// rounds of validating and refreshing an order
+const MAX_ROUNDS: usize = 5;
+// milliseconds between polls of validation and finalization
+const POLL_INTERVAL: u64 = 5000;
+
+// a certificate of mirror domains from let's encrypt by http-01 challenges,
+// which are answered on listen_address, where port 80 of the domains must
+// reach, all of it is blocking
+pub struct Acme {
+    config: AcmeConfig,
+    domains: Vec<String>,
+    // token to proof of challenges being validated
+    challenges: Mutex<HashMap<String, String>>,
+}
+
+impl Acme {
+    pub fn new(config: &Config, acme: &AcmeConfig) -> Result<Acme> {
+        let mut domains = if acme.domains.is_empty() {
+            mirror_domains(config)
+        } else {
+            acme.domains.clone()
+        };
+        domains.sort();
+        domains.dedup();
+        if domains.is_empty() {
+            return Err(anyhow!("no domain for acme"));
+        }
+        Ok(Acme {
+            config: acme.clone(),
+            domains,
+            challenges: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // proof of a challenge being validated
+    pub fn respond(&self, req: &Request) -> Option<Response> {
+        let token = req.url().path().strip_prefix(CHALLENGE_PREFIX)?;
+        let proof = self.challenges.lock().unwrap().get(token)?.clone();
+        let mut resp = Response::new(StatusCode::Ok);
+        resp.insert_header("content-type", "text/plain");
+        resp.set_body(proof);
+        Some(resp)
+    }
+
+    // the stored certificate, ordered again if missing, expiring or of other
+    // domains, and whether it is new
+    fn certificate(&self) -> Result<(Certificate, bool)> {
+        fs::create_dir_all(&self.config.dir)?;
+        let url = if self.config.staging {
+            DirectoryUrl::LetsEncryptStaging
+        } else {
+            DirectoryUrl::LetsEncrypt
+        };
+        let directory = Directory::from_url(FilePersist::new(&self.config.dir), url)?;
+        let account = directory.account(&self.config.email)?;
+        // names are kept besides, acme-lib stores certificates by the first one
+        let names = Path::new(&self.config.dir).join("domains");
+        let same = fs::read_to_string(&names).map_or(false, |i| i == self.domains.join("\n"));
+        let (primary, alt) = self.domains.split_first().unwrap();
+        if let Some(cert) = account.certificate(primary)? {
+            if same && cert.valid_days_left() > self.config.renew_days as i64 {
+                return Ok((cert, false));
+            }
+        }
+        let alt: Vec<_> = alt.iter().map(|i| i.as_str()).collect();
+        let order = account.new_order(primary, &alt)?;
+        let cert = self.validate(order);
+        self.challenges.lock().unwrap().clear();
+        let cert = cert?;
+        fs::write(&names, self.domains.join("\n"))?;
+        Ok((cert, true))
+    }
+
+    fn validate(&self, mut order: NewOrder<FilePersist>) -> Result<Certificate> {
+        for _ in 0..MAX_ROUNDS {
+            if let Some(csr) = order.confirm_validations() {
+                let cert = csr.finalize_pkey(create_p384_key(), POLL_INTERVAL)?;
+                return Ok(cert.download_and_save_cert()?);
+            }
+            for auth in order.authorizations()? {
+                if !auth.need_challenge() {
+                    continue;
+                }
+                let challenge = auth.http_challenge();
+                let token = challenge.http_token().to_string();
+                let proof = challenge.http_proof();
+                self.challenges.lock().unwrap().insert(token, proof);
+                challenge.validate(POLL_INTERVAL)?;
+            }
+            order.refresh()?;
+        }
+        Err(anyhow!(
+            "validations of {} not confirmed",
+            self.domains.join(", ")
+        ))
+    }
+}
+
+// names of domain_name, those of routes without path, but wildcard ones
+// which need dns challenges
+fn mirror_domains(config: &Config) -> Vec<String> {
+    config
+        .domain_name
+        .keys()
+        .map(|i| i.split('/').next().unwrap_or_default().to_string())
+        .filter(|i| !i.is_empty() && !i.starts_with('*'))
+        .collect()
+}
+
+// keep acceptor with a fresh certificate, checked at once and twice a day,
+// until acceptor is gone with its server
+pub fn watch(acme: Arc<Acme>, acceptor: Weak<RwLock<Option<TlsAcceptor>>>) {
+    std::thread::spawn(move || loop {
+        let interval = match acme.certificate() {
+            Ok((cert, new)) => {
+                let acceptor = match acceptor.upgrade() {
+                    Some(acceptor) => acceptor,
+                    None => return,
+                };
+                match tls::acceptor_pem(cert.certificate(), cert.private_key()) {
+                    Ok(tls) => {
+                        if new {
+                            info!("certificate of {} renewed", acme.domains.join(", "));
+                        }
+                        *acceptor.write().unwrap() = Some(tls);
+                        CHECK_INTERVAL
+                    }
+                    Err(err) => {
+                        error!("acme certificate error: {}", err);
+                        RETRY_INTERVAL
+                    }
+                }
+            }
+            Err(err) => {
+                error!("acme error: {}", err);
+                RETRY_INTERVAL
+            }
+        };
+        std::thread::sleep(interval);
+    });
+}
@@ -0,0 +1,160 @@
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, TcpListener},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use http_types::{Method, Request, Response, StatusCode};
+use serde_json::json;
+use smol::{Async, Task};
+
+use crate::{
+    config::{Config, DomainName},
+    server::Server,
+};
+
+pub async fn listen(server: Arc<Server>, addr: String) -> Result<()> {
+    let addr: SocketAddr = addr.parse()?;
+    let listener = Async::<TcpListener>::bind(addr)?;
+    while let Some(accepted) = server.or_shutdown(listener.accept()).await {
+        let (stream, _) = accepted?;
+        let stream = async_dup::Arc::new(stream);
+        let server = server.clone();
+        let task = Task::spawn(async move {
+            let endpoint = |req| {
+                let server = server.clone();
+                async move { handle(&server, req).await }
+            };
+            if let Err(err) = async_h1::accept(stream, endpoint).await {
+                error!("Admin connection error: {:#?}", err);
+            }
+        });
+
+        task.detach();
+    }
+    Ok(())
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response {
+    let mut resp = Response::new(status);
+    resp.insert_header("content-type", "application/json");
+    resp.set_body(body.to_string());
+    resp
+}
+
+// takes as long wherever they differ, so the token can't be guessed byte by byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn handle(server: &Server, req: Request) -> http_types::Result<Response> {
+    if let Some(token) = &server.config().admin_token {
+        let expected = format!("Bearer {}", token);
+        let given = req.header("authorization").map_or("", |i| i.as_str());
+        if !constant_time_eq(given.as_bytes(), expected.as_bytes()) {
+            return Ok(Response::new(StatusCode::Unauthorized));
+        }
+    }
+    let path = req.url().path().to_string();
+    let segments: Vec<_> = path.trim_matches('/').split('/').collect();
+    match (req.method(), segments.as_slice()) {
+        (Method::Get, ["domains"]) => Ok(domains(server)),
+        (Method::Put, ["domains", name]) => add_domain(server, name, req).await,
+        (Method::Delete, ["domains", name]) => remove_domain(server, name),
+        (Method::Post, ["cache", "flush"]) => Ok(flush_cache(server)),
+        (Method::Get, ["health"]) => Ok(health(server)),
+        (Method::Get, ["usage"]) => Ok(usage(server)),
+        _ => Ok(Response::new(StatusCode::NotFound)),
+    }
+}
+
+fn domains(server: &Server) -> Response {
+    let forward = server.forward();
+    let domains: HashMap<_, _> = forward
+        .config()
+        .domain_name
+        .iter()
+        .map(|(k, v)| match v.targets() {
+            [target] => (k, json!(target)),
+            targets => (k, json!(targets)),
+        })
+        .collect();
+    json_response(StatusCode::Ok, json!(domains))
+}
+
+// body is the same as value of domain_name in config file, as json
+async fn add_domain(
+    server: &Server,
+    name: &str,
+    mut req: Request,
+) -> http_types::Result<Response> {
+    let body = req.body_string().await?;
+    let domain: DomainName = match serde_json::from_str(&body) {
+        Ok(domain) => domain,
+        Err(err) => {
+            let body = json!({ "error": err.to_string() });
+            return Ok(json_response(StatusCode::BadRequest, body));
+        }
+    };
+    update(server, |config| {
+        config.domain_name.insert(name.to_string(), domain);
+    })
+}
+
+fn remove_domain(server: &Server, name: &str) -> http_types::Result<Response> {
+    if !server.forward().config().domain_name.contains_key(name) {
+        return Ok(Response::new(StatusCode::NotFound));
+    }
+    update(server, |config| {
+        config.domain_name.remove(name);
+    })
+}
+
+fn update<F>(server: &Server, f: F) -> http_types::Result<Response>
+where
+    F: FnOnce(&mut Config),
+{
+    match server.update(f) {
+        Ok(()) => Ok(json_response(StatusCode::Ok, json!({ "ok": true }))),
+        Err(err) => {
+            let body = json!({ "error": err.to_string() });
+            Ok(json_response(StatusCode::BadRequest, body))
+        }
+    }
+}
+
+fn flush_cache(server: &Server) -> Response {
+    if let Some(cache) = server.forward().cache() {
+        cache.clear();
+    }
+    json_response(StatusCode::Ok, json!({ "ok": true }))
+}
+
+fn health(server: &Server) -> Response {
+    let forward = server.forward();
+    let (entries, bytes) = forward.cache().map(|i| i.stats()).unwrap_or_default();
+    json_response(
+        StatusCode::Ok,
+        json!({
+            "status": "ok",
+            "uptime": server.uptime().as_secs(),
+            "domains": forward.config().domain_name.len(),
+            "cache": { "entries": entries, "bytes": bytes },
+        }),
+    )
+}
+
+fn usage(server: &Server) -> Response {
+    let usage: HashMap<_, _> = server
+        .forward()
+        .usage()
+        .domains()
+        .into_iter()
+        .map(|(domain, today, month)| (domain, json!({ "today": today, "month": month })))
+        .collect();
+    json_response(StatusCode::Ok, json!(usage))
+}
@@ -0,0 +1,179 @@
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::io::AsyncRead;
+use http_types::{Body, Response};
+
+use crate::config::{Balance, DomainOptions};
+
+// spreads requests across targets of a mirror domain
+pub struct Balancer<T> {
+    nodes: Vec<Node<T>>,
+    balance: Balance,
+    next: AtomicUsize,
+    max_fails: u32,
+    fail_timeout: Duration,
+}
+
+struct Node<T> {
+    target: T,
+    // requests in flight
+    active: AtomicUsize,
+    health: Mutex<Health>,
+}
+
+#[derive(Default)]
+struct Health {
+    fails: u32,
+    // first failure counted
+    since: Option<Instant>,
+    down_until: Option<Instant>,
+    // by active health check
+    checked_down: bool,
+}
+
+impl<T> Node<T> {
+    fn is_down(&self) -> bool {
+        let health = self.health.lock().unwrap();
+        health.checked_down || health.down_until.map_or(false, |i| i > Instant::now())
+    }
+}
+
+impl<T> Balancer<T> {
+    pub fn new(targets: Vec<T>, options: &DomainOptions) -> Balancer<T> {
+        let nodes = targets
+            .into_iter()
+            .map(|target| Node {
+                target,
+                active: AtomicUsize::new(0),
+                health: Mutex::new(Health::default()),
+            })
+            .collect();
+        Balancer {
+            nodes,
+            balance: options.balance,
+            next: AtomicUsize::new(0),
+            max_fails: options.max_fails.unwrap_or(1),
+            fail_timeout: Duration::from_secs(options.fail_timeout.unwrap_or(10)),
+        }
+    }
+
+    pub fn targets(&self) -> impl Iterator<Item = &T> {
+        self.nodes.iter().map(|i| &i.target)
+    }
+
+    // result of active health check, true if changed
+    pub fn set_up(&self, index: usize, up: bool) -> bool {
+        let mut health = self.nodes[index].health.lock().unwrap();
+        let changed = health.checked_down == up;
+        health.checked_down = !up;
+        changed
+    }
+
+    // targets marked down are skipped, None if all of them are
+    pub fn pick(self: &Arc<Self>) -> Option<Lease<T>> {
+        let candidates: Vec<usize> = (0..self.nodes.len())
+            .filter(|i| !self.nodes[*i].is_down())
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        let index = match self.balance {
+            Balance::RoundRobin => {
+                let n = self.next.fetch_add(1, Ordering::Relaxed);
+                candidates[n % candidates.len()]
+            }
+            Balance::LeastConnections => *candidates
+                .iter()
+                .min_by_key(|i| self.nodes[**i].active.load(Ordering::Relaxed))
+                .unwrap(),
+        };
+        self.nodes[index].active.fetch_add(1, Ordering::Relaxed);
+        Some(Lease {
+            balancer: self.clone(),
+            index,
+        })
+    }
+}
+
+// a picked target, counted as active until dropped
+pub struct Lease<T> {
+    balancer: Arc<Balancer<T>>,
+    index: usize,
+}
+
+impl<T: Send + Sync + 'static> Lease<T> {
+    pub fn target(&self) -> &T {
+        &self.balancer.nodes[self.index].target
+    }
+
+    // passive health check by result of a request, true if target is marked down
+    pub fn report(&self, ok: bool) -> bool {
+        let balancer = &self.balancer;
+        let mut health = balancer.nodes[self.index].health.lock().unwrap();
+        if ok {
+            health.fails = 0;
+            health.since = None;
+            health.down_until = None;
+            return false;
+        }
+        if balancer.max_fails == 0 {
+            return false;
+        }
+        let now = Instant::now();
+        if health.since.map_or(true, |i| now - i > balancer.fail_timeout) {
+            health.fails = 0;
+            health.since = Some(now);
+        }
+        health.fails += 1;
+        if health.fails < balancer.max_fails {
+            return false;
+        }
+        health.down_until = Some(now + balancer.fail_timeout);
+        health.fails = 0;
+        health.since = None;
+        true
+    }
+
+    // keep the lease until the body of response is read
+    pub fn attach(self, resp: &mut Response) {
+        let body = resp.take_body();
+        let len = body.len();
+        let body = Leased {
+            inner: body,
+            _lease: self,
+        };
+        let body = async_std::io::BufReader::new(body);
+        resp.set_body(Body::from_reader(body, len));
+    }
+}
+
+impl<T> Drop for Lease<T> {
+    fn drop(&mut self) {
+        self.balancer.nodes[self.index]
+            .active
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct Leased<R, T> {
+    inner: R,
+    _lease: Lease<T>,
+}
+
+impl<R: AsyncRead + Unpin, T> AsyncRead for Leased<R, T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
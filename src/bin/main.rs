@@ -1,9 +1,98 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
-use web_jingzi::server::run;
+use web_jingzi::{
+    config::{Config, Targets},
+    server::Server,
+};
+
+const USAGE: &str = "usage: web-jingzi [options]
+
+options:
+    -c, --config <file>         config file, default to $CONFIG_FILE or config.yaml
+    -l, --listen <address>      replaces listen_address, may be repeated
+        --log-level <level>     error, warn, info, debug or trace, overrides RUST_LOG
+        --validate              check config and exit
+        --dump-effective-config print config with overrides applied as yaml and exit
+        --migrate               print config with every domain a section as yaml and exit
+        --export <url>          crawl the mirror from url and write it as static files
+        --out <dir>             directory of --export, default export
+    -h, --help                  print this help";
+
+#[derive(Default)]
+struct Args {
+    config: Option<String>,
+    listen: Vec<String>,
+    log_level: Option<String>,
+    validate: bool,
+    dump: bool,
+    migrate: bool,
+    export: Option<String>,
+    out: Option<String>,
+    help: bool,
+}
+
+impl Args {
+    fn parse() -> Result<Args> {
+        let mut args = Args::default();
+        let mut iter = std::env::args().skip(1);
+        while let Some(arg) = iter.next() {
+            let mut value = || iter.next().ok_or(anyhow!("{} needs a value", arg));
+            match arg.as_str() {
+                "-c" | "--config" => args.config = Some(value()?),
+                "-l" | "--listen" => args.listen.push(value()?),
+                "--log-level" => args.log_level = Some(value()?),
+                "--validate" | "--check-config" => args.validate = true,
+                "--dump-effective-config" => args.dump = true,
+                "--migrate" => args.migrate = true,
+                "--export" => args.export = Some(value()?),
+                "--out" => args.out = Some(value()?),
+                "-h" | "--help" => args.help = true,
+                _ => return Err(anyhow!("unknown argument: {}\n\n{}", arg, USAGE)),
+            }
+        }
+        Ok(args)
+    }
+}
 
 fn main() -> Result<()> {
-    env_logger::init();
-    std::env::set_var("CONFIG_FILE", "config.yaml");
-    run()
+    let args = Args::parse()?;
+    if args.help {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(level) = &args.log_level {
+        logger.parse_filters(level);
+    }
+    logger.init();
+
+    let file = args
+        .config
+        .or_else(|| std::env::var("CONFIG_FILE").ok())
+        .unwrap_or_else(|| "config.yaml".to_string());
+    if args.migrate {
+        print!("{}", Config::migrate(&file)?);
+        return Ok(());
+    }
+    let mut builder = Server::builder().config_file(&file)?;
+    if !args.listen.is_empty() {
+        builder.config_mut().listen_address = Targets::Many(args.listen);
+    }
+    if args.dump {
+        print!("{}", serde_yaml::to_string(builder.config_mut())?);
+        return Ok(());
+    }
+    if args.validate {
+        builder.check()?;
+        println!("{} is ok", file);
+        return Ok(());
+    }
+    if let Some(start) = &args.export {
+        let dir = args.out.as_deref().unwrap_or("export");
+        let written = builder.build()?.export(start, dir)?;
+        println!("{} files written to {}", written, dir);
+        return Ok(());
+    }
+    builder.build()?.run()
 }
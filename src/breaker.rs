@@ -0,0 +1,74 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config::CircuitBreakerConfig;
+
+// inserted into extensions of the response given instead of contacting a target
+pub struct Open;
+
+// stops requests to targets failed in a row, by origin
+pub struct Breaker {
+    failures: u32,
+    cooldown: Duration,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+#[derive(Default)]
+struct Circuit {
+    // failures in a row
+    fails: u32,
+    open_until: Option<Instant>,
+    // when a request was let through after cooldown, its result closes or
+    // opens the circuit again, another is let through if it takes a cooldown
+    probe: Option<Instant>,
+}
+
+impl Breaker {
+    pub fn new(config: &CircuitBreakerConfig) -> Breaker {
+        Breaker {
+            failures: config.failures,
+            cooldown: Duration::from_secs(config.cooldown),
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // false while open, once cooldown is over a single request is let through
+    pub fn allow(&self, origin: &str) -> bool {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = match circuits.get_mut(origin) {
+            Some(circuit) => circuit,
+            None => return true,
+        };
+        let now = Instant::now();
+        let until = match circuit.open_until {
+            Some(until) => until,
+            None => return true,
+        };
+        if until > now || circuit.probe.map_or(false, |i| now - i < self.cooldown) {
+            return false;
+        }
+        circuit.probe = Some(now);
+        true
+    }
+
+    // true if the circuit is opened by this failure
+    pub fn report(&self, origin: &str, ok: bool) -> bool {
+        let mut circuits = self.circuits.lock().unwrap();
+        if ok {
+            circuits.remove(origin);
+            return false;
+        }
+        let circuit = circuits.entry(origin.to_string()).or_default();
+        circuit.fails += 1;
+        if circuit.probe.is_some() || circuit.fails >= self.failures {
+            let opened = circuit.open_until.is_none() || circuit.probe.is_some();
+            circuit.open_until = Some(Instant::now() + self.cooldown);
+            circuit.probe = None;
+            return opened;
+        }
+        false
+    }
+}
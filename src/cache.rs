@@ -0,0 +1,511 @@
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use futures::io::AsyncRead;
+use http_types::{Body, Method, Request, Response, StatusCode};
+use smol::Task;
+
+use crate::{
+    config::{CacheConfig, PrefetchConfig},
+    disk_cache::{DiskCache, Meta},
+};
+
+#[derive(Clone)]
+pub struct Entry {
+    status: StatusCode,
+    headers: Vec<(String, Vec<String>)>,
+    body: Arc<Vec<u8>>,
+    expires: Instant,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl Entry {
+    pub fn is_fresh(&self) -> bool {
+        Instant::now() < self.expires
+    }
+
+    // fresh, or stale for less than window
+    pub fn is_fresh_within(&self, window: Duration) -> bool {
+        Instant::now() < self.expires + window
+    }
+
+    pub fn response(&self) -> Response {
+        let mut resp = Response::new(self.status);
+        for (name, values) in &self.headers {
+            for value in values {
+                resp.append_header(name.as_str(), value.as_str());
+            }
+        }
+        resp.set_body(self.body.as_ref().clone());
+        resp
+    }
+
+    // 304 if the entry is what the client has already
+    pub fn response_to(&self, conditions: &Conditions) -> Response {
+        if !self.matches(conditions) {
+            return self.response();
+        }
+        let mut resp = Response::new(StatusCode::NotModified);
+        for (name, values) in &self.headers {
+            if matches!(name.as_str(), "content-type" | "content-encoding") {
+                continue;
+            }
+            for value in values {
+                resp.append_header(name.as_str(), value.as_str());
+            }
+        }
+        resp
+    }
+
+    fn matches(&self, conditions: &Conditions) -> bool {
+        if let Some(if_none_match) = &conditions.if_none_match {
+            let etag = match &self.etag {
+                Some(etag) => etag,
+                None => return false,
+            };
+            return if_none_match
+                .split(',')
+                .map(|i| i.trim())
+                .any(|i| i == "*" || weak(i) == weak(etag));
+        }
+        let since = conditions
+            .if_modified_since
+            .as_ref()
+            .and_then(|i| httpdate::parse_http_date(i).ok());
+        let last_modified = self
+            .last_modified
+            .as_ref()
+            .and_then(|i| httpdate::parse_http_date(i).ok());
+        match (since, last_modified) {
+            (Some(since), Some(last_modified)) => last_modified <= since,
+            _ => false,
+        }
+    }
+
+    // revalidate with upstream by validators of the entry, instead of those of
+    // client, so the entry is refreshed on 304
+    pub fn add_validators(&self, req: &mut Request) {
+        req.remove_header("if-none-match");
+        req.remove_header("if-modified-since");
+        if let Some(etag) = &self.etag {
+            req.insert_header("if-none-match", weak(etag));
+        }
+        if let Some(last_modified) = &self.last_modified {
+            req.insert_header("if-modified-since", last_modified.as_str());
+        }
+    }
+
+    // marked public by target, fit for requests with cookies
+    pub fn is_public(&self) -> bool {
+        self.headers
+            .iter()
+            .filter(|(name, _)| name == "cache-control")
+            .flat_map(|(_, values)| values.iter().flat_map(|i| i.split(',')))
+            .any(|i| i.trim().eq_ignore_ascii_case("public"))
+    }
+
+    pub fn has_validators(&self) -> bool {
+        self.etag.is_some() || self.last_modified.is_some()
+    }
+
+    fn to_meta(&self, key: &str) -> Meta {
+        let now = Instant::now();
+        let expires = if self.expires > now {
+            SystemTime::now() + (self.expires - now)
+        } else {
+            SystemTime::now() - (now - self.expires)
+        };
+        Meta {
+            key: key.to_string(),
+            status: self.status.into(),
+            headers: self.headers.clone(),
+            expires: expires
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            etag: self.etag.clone(),
+            last_modified: self.last_modified.clone(),
+        }
+    }
+
+    fn from_meta(meta: Meta, body: Vec<u8>) -> Option<Entry> {
+        // stale ones keep their age
+        let now = Instant::now();
+        let expires = match (UNIX_EPOCH + Duration::from_secs(meta.expires))
+            .duration_since(SystemTime::now())
+        {
+            Ok(lifetime) => now + lifetime,
+            Err(err) => now.checked_sub(err.duration()).unwrap_or(now),
+        };
+        Some(Entry {
+            status: StatusCode::try_from(meta.status).ok()?,
+            headers: meta.headers,
+            body: Arc::new(body),
+            expires,
+            etag: meta.etag,
+            last_modified: meta.last_modified,
+        })
+    }
+}
+
+// validators sent by client
+pub struct Conditions {
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+}
+
+impl Conditions {
+    pub fn of(req: &Request) -> Conditions {
+        Conditions {
+            if_none_match: req.header("if-none-match").map(|i| i.as_str().to_string()),
+            if_modified_since: req.header("if-modified-since").map(|i| i.as_str().to_string()),
+        }
+    }
+}
+
+// an etag without `W/`, which may be added when the body is compressed,
+// as If-None-Match compares etags weakly anyway
+pub fn weak(etag: &str) -> &str {
+    etag.trim_start_matches("W/")
+}
+
+struct Inner {
+    entries: HashMap<String, (Entry, u64)>,
+    used: usize,
+    tick: u64,
+    // keys of stale entries being fetched again in the background
+    refreshing: HashSet<String>,
+}
+
+// inserted into extensions of a request fetching a stale entry again,
+// which is not served stale to it
+pub struct Revalidation;
+
+// inserted into extensions of a stale response, the server fetches the entry
+// again by request, the key is refreshed by none else until it's dropped
+pub struct Refresh {
+    req: Request,
+    cache: Arc<Cache>,
+    key: String,
+}
+
+impl Refresh {
+    pub fn request(&mut self) -> &mut Request {
+        &mut self.req
+    }
+}
+
+impl Drop for Refresh {
+    fn drop(&mut self) {
+        let mut inner = self.cache.inner.lock().unwrap();
+        inner.refreshing.remove(&self.key);
+    }
+}
+
+pub struct Cache {
+    size: usize,
+    default_ttl: Duration,
+    inner: Mutex<Inner>,
+    // behind memory, entries are saved to it and loaded on misses
+    disk: Option<Arc<DiskCache>>,
+    prefetch: Option<PrefetchConfig>,
+}
+
+impl Cache {
+    pub fn new(config: &CacheConfig) -> Result<Cache> {
+        Ok(Cache {
+            size: config.size,
+            default_ttl: Duration::from_secs(config.ttl),
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                used: 0,
+                tick: 0,
+                refreshing: HashSet::new(),
+            }),
+            disk: config.disk.as_ref().map(DiskCache::new).transpose()?,
+            prefetch: config.prefetch.clone(),
+        })
+    }
+
+    pub fn prefetch(&self) -> Option<&PrefetchConfig> {
+        self.prefetch.as_ref()
+    }
+
+    pub fn key(req: &Request) -> Option<String> {
+        if req.method() != Method::Get
+            || req.header("authorization").is_some()
+            || req.header("range").is_some()
+        {
+            return None;
+        }
+        let encoding = req
+            .header("accept-encoding")
+            .map(|i| i.as_str().to_string())
+            .unwrap_or_default();
+        Some(format!("{} {}", req.url(), encoding))
+    }
+
+    pub fn get(&self, key: &str) -> Option<Entry> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let (entry, last_used) = inner.entries.get_mut(key)?;
+        *last_used = tick;
+        Some(entry.clone())
+    }
+
+    // from memory, or from disk into memory
+    pub async fn lookup(&self, key: &str) -> Option<Entry> {
+        if let Some(entry) = self.get(key) {
+            return Some(entry);
+        }
+        let disk = self.disk.clone()?;
+        let name = key.to_string();
+        let (meta, body) = smol::unblock!(disk.load(&name))?;
+        let entry = Entry::from_meta(meta, body)?;
+        self.remember(key.to_string(), entry.clone());
+        Some(entry)
+    }
+
+    // None if the entry is being refreshed already
+    pub fn start_refresh(self: &Arc<Self>, key: &str, req: Request) -> Option<Refresh> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.refreshing.insert(key.to_string()) {
+            return None;
+        }
+        Some(Refresh {
+            req,
+            cache: self.clone(),
+            key: key.to_string(),
+        })
+    }
+
+    // update expiration after upstream answered 304 to revalidation
+    pub fn refresh(&self, key: &str, resp: &Response) -> Option<Entry> {
+        let lifetime = self.lifetime(resp).unwrap_or_default();
+        let mut inner = self.inner.lock().unwrap();
+        let (entry, _) = inner.entries.get_mut(key)?;
+        entry.expires = Instant::now() + lifetime;
+        let entry = entry.clone();
+        drop(inner);
+        self.save(key, &entry);
+        Some(entry)
+    }
+
+    // number of entries and bytes of bodies
+    pub fn stats(&self) -> (usize, usize) {
+        let inner = self.inner.lock().unwrap();
+        (inner.entries.len(), inner.used)
+    }
+
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.entries.clear();
+        inner.used = 0;
+        if let Some(disk) = self.disk.clone() {
+            Task::spawn(async move { smol::unblock!(disk.clear()) }).detach();
+        }
+    }
+
+    // record body of cacheable response into cache while it's sent to client
+    pub fn store(self: &Arc<Self>, key: String, resp: &mut Response) {
+        if resp.status() != StatusCode::Ok {
+            return;
+        }
+        // never ends
+        if resp.content_type().map_or(false, |i| i.essence() == "text/event-stream") {
+            return;
+        }
+        let lifetime = match self.lifetime(resp) {
+            Some(lifetime) => lifetime,
+            None => return,
+        };
+        let etag = resp.header("etag").map(|i| i.as_str().to_string());
+        let last_modified = resp.header("last-modified").map(|i| i.as_str().to_string());
+        if lifetime == Duration::default() && etag.is_none() && last_modified.is_none() {
+            return;
+        }
+        let headers = resp
+            .iter()
+            .filter(|(name, _)| {
+                !matches!(
+                    name.as_str(),
+                    "content-length" | "transfer-encoding" | "connection"
+                )
+            })
+            .map(|(name, values)| {
+                let values = values.iter().map(|i| i.as_str().to_string()).collect();
+                (name.as_str().to_string(), values)
+            })
+            .collect();
+        let entry = Entry {
+            status: resp.status(),
+            headers,
+            body: Arc::new(Vec::new()),
+            expires: Instant::now() + lifetime,
+            etag,
+            last_modified,
+        };
+        let body = resp.take_body();
+        let len = body.len();
+        let recorder = Recorder {
+            inner: body,
+            cache: self.clone(),
+            key: Some(key),
+            entry: Some(entry),
+            body: Vec::new(),
+            // an entry never takes more than 1/8 of the cache
+            limit: self.size / 8,
+        };
+        let recorder = async_std::io::BufReader::new(recorder);
+        resp.set_body(Body::from_reader(recorder, len));
+    }
+
+    fn insert(&self, key: String, entry: Entry) {
+        self.save(&key, &entry);
+        self.remember(key, entry);
+    }
+
+    // to disk in the background
+    fn save(&self, key: &str, entry: &Entry) {
+        let disk = match self.disk.clone() {
+            Some(disk) => disk,
+            None => return,
+        };
+        let meta = entry.to_meta(key);
+        let body = entry.body.clone();
+        let key = key.to_string();
+        let task = Task::spawn(async move {
+            if let Err(err) = smol::unblock!(disk.save(&meta, &body)) {
+                warn!("saving {} to disk cache: {}", key, err);
+            }
+        });
+        task.detach();
+    }
+
+    // in memory only
+    fn remember(&self, key: String, entry: Entry) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let len = entry.body.len();
+        if let Some((old, _)) = inner.entries.insert(key, (entry, tick)) {
+            inner.used -= old.body.len();
+        }
+        inner.used += len;
+        // evict least recently used entries
+        while inner.used > self.size {
+            let oldest = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(k, _)| k.clone());
+            match oldest.and_then(|k| inner.entries.remove(&k)) {
+                Some((old, _)) => inner.used -= old.body.len(),
+                None => break,
+            }
+        }
+    }
+
+    // how long the response stays fresh, None if it must not be stored
+    fn lifetime(&self, resp: &Response) -> Option<Duration> {
+        if let Some(vary) = resp.header("vary") {
+            let other = vary
+                .iter()
+                .flat_map(|i| i.as_str().split(','))
+                .any(|i| !i.trim().eq_ignore_ascii_case("accept-encoding"));
+            if other {
+                return None;
+            }
+        }
+        // cookies of a client are not given to others
+        if resp.header("set-cookie").is_some() {
+            return None;
+        }
+        let directives = directives(resp);
+        if directives.iter().any(|i| i == "no-store" || i == "private") {
+            return None;
+        }
+        if directives.iter().any(|i| i == "no-cache") {
+            return Some(Duration::default());
+        }
+        for name in &["s-maxage=", "max-age="] {
+            let max_age = directives
+                .iter()
+                .find(|i| i.starts_with(name))
+                .and_then(|i| i[name.len()..].parse().ok());
+            if let Some(max_age) = max_age {
+                return Some(Duration::from_secs(max_age));
+            }
+        }
+        if let Some(expires) = resp.header("expires") {
+            let lifetime = httpdate::parse_http_date(expires.as_str())
+                .ok()
+                .and_then(|i| i.duration_since(SystemTime::now()).ok())
+                .unwrap_or_default();
+            return Some(lifetime);
+        }
+        Some(self.default_ttl)
+    }
+}
+
+// marked public by target, fit for requests with cookies
+pub fn is_public(resp: &Response) -> bool {
+    directives(resp).iter().any(|i| i == "public")
+}
+
+// of Cache-Control, lowercased
+fn directives(resp: &Response) -> Vec<String> {
+    resp.header("cache-control")
+        .map(|i| {
+            i.iter()
+                .flat_map(|i| i.as_str().split(','))
+                .map(|i| i.trim().to_lowercase())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+struct Recorder<R> {
+    inner: R,
+    cache: Arc<Cache>,
+    key: Option<String>,
+    entry: Option<Entry>,
+    body: Vec<u8>,
+    limit: usize,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Recorder<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let n = match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(n)) => n,
+            other => return other,
+        };
+        if n == 0 {
+            if let (Some(key), Some(mut entry)) = (self.key.take(), self.entry.take()) {
+                entry.body = Arc::new(std::mem::take(&mut self.body));
+                self.cache.insert(key, entry);
+            }
+        } else if self.entry.is_some() {
+            if self.body.len() + n > self.limit {
+                self.entry = None;
+                self.body = Vec::new();
+            } else {
+                self.body.extend_from_slice(&buf[..n]);
+            }
+        }
+        Poll::Ready(Ok(n))
+    }
+}
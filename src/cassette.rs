@@ -0,0 +1,133 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    convert::TryFrom,
+    fs,
+    hash::{Hash, Hasher},
+    path::PathBuf,
+};
+
+use anyhow::Result;
+use http_types::{Error as HttpError, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{CassetteConfig, CassetteMode};
+
+// a recorded response besides its body
+#[derive(Serialize, Deserialize)]
+struct Recording {
+    key: String,
+    status: u16,
+    headers: Vec<(String, Vec<String>)>,
+}
+
+// exchanges with targets recorded to files of a directory, or served from
+// them without network, a file is the recording as a json line and the body
+pub struct Cassette {
+    dir: PathBuf,
+    mode: CassetteMode,
+}
+
+impl Cassette {
+    pub fn new(config: &CassetteConfig) -> Result<Cassette> {
+        let dir = PathBuf::from(&config.dir);
+        if let CassetteMode::Record = config.mode {
+            fs::create_dir_all(&dir)?;
+        }
+        Ok(Cassette {
+            dir,
+            mode: config.mode,
+        })
+    }
+
+    pub fn replaying(&self) -> bool {
+        matches!(self.mode, CassetteMode::Replay)
+    }
+
+    // method, url and hash of body, which is read and put back
+    pub async fn key(req: &mut Request) -> http_types::Result<String> {
+        let body = req.body_bytes().await?;
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        if !body.is_empty() {
+            let typed = req.header("content-type").is_some();
+            req.set_body(body);
+            // not the one of body set
+            if !typed {
+                req.remove_header("content-type");
+            }
+        }
+        Ok(format!("{} {} {:x}", req.method(), req.url(), hasher.finish()))
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        self.dir.join(format!("{:016x}", hasher.finish()))
+    }
+
+    // 502 for exchanges never recorded
+    pub async fn replay(&self, key: &str) -> http_types::Result<Response> {
+        let path = self.path(key);
+        let data = smol::unblock!(fs::read(path)).ok();
+        let recording = data.as_ref().and_then(|data| {
+            let end = data.iter().position(|i| *i == b'\n')?;
+            let recording: Recording = serde_json::from_slice(&data[..end]).ok()?;
+            Some((recording, &data[end + 1..]))
+        });
+        let (recording, body) = match recording {
+            Some((recording, body)) if recording.key == key => (recording, body),
+            _ => {
+                let err = format!("not recorded: {}", key);
+                return Err(HttpError::from_str(StatusCode::BadGateway, err));
+            }
+        };
+        let status = StatusCode::try_from(recording.status)
+            .map_err(|_| HttpError::from_str(StatusCode::BadGateway, "invalid recording"))?;
+        let mut resp = Response::new(status);
+        for (name, values) in &recording.headers {
+            for value in values {
+                resp.append_header(name.as_str(), value.as_str());
+            }
+        }
+        let typed = resp.header("content-type").is_some();
+        resp.set_body(body.to_vec());
+        if !typed {
+            resp.remove_header("content-type");
+        }
+        Ok(resp)
+    }
+
+    // the body is read whole, and put back
+    pub async fn record(&self, key: &str, resp: &mut Response) -> http_types::Result<()> {
+        let body = resp.body_bytes().await?;
+        let headers = resp
+            .iter()
+            .filter(|(name, _)| {
+                !matches!(
+                    name.as_str(),
+                    "content-length" | "transfer-encoding" | "connection"
+                )
+            })
+            .map(|(name, values)| {
+                let values = values.iter().map(|i| i.as_str().to_string()).collect();
+                (name.as_str().to_string(), values)
+            })
+            .collect();
+        let recording = Recording {
+            key: key.to_string(),
+            status: resp.status().into(),
+            headers,
+        };
+        let mut data = serde_json::to_vec(&recording)?;
+        data.push(b'\n');
+        data.extend_from_slice(&body);
+        let path = self.path(key);
+        smol::unblock!(fs::write(path, data))?;
+        let typed = resp.header("content-type").is_some();
+        resp.set_body(body);
+        if !typed {
+            resp.remove_header("content-type");
+        }
+        Ok(())
+    }
+}
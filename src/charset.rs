@@ -0,0 +1,112 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use encoding_rs::{Decoder, Encoding, UTF_8};
+use futures::io::AsyncRead;
+
+const CHUNK_SIZE: usize = 8192;
+
+// charset of body by byte order mark, Content-Type, then <meta charset> of html
+pub fn detect(label: Option<&str>, html: bool, head: &[u8]) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(head) {
+        return encoding;
+    }
+    if let Some(encoding) = label.and_then(|i| Encoding::for_label(i.trim().as_bytes())) {
+        return encoding;
+    }
+    if html {
+        if let Some(encoding) = meta_charset(head).and_then(Encoding::for_label) {
+            return encoding;
+        }
+    }
+    UTF_8
+}
+
+// value of charset= in the first <meta> having one
+fn meta_charset(head: &[u8]) -> Option<&[u8]> {
+    let lower = head.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(start) = find(&lower[from..], b"<meta").map(|i| i + from) {
+        let end = find(&lower[start..], b">").map_or(lower.len(), |i| i + start);
+        if let Some(i) = find(&lower[start..end], b"charset=") {
+            let value = &head[start + i + 8..end];
+            let value = match value.first() {
+                Some(b'"') | Some(b'\'') => &value[1..],
+                _ => value,
+            };
+            let len = value
+                .iter()
+                .position(|i| {
+                    matches!(i, b'"' | b'\'' | b';' | b'/' | b'>') || i.is_ascii_whitespace()
+                })
+                .unwrap_or_else(|| value.len());
+            return Some(&value[..len]);
+        }
+        from = end;
+    }
+    None
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|i| i == needle)
+}
+
+// decodes body of encoding into utf-8
+pub struct Transcoder<R> {
+    inner: R,
+    decoder: Decoder,
+    output: Vec<u8>,
+    output_pos: usize,
+    eof: bool,
+}
+
+impl<R> Transcoder<R> {
+    pub fn new(inner: R, encoding: &'static Encoding) -> Transcoder<R> {
+        Transcoder {
+            inner,
+            decoder: encoding.new_decoder(),
+            output: Vec::new(),
+            output_pos: 0,
+            eof: false,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Transcoder<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        loop {
+            if self.output_pos < self.output.len() {
+                let n = buf.len().min(self.output.len() - self.output_pos);
+                let pos = self.output_pos;
+                buf[..n].copy_from_slice(&self.output[pos..pos + n]);
+                self.output_pos += n;
+                return Poll::Ready(Ok(n));
+            }
+            if self.eof {
+                return Poll::Ready(Ok(0));
+            }
+            let mut chunk = [0; CHUNK_SIZE];
+            let n = match Pin::new(&mut self.inner).poll_read(cx, &mut chunk) {
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            self.eof = n == 0;
+            let this = &mut *self;
+            // enough for all of chunk, so nothing is left in it
+            let len = this.decoder.max_utf8_buffer_length(n).unwrap_or(n * 3 + 16);
+            this.output.resize(len, 0);
+            let (_, _, written, _) =
+                this.decoder
+                    .decode_to_utf8(&chunk[..n], &mut this.output, this.eof);
+            this.output.truncate(written);
+            this.output_pos = 0;
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use futures::{
+    channel::oneshot::{self, Receiver, Sender},
+    io::{AsyncReadExt, Cursor},
+};
+use http_types::{Body, Request, Response, StatusCode};
+
+use crate::{access_log::Upstream, cache::Cache};
+
+// a larger body is not shared, followers send requests of their own
+const MAX_SIZE: u64 = 8 * 1024 * 1024;
+
+// identical requests at once share one to target, the first one leads
+// and the others follow, they get copies of its response
+#[derive(Default)]
+pub struct Coalescer {
+    flights: Mutex<HashMap<String, Vec<Sender<Shared>>>>,
+}
+
+pub enum Role<'a> {
+    Leader(Flight<'a>),
+    // canceled if the response of leader is not for them
+    Follower(Receiver<Shared>),
+}
+
+impl Coalescer {
+    // requests with cookies, validators or origin get responses of their own
+    pub fn key(req: &Request) -> Option<String> {
+        let own = ["cookie", "if-none-match", "if-modified-since", "origin"];
+        if own.iter().any(|i| req.header(*i).is_some()) {
+            return None;
+        }
+        Cache::key(req)
+    }
+
+    pub fn join(&self, key: &str) -> Role {
+        let mut flights = self.flights.lock().unwrap();
+        match flights.get_mut(key) {
+            Some(followers) => {
+                let (sender, receiver) = oneshot::channel();
+                followers.push(sender);
+                Role::Follower(receiver)
+            }
+            None => {
+                flights.insert(key.to_string(), Vec::new());
+                Role::Leader(Flight {
+                    coalescer: self,
+                    key: Some(key.to_string()),
+                })
+            }
+        }
+    }
+}
+
+// a request being led, followers left are canceled once it's dropped, requests
+// coming later lead flights of their own
+pub struct Flight<'a> {
+    coalescer: &'a Coalescer,
+    // None once followers are taken
+    key: Option<String>,
+}
+
+impl Flight<'_> {
+    // the body is read for followers if there are any, and the response is
+    // fit for them, the leader gets it from memory as well
+    pub async fn share(mut self, resp: &mut Response) -> http_types::Result<()> {
+        let followers = self.take();
+        if followers.is_empty() || !shareable(resp) {
+            return Ok(());
+        }
+        let mut body = resp.take_body();
+        let len = body.len();
+        let mut buf = Vec::new();
+        (&mut body).take(MAX_SIZE + 1).read_to_end(&mut buf).await?;
+        if buf.len() as u64 > MAX_SIZE {
+            let rest = async_std::io::BufReader::new(Cursor::new(buf).chain(body));
+            resp.set_body(Body::from_reader(rest, len));
+            return Ok(());
+        }
+        resp.set_body(buf.clone());
+        let shared = Shared {
+            status: resp.status(),
+            headers: resp
+                .iter()
+                .filter(|(name, _)| name.as_str() != "connection")
+                .map(|(name, values)| {
+                    let values = values.iter().map(|i| i.as_str().to_string()).collect();
+                    (name.as_str().to_string(), values)
+                })
+                .collect(),
+            body: Arc::new(buf),
+            upstream: resp.ext().get::<Upstream>().map(|i| i.0.clone()),
+        };
+        for follower in followers {
+            let _ = follower.send(shared.clone());
+        }
+        Ok(())
+    }
+
+    fn take(&mut self) -> Vec<Sender<Shared>> {
+        let key = match self.key.take() {
+            Some(key) => key,
+            None => return Vec::new(),
+        };
+        let mut flights = self.coalescer.flights.lock().unwrap();
+        flights.remove(&key).unwrap_or_default()
+    }
+}
+
+impl Drop for Flight<'_> {
+    fn drop(&mut self) {
+        self.take();
+    }
+}
+
+// the response of a leader, as followers get it
+#[derive(Clone)]
+pub struct Shared {
+    status: StatusCode,
+    headers: Vec<(String, Vec<String>)>,
+    body: Arc<Vec<u8>>,
+    upstream: Option<String>,
+}
+
+impl Shared {
+    pub fn response(&self) -> Response {
+        let mut resp = Response::new(self.status);
+        for (name, values) in &self.headers {
+            for value in values {
+                resp.append_header(name.as_str(), value.as_str());
+            }
+        }
+        resp.set_body(self.body.as_ref().clone());
+        if let Some(upstream) = &self.upstream {
+            resp.ext_mut().insert(Upstream(upstream.clone()));
+        }
+        resp
+    }
+}
+
+// cookies of a client are not given to others, nor responses varying by
+// more than encoding
+fn shareable(resp: &Response) -> bool {
+    if resp.header("set-cookie").is_some() {
+        return false;
+    }
+    match resp.header("vary") {
+        Some(vary) => vary
+            .iter()
+            .flat_map(|i| i.as_str().split(','))
+            .all(|i| i.trim().eq_ignore_ascii_case("accept-encoding")),
+        None => true,
+    }
+}
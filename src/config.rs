@@ -1,20 +1,1358 @@
-use std::{collections::HashMap, fs::File};
+use std::{
+    collections::HashMap,
+    fs,
+    net::{IpAddr, SocketAddr},
+};
 
-use anyhow::Result;
-use serde::Deserialize;
+use anyhow::{anyhow, Result};
+use http_types::Url;
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+use serde_yaml::{Mapping, Value};
 
-#[derive(Deserialize, Debug)]
+// content encodings the mirror can decode and encode
+pub const ENCODINGS: &[&str] = &["br", "deflate", "gzip", "zstd"];
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
-    pub listen_address: String,
-    pub domain_name: HashMap<String, String>,
+    pub listen_address: Targets,
+    pub listen_tls_address: Option<Targets>,
+    pub listen_unix: Option<UnixConfig>,
+    // connections of all listeners start with PROXY protocol header
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+    // certificate of listen_tls_address from let's encrypt instead of cert_file
+    pub acme: Option<AcmeConfig>,
+    // certificates by server name of clients, `*.x.com` for subdomains of x.com,
+    // cert_file or acme serves other names
+    #[serde(default)]
+    pub certificates: HashMap<String, CertificateConfig>,
+    // plain http listeners redirecting every request to the mirror over https
+    pub https_redirect: Option<RedirectConfig>,
+    pub domain_name: HashMap<String, DomainName>,
     pub socks5_server: Option<String>,
+    // `http://` or `socks5://` url, takes precedence over socks5_server
+    pub proxy: Option<String>,
+    // local address connections to targets or proxy are bound to
+    pub bind_address: Option<String>,
+    // resolve targets and proxies by these servers instead of the system
+    pub dns: Option<DnsConfig>,
+    pub cache: Option<CacheConfig>,
+    // record exchanges with targets, or replay them without network, for tests
+    pub cassette: Option<CassetteConfig>,
+    // identical requests at once share one request to target
+    #[serde(default)]
+    pub coalesce: bool,
+    // compress responses target sent plain
+    pub compress: Option<CompressConfig>,
+    // re-encode images smaller, for mirrors short of bandwidth
+    pub image: Option<ImageConfig>,
+    // responses of these types get domain names replaced
+    #[serde(default = "default_rewrite_content_types")]
+    pub rewrite_content_types: Vec<String>,
+    // responses of these types, `image/*` for all images, are relayed as they
+    // come, neither decoded nor compressed, unless in rewrite_content_types
+    #[serde(default = "default_passthrough_content_types")]
+    pub passthrough_content_types: Vec<String>,
+    // requests of these types get mirror domain names replaced by origin ones
+    #[serde(default)]
+    pub rewrite_request_content_types: Vec<String>,
+    // asked from targets, among those the client accepts
+    #[serde(default = "default_upstream_encodings")]
+    pub upstream_encodings: Vec<String>,
+    // send Accept-Encoding of client as it is
+    #[serde(default)]
+    pub pass_accept_encoding: bool,
+    // what to do with Content-Security-Policy headers of response
+    #[serde(default)]
+    pub content_security_policy: CspPolicy,
+    // remove upgrade-insecure-requests of Content-Security-Policy, for mirrors on http
+    #[serde(default)]
+    pub strip_upgrade_insecure_requests: bool,
+    // what to do with Strict-Transport-Security headers of response
+    #[serde(default)]
+    pub strict_transport_security: HstsPolicy,
+    // drop `integrity` of html tags, hashes would not match rewritten resources
+    #[serde(default = "default_true")]
+    pub strip_integrity: bool,
+    // keep search engines off mirrors, robots.txt denying all is served and
+    // responses get `X-Robots-Tag: noindex, nofollow`
+    #[serde(default)]
+    pub noindex: bool,
+    // `<link rel=canonical>` and Link headers of it
+    #[serde(default)]
+    pub canonical: CanonicalPolicy,
+    #[serde(default)]
+    pub cookie: CookieConfig,
+    pub rate_limit: Option<RateLimitConfig>,
+    // bytes each mirror domain may serve
+    pub quota: Option<QuotaConfig>,
+    // cidr of clients, deny takes precedence over allow,
+    // everything is allowed if allow is empty
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    #[serde(default)]
+    pub forwarded: ForwardedConfig,
+    #[serde(default)]
+    pub request_id: RequestIdConfig,
+    #[serde(default)]
+    pub response_limit: ResponseLimitConfig,
+    #[serde(default)]
+    pub concurrency: ConcurrencyConfig,
+    // socket options of listeners and connections of clients and to targets
+    #[serde(default)]
+    pub tcp: TcpConfig,
+    #[serde(default)]
+    pub timeout: TimeoutConfig,
+    pub retry: Option<RetryConfig>,
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    // html file served with 503 when all targets of a domain are down
+    pub maintenance_page: Option<String>,
+    // html files by status code or `default`, for errors of forwarding
+    #[serde(default)]
+    pub error_pages: HashMap<String, String>,
+    // keep internal error messages out of error responses
+    #[serde(default)]
+    pub hide_error_detail: bool,
+    // durations of the stages of a request in Server-Timing header of response,
+    // they are logged at debug level anyway
+    #[serde(default)]
+    pub server_timing: bool,
+    #[serde(default)]
+    pub inject: InjectConfig,
+    // run on bodies after domain names are replaced
+    #[serde(default)]
+    pub rewrite_rules: Vec<RewriteRule>,
+    // answer matching requests without contacting target
+    #[serde(default)]
+    pub responders: Vec<ResponderConfig>,
+    #[serde(default)]
+    pub pool: PoolConfig,
+    pub access_log: Option<AccessLogConfig>,
+    // optional, listen address of admin api
+    pub admin_address: Option<String>,
+    // bearer token required by admin api, and by admin_address not on loopback
+    pub admin_token: Option<String>,
+    // threads running tasks, default number of cpu cores
+    pub threads: Option<usize>,
+    // more mirrors served by this process, by name, each with options of its
+    // own like those of this file, threads and shutdown_timeout are of this one
+    #[serde(default)]
+    pub sites: HashMap<String, Config>,
+    // in seconds, wait for requests in flight on SIGINT or SIGTERM
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum DomainName {
+    Target(Targets),
+    Options(DomainOptions),
+}
+
+// a mapping is options, whose errors would be hidden by an untagged enum
+impl<'de> Deserialize<'de> for DomainName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        let name = if value.is_mapping() {
+            serde_yaml::from_value(value).map(DomainName::Options)
+        } else {
+            serde_yaml::from_value(value).map(DomainName::Target)
+        };
+        name.map_err(D::Error::custom)
+    }
+}
+
+// one or a list, load is spread across a list of targets
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Targets {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Default for Targets {
+    fn default() -> Targets {
+        Targets::One(String::new())
+    }
+}
+
+impl Targets {
+    pub fn as_slice(&self) -> &[String] {
+        match self {
+            Targets::One(target) => std::slice::from_ref(target),
+            Targets::Many(targets) => targets,
+        }
+    }
+}
+
+impl DomainName {
+    pub fn targets(&self) -> &[String] {
+        match self {
+            DomainName::Target(targets) => targets.as_slice(),
+            DomainName::Options(options) => options.target.as_slice(),
+        }
+    }
+
+    pub fn options(&self) -> Option<&DomainOptions> {
+        match self {
+            DomainName::Target(_) => None,
+            DomainName::Options(options) => Some(options),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct DomainOptions {
+    pub target: Targets,
+    // how requests are spread across a list of targets
+    #[serde(default)]
+    pub balance: Balance,
+    // a target is skipped for fail_timeout seconds after max_fails failures
+    // in fail_timeout seconds, default 1 and 10, 0 max_fails never skips
+    pub max_fails: Option<u32>,
+    pub fail_timeout: Option<u64>,
+    // requests to targets marked down by it are not sent
+    pub health_check: Option<HealthCheckConfig>,
+    // served with 503 when all targets are down, overrides the global one
+    pub maintenance_page: Option<String>,
+    // directory of files served instead of forwarding, by path of request
+    pub overrides: Option<String>,
+    // unset ones fall back to the global ones
+    pub inject: Option<InjectConfig>,
+    // run after the global ones
+    #[serde(default)]
+    pub rewrite_rules: Vec<RewriteRule>,
+    // checked before the global ones
+    #[serde(default)]
+    pub responders: Vec<ResponderConfig>,
+    // map paths of requests before they are sent to target, the first matching one wins
+    #[serde(default)]
+    pub path_rules: Vec<PathRule>,
+    // paths of requests forwarded, `*` matches anything or a regex after `~`,
+    // deny takes precedence
+    #[serde(default)]
+    pub allow_paths: Vec<String>,
+    #[serde(default)]
+    pub deny_paths: Vec<String>,
+    // answer to other paths, default 404
+    pub blocked_status: Option<u16>,
+    // methods forwarded, others are answered with 405, any if empty
+    #[serde(default)]
+    pub methods: Vec<String>,
+    // overrides the global one
+    pub noindex: Option<bool>,
+    // override the global ones, `direct` to connect without proxy
+    pub socks5_server: Option<String>,
+    pub proxy: Option<String>,
+    // overrides the global one
+    pub bind_address: Option<String>,
+    // connecting to https targets
+    pub tls: Option<TlsConfig>,
+    // overrides the global one
+    pub rate_limit: Option<RateLimitConfig>,
+    // unset ones fall back to the global ones
+    pub timeout: Option<TimeoutConfig>,
+    // overrides the global one
+    pub retry: Option<RetryConfig>,
+    // overrides the global one
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    // overrides the global one
+    pub image: Option<ImageConfig>,
+    // overrides the global one
+    pub quota: Option<QuotaConfig>,
+    // override those of cache
+    pub stale_while_revalidate: Option<u64>,
+    pub stale_if_error: Option<u64>,
+    // checked after the global ones
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    // `user:password`
+    #[serde(default)]
+    pub basic_auth: Vec<String>,
+    #[serde(default)]
+    pub bearer_tokens: Vec<String>,
+    // Access-Control-* headers of target are replaced, preflights are answered here
+    pub cors: Option<CorsConfig>,
+    // applied to request before sending to target
+    #[serde(default)]
+    pub request_headers: Vec<HeaderRule>,
+    // applied to response before sending to client
+    #[serde(default)]
+    pub response_headers: Vec<HeaderRule>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RewriteRule {
+    pub pattern: String,
+    // `$1` for capture groups, and `{mirror}`, `{target}` and `{origin}`
+    pub replace: String,
+    // defaults to rewrite_content_types
+    #[serde(default)]
+    pub content_types: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TcpConfig {
+    // TCP_NODELAY, for small interactive responses
+    #[serde(default = "default_true")]
+    pub nodelay: bool,
+    // seconds idle before keepalive probes, off if unset
+    pub keepalive: Option<u64>,
+    // pending connections of listeners
+    #[serde(default = "default_tcp_backlog")]
+    pub backlog: i32,
+    // SO_REUSEPORT of listeners, for processes sharing a port
+    #[serde(default)]
+    pub reuse_port: bool,
+}
+
+impl Default for TcpConfig {
+    fn default() -> TcpConfig {
+        TcpConfig {
+            nodelay: true,
+            keepalive: None,
+            backlog: default_tcp_backlog(),
+            reuse_port: false,
+        }
+    }
+}
+
+fn default_tcp_backlog() -> i32 {
+    1024
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DnsConfig {
+    // tried in turn, `1.1.1.1:53`, `tls://1.1.1.1` or `https://1.1.1.1/dns-query`
+    pub servers: Vec<String>,
+    // seconds waiting for each server
+    #[serde(default = "default_dns_timeout")]
+    pub timeout: u64,
+}
+
+fn default_dns_timeout() -> u64 {
+    5
+}
+
+// more than these are answered with 503 at once
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ConcurrencyConfig {
+    // connections of clients, excess ones are closed after the 503
+    pub max_connections: Option<usize>,
+    // requests in flight to each target
+    pub max_requests_per_target: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ResponderConfig {
+    // any if empty
+    #[serde(default)]
+    pub methods: Vec<String>,
+    // `/api/*` with `*` for the rest of path, or a whole path
+    pub path: String,
+    #[serde(default = "default_responder_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub body: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CorsConfig {
+    // `*` for any
+    #[serde(default = "default_cors_allow_origins")]
+    pub allow_origins: Vec<String>,
+    #[serde(default = "default_cors_allow_methods")]
+    pub allow_methods: Vec<String>,
+    // those asked by preflight if empty
+    #[serde(default)]
+    pub allow_headers: Vec<String>,
+    #[serde(default)]
+    pub expose_headers: Vec<String>,
+    #[serde(default)]
+    pub allow_credentials: bool,
+    // seconds browsers keep result of preflight
+    pub max_age: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PathRule {
+    // `/old/*` with `*` for the rest of path, or a whole path
+    pub from: String,
+    // `/new/*`, `/*` strips the prefix of from
+    pub to: String,
+    // appended to query of request, e.g. `lang=en`
+    pub query: Option<String>,
+}
+
+// snippets inserted into html responses
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct InjectConfig {
+    // before `</head>`
+    pub head: Option<String>,
+    // before `</body>`
+    pub body: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    #[serde(default = "default_health_check_path")]
+    pub path: String,
+    #[serde(default)]
+    pub method: HealthCheckMethod,
+    // in seconds
+    #[serde(default = "default_health_check_interval")]
+    pub interval: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthCheckMethod {
+    Head,
+    Get,
+}
+
+impl Default for HealthCheckMethod {
+    fn default() -> HealthCheckMethod {
+        HealthCheckMethod::Head
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    RoundRobin,
+    LeastConnections,
+}
+
+impl Default for Balance {
+    fn default() -> Balance {
+        Balance::RoundRobin
+    }
+}
+
+// value and from may contain `{mirror}`, `{target}` and `{origin}`,
+// replaced by mirror host, target host and target origin
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRule {
+    pub action: HeaderAction,
+    pub name: String,
+    #[serde(default)]
+    pub value: String,
+    // substring replaced by value, for `replace`
+    #[serde(default)]
+    pub from: String,
+    // of responses, the rule applies to those matching all given, any if empty
+    #[serde(default)]
+    pub status: Vec<u16>,
+    #[serde(default)]
+    pub content_types: Vec<String>,
+    // of mirror, `/api/*` with `*` for the rest of path, or a whole path
+    pub path: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum HeaderAction {
+    Set,
+    Add,
+    Remove,
+    Replace,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum CspPolicy {
+    // map domain names in it to mirrors
+    Rewrite,
+    Drop,
+    Keep,
+}
+
+impl Default for CspPolicy {
+    fn default() -> CspPolicy {
+        CspPolicy::Rewrite
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CanonicalPolicy {
+    // mapped like other links, pointing at the mirror
+    Rewrite,
+    // removed, so crawlers fall back to urls they fetched
+    Strip,
+}
+
+impl Default for CanonicalPolicy {
+    fn default() -> CanonicalPolicy {
+        CanonicalPolicy::Rewrite
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum HstsPolicy {
+    Keep,
+    Drop,
+    // without includeSubDomains and preload, which would cover other mirrors
+    Rewrite,
+}
+
+impl Default for HstsPolicy {
+    fn default() -> HstsPolicy {
+        HstsPolicy::Keep
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct CookieConfig {
+    #[serde(default)]
+    pub secure: CookieSecure,
+    #[serde(default)]
+    pub same_site: CookieSameSite,
+    // cookies of targets are kept by the mirror instead of clients
+    pub jar: Option<CookieJarConfig>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CookieJarConfig {
+    // cookie naming the session of a client
+    #[serde(default = "default_cookie_jar_name")]
+    pub name: String,
+    // seconds a session is kept since its last use
+    #[serde(default = "default_cookie_jar_max_age")]
+    pub max_age: u64,
+    #[serde(default = "default_cookie_jar_max_sessions")]
+    pub max_sessions: usize,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum CookieSecure {
+    Keep,
+    Add,
+    Remove,
+    // keep over https, remove over http
+    Auto,
+}
+
+impl Default for CookieSecure {
+    fn default() -> CookieSecure {
+        CookieSecure::Auto
+    }
+}
+
+// anything other than keep and remove sets the attribute
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum CookieSameSite {
+    Keep,
+    Remove,
+    None,
+    Lax,
+    Strict,
+}
+
+impl Default for CookieSameSite {
+    fn default() -> CookieSameSite {
+        CookieSameSite::Keep
+    }
+}
+
+// headers telling target about the client
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardedConfig {
+    #[serde(default)]
+    pub x_forwarded_for: bool,
+    #[serde(default)]
+    pub x_forwarded_proto: bool,
+    // rfc 7239
+    #[serde(default)]
+    pub forwarded: bool,
+    // cidr of proxies in front of the mirror, whose forwarded headers are believed
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+}
+
+// in seconds, 0 for no timeout
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutConfig {
+    // including proxy and tls handshake, default 10
+    pub connect: Option<u64>,
+    // waiting for target to send, default 60
+    pub read: Option<u64>,
+    // waiting for target to receive, default 60
+    pub write: Option<u64>,
+    // until head of response is received, default none
+    pub total: Option<u64>,
+}
+
+// for GET and HEAD requests
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    // retries after the first attempt
+    pub count: u32,
+    // in milliseconds, doubled after each retry
+    #[serde(default = "default_retry_backoff")]
+    pub backoff: u64,
+    // failure to connect to or talk with target
+    #[serde(default = "default_true")]
+    pub on_error: bool,
+    #[serde(default = "default_true")]
+    pub on_timeout: bool,
+    // status codes of response
+    #[serde(default)]
+    pub statuses: Vec<u16>,
+}
+
+// a target is not contacted for cooldown after failing in a row,
+// errors and 502 to 504 are failures
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    #[serde(default = "default_circuit_breaker_failures")]
+    pub failures: u32,
+    // in seconds
+    #[serde(default = "default_circuit_breaker_cooldown")]
+    pub cooldown: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    // requests per second
+    pub rate: f64,
+    // defaults to rate
+    pub burst: Option<f64>,
+    #[serde(default)]
+    pub key: RateLimitKey,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    // ip of client
+    Client,
+    // mirror domain
+    Domain,
+    ClientDomain,
+}
+
+impl Default for RateLimitKey {
+    fn default() -> RateLimitKey {
+        RateLimitKey::Client
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CompressConfig {
+    // default to rewrite_content_types
+    pub content_types: Option<Vec<String>>,
+    // in order of preference
+    #[serde(default = "default_compress_encodings")]
+    pub encodings: Vec<String>,
+    // bytes, smaller responses of known length are left plain
+    #[serde(default = "default_compress_min_size")]
+    pub min_size: u64,
+}
+
+fn default_upstream_encodings() -> Vec<String> {
+    ENCODINGS.iter().map(|i| i.to_string()).collect()
+}
+
+fn default_compress_encodings() -> Vec<String> {
+    vec!["br".to_string(), "gzip".to_string()]
+}
+
+fn default_compress_min_size() -> u64 {
+    1024
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    // in bytes
+    #[serde(default = "default_cache_size")]
+    pub size: usize,
+    // in seconds, used when response has no freshness information
+    #[serde(default = "default_cache_ttl")]
+    pub ttl: u64,
+    // in seconds, an entry stale for less is served while fetched again
+    // in the background
+    #[serde(default)]
+    pub stale_while_revalidate: u64,
+    // in seconds, an entry stale for less is served when target fails
+    #[serde(default)]
+    pub stale_if_error: u64,
+    pub disk: Option<DiskCacheConfig>,
+    // subresources of html pages are fetched into cache once a page is sent
+    pub prefetch: Option<PrefetchConfig>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PrefetchConfig {
+    // of a page, fetched one by one
+    #[serde(default = "default_prefetch_max_links")]
+    pub max_links: usize,
+}
+
+// bytes of bodies of a UTC day or month, requests get status once they are served
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaConfig {
+    pub daily: Option<u64>,
+    pub monthly: Option<u64>,
+    // 429 or 503
+    #[serde(default = "default_quota_status")]
+    pub status: u16,
+}
+
+// converted images are cached as other responses by cache
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ImageConfig {
+    // of image/jpeg and image/png
+    #[serde(default = "default_image_content_types")]
+    pub content_types: Vec<String>,
+    // of jpeg, 1 to 100
+    #[serde(default = "default_image_quality")]
+    pub quality: u8,
+    // in pixels, larger images are scaled down with aspect ratio kept
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    // in bytes, larger images are left as they are
+    #[serde(default = "default_image_max_size")]
+    pub max_size: u64,
+    // width times height, larger images are left as they are, read from their
+    // header before decoding, as a small file may decode to gigabytes
+    #[serde(default = "default_image_max_pixels")]
+    pub max_pixels: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CassetteConfig {
+    pub dir: String,
+    pub mode: CassetteMode,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum CassetteMode {
+    // exchanges with targets are saved, bodies are read whole
+    Record,
+    // responses are served from saved ones, targets are never connected
+    Replay,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DiskCacheConfig {
+    pub dir: String,
+    // in bytes
+    #[serde(default = "default_disk_cache_size")]
+    pub size: u64,
+    // in seconds, files unused this long are removed
+    #[serde(default = "default_disk_cache_max_age")]
+    pub max_age: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RedirectConfig {
+    pub listen_address: Targets,
+    // of Location, default to that of listen_tls_address, left out if 443
+    pub port: Option<u16>,
+    // 301, 302, 307 or 308
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CertificateConfig {
+    // PEM encoded certificate chain and PKCS #8 private key
+    pub cert_file: String,
+    pub key_file: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AcmeConfig {
+    // account key, certificates and keys are kept in it
+    pub dir: String,
+    pub email: String,
+    // directory of let's encrypt staging, for trying out
+    #[serde(default)]
+    pub staging: bool,
+    // names of the certificate, default to mirror domains but wildcard ones
+    #[serde(default)]
+    pub domains: Vec<String>,
+    // renewed when it expires in fewer days
+    #[serde(default = "default_acme_renew_days")]
+    pub renew_days: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    // server name sent and verified instead of host of target
+    pub sni: Option<String>,
+    // PEM encoded certificates trusted besides the system ones
+    pub ca_file: Option<String>,
+    // accept any certificate, for test environments only
+    #[serde(default)]
+    pub insecure: bool,
+    // client certificate chain and PKCS #8 key, PEM encoded, for targets requiring mtls
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct UnixConfig {
+    pub path: String,
+    // octal permissions of socket file
+    #[serde(default = "default_unix_mode")]
+    pub mode: String,
+}
+
+impl UnixConfig {
+    pub fn mode(&self) -> Result<u32> {
+        u32::from_str_radix(&self.mode, 8).map_err(|_| anyhow!("invalid mode: {}", self.mode))
+    }
+}
+
+fn default_unix_mode() -> String {
+    "660".to_string()
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PoolConfig {
+    // in seconds
+    #[serde(default = "default_pool_idle_timeout")]
+    pub idle_timeout: u64,
+    // idle connections kept per target, 0 disables pooling
+    #[serde(default = "default_pool_max_idle")]
+    pub max_idle: usize,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ResponseLimitConfig {
+    // bytes of a response from target, larger ones get 502
+    pub max_size: Option<u64>,
+    // bytes per second sent to each client
+    pub bandwidth: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RequestIdConfig {
+    // carries the id in response, from trusted proxies and to targets
+    #[serde(default = "default_request_id_header")]
+    pub header: String,
+    // send the id to targets
+    #[serde(default)]
+    pub forward: bool,
+}
+
+impl Default for RequestIdConfig {
+    fn default() -> RequestIdConfig {
+        RequestIdConfig {
+            header: default_request_id_header(),
+            forward: false,
+        }
+    }
+}
+
+fn default_request_id_header() -> String {
+    "x-request-id".to_string()
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig {
+            idle_timeout: default_pool_idle_timeout(),
+            max_idle: default_pool_max_idle(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLogConfig {
+    // file path, or `stdout`
+    pub path: String,
+    #[serde(default)]
+    pub format: AccessLogFormat,
+    // in bytes, rotate file when its size exceeds
+    pub rotate_size: Option<u64>,
+    // rotated files kept
+    #[serde(default = "default_rotate_keep")]
+    pub rotate_keep: usize,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    // apache combined log format, followed by target and milliseconds taken
+    Combined,
+    // a json object per line
+    Json,
+}
+
+impl Default for AccessLogFormat {
+    fn default() -> AccessLogFormat {
+        AccessLogFormat::Combined
+    }
+}
+
+fn default_rewrite_content_types() -> Vec<String> {
+    [
+        "text/html",
+        "text/css",
+        "text/javascript",
+        "text/xml",
+        "application/javascript",
+        "application/json",
+        "application/manifest+json",
+        "application/xml",
+        "application/rss+xml",
+        "application/atom+xml",
+        "image/svg+xml",
+    ]
+    .iter()
+    .map(|i| i.to_string())
+    .collect()
+}
+
+fn default_passthrough_content_types() -> Vec<String> {
+    [
+        "image/*",
+        "video/*",
+        "audio/*",
+        "font/*",
+        "application/octet-stream",
+        "application/pdf",
+        "application/wasm",
+        "application/zip",
+    ]
+    .iter()
+    .map(|i| i.to_string())
+    .collect()
+}
+
+fn default_quota_status() -> u16 {
+    429
+}
+
+fn default_image_content_types() -> Vec<String> {
+    vec!["image/jpeg".to_string(), "image/png".to_string()]
+}
+
+fn default_image_quality() -> u8 {
+    80
+}
+
+fn default_image_max_size() -> u64 {
+    16 * 1024 * 1024
+}
+
+fn default_image_max_pixels() -> u64 {
+    40_000_000
+}
+
+fn default_cache_size() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_cache_ttl() -> u64 {
+    60
+}
+
+fn default_prefetch_max_links() -> usize {
+    16
+}
+
+fn default_disk_cache_size() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_disk_cache_max_age() -> u64 {
+    7 * 24 * 3600
 }
 
 impl Config {
-    pub fn from_env() -> Result<Config> {
-        let file = std::env::var("CONFIG_FILE")?;
-        let file = File::open(&file)?;
-        let config = serde_yaml::from_reader(file)?;
+    // yaml, or toml if path ends with `.toml`, syntax errors tell the line,
+    // others the field
+    pub fn from_file(path: &str) -> Result<Config> {
+        Config::from_value(path, parse(path)?)
+    }
+
+    fn from_value(path: &str, value: Value) -> Result<Config> {
+        let value = substitute(value).map_err(|err| anyhow!("{}: {}", path, err))?;
+        let config: Config = serde_path_to_error::deserialize(value)
+            .map_err(|err| anyhow!("{}: {}: {}", path, err.path(), err.inner()))?;
+        config.validate()?;
         Ok(config)
     }
+
+    // a config file as yaml with every domain a section of its own, like
+    // `x.com: { target: www.google.com }` for `x.com: www.google.com`, so
+    // options are added to it, comments are lost and `${VAR}` kept
+    pub fn migrate(path: &str) -> Result<String> {
+        let mut value = parse(path)?;
+        let domains = value
+            .get_mut("domain_name")
+            .and_then(|i| i.as_mapping_mut())
+            .ok_or_else(|| anyhow!("{}: domain_name is missing", path))?;
+        let names: Vec<_> = domains.iter().map(|(k, _)| k.clone()).collect();
+        for name in names {
+            let target = match domains.get_mut(&name) {
+                Some(target) if !target.is_mapping() => target,
+                _ => continue,
+            };
+            let mut section = Mapping::new();
+            section.insert("target".into(), target.clone());
+            *target = Value::Mapping(section);
+        }
+        Config::from_value(path, value.clone())?;
+        Ok(serde_yaml::to_string(&value)?)
+    }
+
+    // defaults of every option, listening nowhere and mirroring nothing
+    pub fn empty() -> Config {
+        serde_yaml::from_str("{ listen_address: [], domain_name: {} }").unwrap()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for (name, site) in &self.sites {
+            if !site.sites.is_empty() {
+                return Err(anyhow!("site {} has sites", name));
+            }
+            site.validate().map_err(|err| anyhow!("site {}: {}", name, err))?;
+        }
+        if self.listen_tls_address.is_some() && self.acme.is_none() && self.certificates.is_empty()
+        {
+            if self.cert_file.is_none() {
+                return Err(anyhow!("cert_file is required by listen_tls_address"));
+            }
+            if self.key_file.is_none() {
+                return Err(anyhow!("key_file is required by listen_tls_address"));
+            }
+        }
+        let encodings = self
+            .upstream_encodings
+            .iter()
+            .chain(self.compress.iter().flat_map(|i| &i.encodings));
+        for encoding in encodings {
+            if !ENCODINGS.contains(&encoding.as_str()) {
+                return Err(anyhow!("unsupported encoding: {}", encoding));
+            }
+        }
+        let addrs = self
+            .listen_address
+            .as_slice()
+            .iter()
+            .chain(self.listen_tls_address.iter().flat_map(|i| i.as_slice()))
+            .chain(self.https_redirect.iter().flat_map(|i| i.listen_address.as_slice()))
+            .chain(&self.admin_address);
+        for addr in addrs {
+            addr.parse::<SocketAddr>()
+                .map_err(|_| anyhow!("invalid listen address: {}", addr))?;
+        }
+        // anyone who can reach it could change domains
+        if let Some(addr) = &self.admin_address {
+            let loopback = addr.parse::<SocketAddr>().map_or(false, |i| i.ip().is_loopback());
+            if !loopback && self.admin_token.as_deref().map_or(true, str::is_empty) {
+                return Err(anyhow!("admin_token is required by admin_address not on loopback"));
+            }
+        }
+        if let Some(redirect) = &self.https_redirect {
+            if ![301, 302, 307, 308].contains(&redirect.status) {
+                return Err(anyhow!("invalid redirect status: {}", redirect.status));
+            }
+        }
+        let breakers = self.circuit_breaker.iter().chain(
+            self.domain_name
+                .values()
+                .filter_map(|i| i.options())
+                .filter_map(|i| i.circuit_breaker.as_ref()),
+        );
+        for breaker in breakers {
+            if breaker.failures == 0 {
+                return Err(anyhow!("circuit_breaker failures must be positive"));
+            }
+        }
+        let quotas = self.quota.iter().chain(
+            self.domain_name
+                .values()
+                .filter_map(|i| i.options())
+                .filter_map(|i| i.quota.as_ref()),
+        );
+        for quota in quotas {
+            if ![429, 503].contains(&quota.status) {
+                return Err(anyhow!("invalid quota status: {}", quota.status));
+            }
+        }
+        let images = self.image.iter().chain(
+            self.domain_name
+                .values()
+                .filter_map(|i| i.options())
+                .filter_map(|i| i.image.as_ref()),
+        );
+        for image in images {
+            if image.quality == 0 || image.quality > 100 {
+                return Err(anyhow!("invalid image quality: {}", image.quality));
+            }
+            // image/webp among them, which can't be encoded
+            for content_type in &image.content_types {
+                if !matches!(content_type.as_str(), "image/jpeg" | "image/png") {
+                    return Err(anyhow!("unsupported image content type: {}", content_type));
+                }
+            }
+        }
+        let bind_addresses = self.bind_address.iter().chain(
+            self.domain_name
+                .values()
+                .filter_map(|i| i.options())
+                .filter_map(|i| i.bind_address.as_ref()),
+        );
+        for addr in bind_addresses {
+            addr.parse::<IpAddr>()
+                .map_err(|_| anyhow!("invalid bind address: {}", addr))?;
+        }
+        for (domain, name) in &self.domain_name {
+            validate_domain(domain, name)
+                .map_err(|err| anyhow!("domain_name {}: {}", domain, err))?;
+        }
+        if let Some(unix) = &self.listen_unix {
+            unix.mode()?;
+        }
+        Ok(())
+    }
+}
+
+// `${VAR}` and `${VAR:-default}` in keys and values are replaced by environment
+// variables, a value of only `${VAR}` may become a number or bool
+fn parse(path: &str) -> Result<Value> {
+    let text = fs::read_to_string(path)?;
+    let value = if path.ends_with(".toml") {
+        toml::from_str(&text).map_err(|err| anyhow!("{}: {}", path, err))?
+    } else {
+        serde_yaml::from_str(&text).map_err(|err| anyhow!("{}: {}", path, err))?
+    };
+    Ok(value)
+}
+
+fn substitute(value: Value) -> Result<Value> {
+    let value = match value {
+        Value::String(s) if s.contains("${") => {
+            let expanded = expand(&s)?;
+            let whole = s.starts_with("${") && s.ends_with('}') && s.matches("${").count() == 1;
+            match serde_yaml::from_str(&expanded) {
+                Ok(value @ Value::Number(_)) | Ok(value @ Value::Bool(_)) if whole => value,
+                _ => Value::String(expanded),
+            }
+        }
+        Value::Sequence(values) => {
+            Value::Sequence(values.into_iter().map(substitute).collect::<Result<_>>()?)
+        }
+        Value::Mapping(mapping) => Value::Mapping(
+            mapping
+                .into_iter()
+                .map(|(k, v)| Ok((substitute(k)?, substitute(v)?)))
+                .collect::<Result<_>>()?,
+        ),
+        value => value,
+    };
+    Ok(value)
+}
+
+fn expand(s: &str) -> Result<String> {
+    let mut expanded = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .map(|i| i + start)
+            .ok_or(anyhow!("unclosed ${{ in {}", s))?;
+        let reference = &rest[start + 2..end];
+        let (name, default) = match reference.find(":-") {
+            Some(i) => (&reference[..i], Some(&reference[i + 2..])),
+            None => (reference, None),
+        };
+        match (std::env::var(name), default) {
+            (Ok(value), _) => expanded.push_str(&value),
+            (Err(_), Some(default)) => expanded.push_str(default),
+            (Err(_), None) => return Err(anyhow!("environment variable {} is not set", name)),
+        }
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn validate_domain(key: &str, name: &DomainName) -> Result<()> {
+    let host = key.split('/').next().unwrap_or_default();
+    let wildcard = host.starts_with("*.");
+    let valid = host.trim_start_matches("*.").split('.').all(|label| {
+        !label.is_empty()
+            && label
+                .bytes()
+                .all(|i| i.is_ascii_alphanumeric() || i == b'-' || i == b'_')
+    });
+    if !valid {
+        return Err(anyhow!("invalid mirror domain"));
+    }
+    if name.targets().is_empty() {
+        return Err(anyhow!("empty target list"));
+    }
+    for target in name.targets() {
+        if target.contains('*') != wildcard {
+            return Err(anyhow!("wildcard domain and wildcard target go together"));
+        }
+        // as parsed into Target
+        let url = target.replace('*', "wildcard");
+        let url = if url.contains("://") {
+            url
+        } else {
+            format!("https://{}", url)
+        };
+        let url: Url = url
+            .parse()
+            .map_err(|err| anyhow!("invalid target {}: {}", target, err))?;
+        if url.host_str().is_none() || url.port_or_known_default().is_none() {
+            return Err(anyhow!("invalid target {}", target));
+        }
+    }
+    let options = match name {
+        DomainName::Options(options) => options,
+        DomainName::Target(_) => return Ok(()),
+    };
+    if let Some(tls) = &options.tls {
+        if tls.cert_file.is_some() != tls.key_file.is_some() {
+            return Err(anyhow!("tls needs both cert_file and key_file"));
+        }
+    }
+    for rule in &options.request_headers {
+        if !rule.status.is_empty() || !rule.content_types.is_empty() {
+            return Err(anyhow!("request header rule of {} matches responses", rule.name));
+        }
+    }
+    for rule in &options.path_rules {
+        let paths = [&rule.from, &rule.to];
+        let valid = paths
+            .iter()
+            .all(|i| i.starts_with('/') && !i.trim_end_matches('*').contains('*'));
+        if !valid || rule.from.ends_with('*') != rule.to.ends_with('*') {
+            return Err(anyhow!("invalid path rule from {} to {}", rule.from, rule.to));
+        }
+    }
+    Ok(())
+}
+
+fn default_pool_idle_timeout() -> u64 {
+    30
+}
+
+fn default_pool_max_idle() -> usize {
+    16
+}
+
+fn default_rotate_keep() -> usize {
+    5
+}
+
+fn default_health_check_path() -> String {
+    "/".to_string()
+}
+
+fn default_health_check_interval() -> u64 {
+    10
+}
+
+fn default_circuit_breaker_failures() -> u32 {
+    5
+}
+
+fn default_circuit_breaker_cooldown() -> u64 {
+    30
+}
+
+fn default_retry_backoff() -> u64 {
+    100
+}
+
+fn default_responder_status() -> u16 {
+    200
+}
+
+fn default_cors_allow_origins() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+fn default_cors_allow_methods() -> Vec<String> {
+    ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"]
+        .iter()
+        .map(|i| i.to_string())
+        .collect()
+}
+
+fn default_cookie_jar_name() -> String {
+    "jingzi_session".to_string()
+}
+
+fn default_cookie_jar_max_age() -> u64 {
+    24 * 3600
+}
+
+fn default_cookie_jar_max_sessions() -> usize {
+    10000
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+fn default_redirect_status() -> u16 {
+    301
+}
+
+fn default_acme_renew_days() -> u64 {
+    30
 }
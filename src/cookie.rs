@@ -0,0 +1,120 @@
+use crate::config::{CookieConfig, CookieSameSite, CookieSecure};
+
+// map_domain gives the mirror domain for the value of `Domain=`, the
+// attribute is removed if there is none, prefix of route is added to `Path=`,
+// https tells whether the client reached the mirror over https
+pub fn rewrite<F>(
+    cookie: &str,
+    map_domain: F,
+    prefix: &str,
+    https: bool,
+    config: &CookieConfig,
+) -> String
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let keep_secure = match config.secure {
+        CookieSecure::Keep | CookieSecure::Add => true,
+        CookieSecure::Remove => false,
+        CookieSecure::Auto => https,
+    };
+    let mut parts = cookie.split(';');
+    let mut result = vec![parts.next().unwrap_or_default().to_string()];
+    let mut secure = false;
+    let mut same_site = false;
+    for attr in parts {
+        let attr = attr.trim();
+        let (name, value) = match attr.find('=') {
+            Some(i) => (&attr[..i], attr[i + 1..].trim()),
+            None => (attr, ""),
+        };
+        match name.trim().to_lowercase().as_str() {
+            "domain" => {
+                if let Some(domain) = map_domain(value) {
+                    result.push(format!("Domain={}", domain));
+                }
+            }
+            "path" if !prefix.is_empty() => {
+                let path = value.trim_end_matches('/');
+                result.push(format!("Path={}{}", prefix, path))
+            }
+            "secure" => {
+                if keep_secure {
+                    secure = true;
+                    result.push(attr.to_string());
+                }
+            }
+            "samesite" => {
+                same_site = true;
+                match config.same_site {
+                    CookieSameSite::Keep => result.push(attr.to_string()),
+                    CookieSameSite::Remove => (),
+                    _ => result.push(same_site_attr(config.same_site).to_string()),
+                }
+            }
+            _ => result.push(attr.to_string()),
+        }
+    }
+    if !secure && matches!(config.secure, CookieSecure::Add) {
+        secure = true;
+        result.push("Secure".to_string());
+    }
+    if !same_site {
+        if let CookieSameSite::None | CookieSameSite::Lax | CookieSameSite::Strict = config.same_site
+        {
+            result.push(same_site_attr(config.same_site).to_string());
+        }
+    }
+    // browsers reject `SameSite=None` without Secure, which is added over https
+    // unless removed by config
+    if !secure {
+        let none = result
+            .iter()
+            .position(|i| i.replace(' ', "").eq_ignore_ascii_case("samesite=none"));
+        if let Some(i) = none {
+            if https && !matches!(config.secure, CookieSecure::Remove) {
+                result.push("Secure".to_string());
+            } else {
+                result[i] = "SameSite=Lax".to_string();
+            }
+        }
+    }
+    result.join("; ")
+}
+
+// cookies folded into one Set-Cookie value by commas, which also appear in
+// `Expires=Wed, 21 Oct 2015 07:28:00 GMT`, a comma starts a new cookie only
+// when `name=` follows it
+pub fn split(value: &str) -> Vec<&str> {
+    let mut cookies = Vec::new();
+    let mut start = 0;
+    for (i, _) in value.match_indices(',') {
+        let rest = &value[i + 1..];
+        let end = rest
+            .find(|c: char| c == ';' || c == ',')
+            .unwrap_or_else(|| rest.len());
+        let starts_cookie = match rest[..end].find('=') {
+            Some(eq) => {
+                let name = rest[..eq].trim();
+                !name.is_empty() && !name.contains(char::is_whitespace)
+            }
+            None => false,
+        };
+        if starts_cookie {
+            cookies.push(value[start..i].trim());
+            start = i + 1;
+        }
+    }
+    cookies.push(value[start..].trim());
+    cookies.retain(|i| !i.is_empty());
+    cookies
+}
+
+fn same_site_attr(same_site: CookieSameSite) -> &'static str {
+    match same_site {
+        CookieSameSite::None => "SameSite=None",
+        CookieSameSite::Lax => "SameSite=Lax",
+        CookieSameSite::Strict => "SameSite=Strict",
+        CookieSameSite::Keep | CookieSameSite::Remove => "",
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::config::CookieJarConfig;
+
+struct Cookie {
+    name: String,
+    value: String,
+    domain: String,
+    // without `Domain=`, not sent to subdomains
+    host_only: bool,
+    path: String,
+    expires: Option<SystemTime>,
+}
+
+impl Cookie {
+    fn matches(&self, host: &str, path: &str) -> bool {
+        let domain =
+            self.domain == host || !self.host_only && host.ends_with(&format!(".{}", self.domain));
+        let path = path == self.path
+            || path.starts_with(&self.path)
+                && (self.path.ends_with('/') || path[self.path.len()..].starts_with('/'));
+        domain && path && self.expires.map_or(true, |i| SystemTime::now() < i)
+    }
+}
+
+struct Session {
+    cookies: Vec<Cookie>,
+    used: Instant,
+}
+
+// cookies of targets kept by the mirror, clients only get a cookie naming
+// their session
+pub struct CookieJar {
+    name: String,
+    max_age: Duration,
+    max_sessions: usize,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl CookieJar {
+    pub fn new(config: &CookieJarConfig) -> CookieJar {
+        CookieJar {
+            name: config.name.clone(),
+            max_age: Duration::from_secs(config.max_age),
+            max_sessions: config.max_sessions,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // session named by `Cookie` of client if known, and the header without it
+    pub fn take_session(&self, cookie: Option<&str>) -> (Option<String>, Option<String>) {
+        let mut session = None;
+        let mut rest = Vec::new();
+        for pair in cookie.unwrap_or_default().split(';') {
+            let pair = pair.trim();
+            match pair.find('=') {
+                Some(i) if pair[..i].trim() == self.name => {
+                    session = Some(pair[i + 1..].trim().to_string());
+                }
+                _ if pair.is_empty() => (),
+                _ => rest.push(pair),
+            }
+        }
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        // unknown ids are not taken, or a client could be given the session of another
+        let session = session.filter(|id| match sessions.get_mut(id) {
+            Some(session) if now.duration_since(session.used) < self.max_age => {
+                session.used = now;
+                true
+            }
+            _ => false,
+        });
+        let rest = if rest.is_empty() {
+            None
+        } else {
+            Some(rest.join("; "))
+        };
+        (session, rest)
+    }
+
+    pub fn new_session(&self) -> io::Result<String> {
+        let mut id = [0; 16];
+        File::open("/dev/urandom")?.read_exact(&mut id)?;
+        let id: String = id.iter().map(|i| format!("{:02x}", i)).collect();
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.len() >= self.max_sessions {
+            let max_age = self.max_age;
+            sessions.retain(|_, i| now.duration_since(i.used) < max_age);
+        }
+        if sessions.len() >= self.max_sessions {
+            let oldest = sessions
+                .iter()
+                .min_by_key(|(_, i)| i.used)
+                .map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                sessions.remove(&oldest);
+            }
+        }
+        sessions.insert(
+            id.clone(),
+            Session {
+                cookies: Vec::new(),
+                used: now,
+            },
+        );
+        Ok(id)
+    }
+
+    // `Set-Cookie` of the session for client
+    pub fn session_cookie(&self, id: &str, secure: bool) -> String {
+        let mut cookie = format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+            self.name,
+            id,
+            self.max_age.as_secs()
+        );
+        if secure {
+            cookie.push_str("; Secure");
+        }
+        cookie
+    }
+
+    // value of `Cookie` for a request to target, longer paths first
+    pub fn cookies(&self, id: &str, host: &str, path: &str) -> Option<String> {
+        let sessions = self.sessions.lock().unwrap();
+        let mut cookies: Vec<_> = sessions
+            .get(id)?
+            .cookies
+            .iter()
+            .filter(|i| i.matches(host, path))
+            .collect();
+        if cookies.is_empty() {
+            return None;
+        }
+        cookies.sort_by(|a, b| b.path.len().cmp(&a.path.len()));
+        let cookies: Vec<_> = cookies
+            .iter()
+            .map(|i| format!("{}={}", i.name, i.value))
+            .collect();
+        Some(cookies.join("; "))
+    }
+
+    // keep a `Set-Cookie` of target, a past expiry removes the cookie
+    pub fn store(&self, id: &str, host: &str, path: &str, set_cookie: &str) {
+        let mut parts = set_cookie.split(';');
+        let pair = parts.next().unwrap_or_default();
+        let (name, value) = match pair.find('=') {
+            Some(i) => (pair[..i].trim(), pair[i + 1..].trim()),
+            None => return,
+        };
+        let mut cookie = Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            domain: host.to_string(),
+            host_only: true,
+            path: default_path(path).to_string(),
+            expires: None,
+        };
+        let mut max_age = None;
+        for attr in parts {
+            let (k, v) = match attr.find('=') {
+                Some(i) => (attr[..i].trim(), attr[i + 1..].trim()),
+                None => (attr.trim(), ""),
+            };
+            match k.to_lowercase().as_str() {
+                "domain" => {
+                    let domain = v.trim_start_matches('.').to_lowercase();
+                    // a target can not set cookies for unrelated domains
+                    if host == domain || host.ends_with(&format!(".{}", domain)) {
+                        cookie.domain = domain;
+                        cookie.host_only = false;
+                    }
+                }
+                "path" if v.starts_with('/') => cookie.path = v.to_string(),
+                "max-age" => max_age = v.parse::<i64>().ok(),
+                "expires" => cookie.expires = httpdate::parse_http_date(v).ok(),
+                _ => (),
+            }
+        }
+        // Max-Age takes precedence over Expires
+        if let Some(max_age) = max_age {
+            cookie.expires = Some(if max_age > 0 {
+                SystemTime::now() + Duration::from_secs(max_age as u64)
+            } else {
+                SystemTime::UNIX_EPOCH
+            });
+        }
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = match sessions.get_mut(id) {
+            Some(session) => session,
+            None => return,
+        };
+        session.cookies.retain(|i| {
+            i.name != cookie.name || i.domain != cookie.domain || i.path != cookie.path
+        });
+        if cookie.expires.map_or(true, |i| SystemTime::now() < i) {
+            session.cookies.push(cookie);
+        }
+    }
+}
+
+// directory of request path, RFC 6265 5.1.4
+fn default_path(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(i) => &path[..i],
+    }
+}
@@ -0,0 +1,83 @@
+use http_types::{Method, Request, Response, StatusCode};
+
+use crate::config::CorsConfig;
+
+pub struct Cors {
+    config: CorsConfig,
+}
+
+impl Cors {
+    pub fn new(config: &CorsConfig) -> Cors {
+        Cors {
+            config: config.clone(),
+        }
+    }
+
+    // answered here instead of by target
+    pub fn preflight(&self, req: &Request) -> Option<Response> {
+        if req.method() != Method::Options || req.header("access-control-request-method").is_none()
+        {
+            return None;
+        }
+        let origin = req.header("origin").map(|i| i.as_str());
+        let mut resp = Response::new(StatusCode::NoContent);
+        if !self.allow_origin(origin, &mut resp) {
+            return Some(resp);
+        }
+        let methods = &self.config.allow_methods;
+        resp.insert_header("access-control-allow-methods", methods.join(", "));
+        let headers = if self.config.allow_headers.is_empty() {
+            // whatever is asked
+            req.header("access-control-request-headers")
+                .map(|i| i.as_str().to_string())
+        } else {
+            Some(self.config.allow_headers.join(", "))
+        };
+        if let Some(headers) = headers {
+            resp.insert_header("access-control-allow-headers", headers);
+        }
+        if let Some(max_age) = self.config.max_age {
+            resp.insert_header("access-control-max-age", max_age.to_string());
+        }
+        Some(resp)
+    }
+
+    // headers of target are replaced, they name origin hosts
+    pub fn apply(&self, origin: Option<&str>, resp: &mut Response) {
+        let names: Vec<_> = resp
+            .header_names()
+            .filter(|i| i.as_str().starts_with("access-control-"))
+            .cloned()
+            .collect();
+        for name in names {
+            resp.remove_header(name);
+        }
+        if !self.allow_origin(origin, resp) {
+            return;
+        }
+        if !self.config.expose_headers.is_empty() {
+            let headers = self.config.expose_headers.join(", ");
+            resp.insert_header("access-control-expose-headers", headers);
+        }
+    }
+
+    // false if origin is not allowed
+    fn allow_origin(&self, origin: Option<&str>, resp: &mut Response) -> bool {
+        let any = self.config.allow_origins.iter().any(|i| i == "*");
+        if any && !self.config.allow_credentials {
+            resp.insert_header("access-control-allow-origin", "*");
+            return true;
+        }
+        // allowed origin is sent back, so it varies
+        resp.append_header("vary", "origin");
+        let origin = match origin {
+            Some(origin) if any || self.config.allow_origins.iter().any(|i| i == origin) => origin,
+            _ => return false,
+        };
+        resp.insert_header("access-control-allow-origin", origin);
+        if self.config.allow_credentials {
+            resp.insert_header("access-control-allow-credentials", "true");
+        }
+        true
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io::{self, Read, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use smol::{Task, Timer};
+
+use crate::config::DiskCacheConfig;
+
+const JANITOR_INTERVAL: Duration = Duration::from_secs(60);
+
+// an entry of cache besides its body
+#[derive(Serialize, Deserialize)]
+pub struct Meta {
+    pub key: String,
+    pub status: u16,
+    pub headers: Vec<(String, Vec<String>)>,
+    // unix seconds
+    pub expires: u64,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+struct Index {
+    // file name to its size and last use
+    files: HashMap<String, (u64, SystemTime)>,
+    used: u64,
+}
+
+// entries of cache kept in files of a directory, so rewritten bodies outgrow
+// memory and outlive restarts, a file is meta as a json line and the body,
+// all of it is blocking
+pub struct DiskCache {
+    dir: PathBuf,
+    size: u64,
+    max_age: Duration,
+    index: Mutex<Index>,
+}
+
+impl DiskCache {
+    // files left by an earlier run are taken, unused ones are removed by a janitor
+    pub fn new(config: &DiskCacheConfig) -> Result<Arc<DiskCache>> {
+        let dir = PathBuf::from(&config.dir);
+        fs::create_dir_all(&dir)?;
+        let mut files = HashMap::new();
+        let mut used = 0;
+        for file in fs::read_dir(&dir)? {
+            let file = file?;
+            let meta = file.metadata()?;
+            if !meta.is_file() {
+                continue;
+            }
+            let name = file.file_name().to_string_lossy().to_string();
+            // of a save that never finished
+            if name.ends_with(".tmp") {
+                let _ = fs::remove_file(file.path());
+                continue;
+            }
+            used += meta.len();
+            files.insert(name, (meta.len(), meta.modified()?));
+        }
+        let cache = Arc::new(DiskCache {
+            dir,
+            size: config.size,
+            max_age: Duration::from_secs(config.max_age),
+            index: Mutex::new(Index { files, used }),
+        });
+        janitor(Arc::downgrade(&cache));
+        Ok(cache)
+    }
+
+    fn name(key: &str) -> String {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+
+    pub fn load(&self, key: &str) -> Option<(Meta, Vec<u8>)> {
+        let name = DiskCache::name(key);
+        if let Some((_, last_used)) = self.index.lock().unwrap().files.get_mut(&name) {
+            *last_used = SystemTime::now();
+        } else {
+            return None;
+        }
+        let mut data = Vec::new();
+        File::open(self.dir.join(&name))
+            .and_then(|mut i| i.read_to_end(&mut data))
+            .ok()?;
+        let end = data.iter().position(|i| *i == b'\n')?;
+        let meta: Meta = serde_json::from_slice(&data[..end]).ok()?;
+        // another key of the same hash
+        if meta.key != key {
+            return None;
+        }
+        data.drain(..=end);
+        Some((meta, data))
+    }
+
+    // written to a temporary file first, so a load never sees half of it
+    pub fn save(&self, meta: &Meta, body: &[u8]) -> io::Result<()> {
+        let name = DiskCache::name(&meta.key);
+        let tmp = self.dir.join(format!("{}.tmp", name));
+        let head = serde_json::to_vec(meta)?;
+        let mut file = File::create(&tmp)?;
+        file.write_all(&head)?;
+        file.write_all(b"\n")?;
+        file.write_all(body)?;
+        fs::rename(&tmp, self.dir.join(&name))?;
+        let len = (head.len() + 1 + body.len()) as u64;
+        let mut index = self.index.lock().unwrap();
+        if let Some((old, _)) = index.files.insert(name, (len, SystemTime::now())) {
+            index.used -= old;
+        }
+        index.used += len;
+        Ok(())
+    }
+
+    pub fn clear(&self) {
+        let files = {
+            let mut index = self.index.lock().unwrap();
+            index.used = 0;
+            std::mem::take(&mut index.files)
+        };
+        for name in files.keys() {
+            let _ = fs::remove_file(self.dir.join(name));
+        }
+    }
+
+    // files unused for max_age, then the least recently used ones over size
+    fn clean(&self) {
+        let now = SystemTime::now();
+        let mut removed = Vec::new();
+        {
+            let mut index = self.index.lock().unwrap();
+            let mut files: Vec<_> = index
+                .files
+                .iter()
+                .map(|(name, (len, last_used))| (*last_used, *len, name.clone()))
+                .collect();
+            files.sort();
+            for (last_used, len, name) in files {
+                let unused = now.duration_since(last_used).unwrap_or_default();
+                if unused <= self.max_age && index.used <= self.size {
+                    break;
+                }
+                index.files.remove(&name);
+                index.used -= len;
+                removed.push(name);
+            }
+        }
+        for name in removed {
+            let _ = fs::remove_file(self.dir.join(name));
+        }
+    }
+}
+
+// until the cache is gone by reload
+fn janitor(cache: Weak<DiskCache>) {
+    let task = Task::spawn(async move {
+        loop {
+            Timer::after(JANITOR_INTERVAL).await;
+            let cache = match cache.upgrade() {
+                Some(cache) => cache,
+                None => break,
+            };
+            smol::unblock!(cache.clean());
+        }
+    });
+    task.detach();
+}
@@ -0,0 +1,297 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use http_types::{Method, Request, Url};
+use smol::Async;
+
+use crate::{config::DnsConfig, timeout};
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+// answers are kept at most this long whatever their ttl
+const MAX_TTL: u32 = 3600;
+
+enum Server {
+    Udp(SocketAddr),
+    // RFC 7858
+    Tls { host: String, port: u16 },
+    // RFC 8484
+    Https(Url),
+}
+
+impl Server {
+    // `1.1.1.1`, `[2606:4700::1111]:53`, `tls://1.1.1.1` or `https://1.1.1.1/dns-query`
+    fn parse(s: &str) -> Result<Server> {
+        if s.starts_with("https://") {
+            return Ok(Server::Https(s.parse()?));
+        }
+        if let Some(rest) = s.strip_prefix("tls://") {
+            let (host, port) = split_port(rest, 853)?;
+            return Ok(Server::Tls { host, port });
+        }
+        let (host, port) = split_port(s.strip_prefix("udp://").unwrap_or(s), 53)?;
+        let ip: IpAddr = host
+            .parse()
+            .map_err(|_| anyhow!("dns server over udp must be an address: {}", s))?;
+        Ok(Server::Udp(SocketAddr::new(ip, port)))
+    }
+
+    // a connection per query for tls and https, answers are cached anyway
+    async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Server::Udp(addr) => {
+                let local: SocketAddr = if addr.is_ipv4() {
+                    (Ipv4Addr::UNSPECIFIED, 0).into()
+                } else {
+                    (Ipv6Addr::UNSPECIFIED, 0).into()
+                };
+                let socket = Async::<UdpSocket>::bind(local)?;
+                socket.send_to(query, *addr).await?;
+                let mut buf = vec![0; 1232];
+                loop {
+                    let (n, from) = socket.recv_from(&mut buf).await?;
+                    // not spoofed by others, or an answer to an earlier query
+                    if from == *addr && n >= 2 && buf[..2] == query[..2] {
+                        buf.truncate(n);
+                        return Ok(buf);
+                    }
+                }
+            }
+            Server::Tls { host, port } => {
+                let stream = connect(host, *port).await?;
+                let mut stream = async_native_tls::connect(host.as_str(), stream).await?;
+                stream
+                    .write_all(&(query.len() as u16).to_be_bytes())
+                    .await?;
+                stream.write_all(query).await?;
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).await?;
+                let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+                stream.read_exact(&mut buf).await?;
+                Ok(buf)
+            }
+            Server::Https(url) => {
+                let host = url
+                    .host_str()
+                    .ok_or_else(|| anyhow!("invalid dns server"))?;
+                let port = url.port_or_known_default().unwrap_or(443);
+                let stream = connect(host, port).await?;
+                let stream = async_native_tls::connect(host, stream).await?;
+                let stream = async_dup::Arc::new(async_dup::Mutex::new(stream));
+                let mut req = Request::new(Method::Post, url.clone());
+                req.insert_header("content-type", "application/dns-message");
+                req.insert_header("accept", "application/dns-message");
+                req.set_body(query.to_vec());
+                let mut resp = async_h1::connect(stream, req)
+                    .await
+                    .map_err(|err| anyhow!("{}", err))?;
+                if !resp.status().is_success() {
+                    return Err(anyhow!("dns server answered {}", resp.status()));
+                }
+                resp.body_bytes().await.map_err(|err| anyhow!("{}", err))
+            }
+        }
+    }
+}
+
+// names of tls and https servers are resolved by the system
+async fn connect(host: &str, port: u16) -> Result<Async<TcpStream>> {
+    let addr = format!("{}:{}", host, port);
+    let addr = smol::unblock!(addr.to_socket_addrs())?
+        .next()
+        .ok_or_else(|| anyhow!("invalid dns server: {}", host))?;
+    Ok(Async::<TcpStream>::connect(addr).await?)
+}
+
+fn split_port(s: &str, default: u16) -> Result<(String, u16)> {
+    if let Some(rest) = s.strip_prefix('[') {
+        let end = rest
+            .find(']')
+            .ok_or_else(|| anyhow!("invalid dns server: {}", s))?;
+        let port = match rest[end + 1..].strip_prefix(':') {
+            Some(port) => port.parse()?,
+            None => default,
+        };
+        return Ok((rest[..end].to_string(), port));
+    }
+    // a bare ipv6 address has several colons
+    match s.rfind(':') {
+        Some(i) if s.matches(':').count() == 1 => Ok((s[..i].to_string(), s[i + 1..].parse()?)),
+        _ => Ok((s.to_string(), default)),
+    }
+}
+
+// resolves host names of targets and proxies by configured servers instead of
+// the system, which may be poisoned or blocked
+pub struct Resolver {
+    servers: Vec<Server>,
+    timeout: Duration,
+    cache: Mutex<HashMap<String, (Vec<IpAddr>, Instant)>>,
+}
+
+impl Resolver {
+    pub fn new(config: &DnsConfig) -> Result<Resolver> {
+        let servers = config
+            .servers
+            .iter()
+            .map(|i| Server::parse(i))
+            .collect::<Result<Vec<_>>>()?;
+        if servers.is_empty() {
+            return Err(anyhow!("empty dns server list"));
+        }
+        Ok(Resolver {
+            servers,
+            timeout: Duration::from_secs(config.timeout),
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // servers are tried in turn
+    pub async fn lookup(&self, host: &str) -> Result<Vec<IpAddr>> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        if let Some((addrs, expires)) = self.cache.lock().unwrap().get(&host) {
+            if Instant::now() < *expires {
+                return Ok(addrs.clone());
+            }
+        }
+        let mut last_err = anyhow!("no dns server");
+        for server in &self.servers {
+            let lookup = self.query(server, &host);
+            match timeout::within(Some(self.timeout), lookup).await {
+                Ok((addrs, ttl)) => {
+                    let expires = Instant::now() + Duration::from_secs(ttl.min(MAX_TTL).into());
+                    let mut cache = self.cache.lock().unwrap();
+                    cache.retain(|_, (_, expires)| Instant::now() < *expires);
+                    cache.insert(host, (addrs.clone(), expires));
+                    return Ok(addrs);
+                }
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+
+    // AAAA and A at once, addresses and the least ttl
+    async fn query(&self, server: &Server, host: &str) -> Result<(Vec<IpAddr>, u32)> {
+        let (v6, v4) = futures::join!(
+            self.query_type(server, host, TYPE_AAAA),
+            self.query_type(server, host, TYPE_A)
+        );
+        let (addrs, ttl) = match (v6, v4) {
+            (Err(err), Err(_)) => return Err(err),
+            (Ok(v6), Err(_)) => v6,
+            (Err(_), Ok(v4)) => v4,
+            (Ok((mut v6, v6_ttl)), Ok((v4, v4_ttl))) => {
+                v6.extend(v4);
+                (v6, v6_ttl.min(v4_ttl))
+            }
+        };
+        if addrs.is_empty() {
+            return Err(anyhow!("no address of {}", host));
+        }
+        Ok((addrs, ttl))
+    }
+
+    async fn query_type(
+        &self,
+        server: &Server,
+        host: &str,
+        qtype: u16,
+    ) -> Result<(Vec<IpAddr>, u32)> {
+        // random, so forged answers can't guess it
+        let id = rand::random::<u16>();
+        let query = build_query(id, host, qtype)?;
+        let answer = server.exchange(&query).await?;
+        parse_answer(&answer, id).ok_or_else(|| anyhow!("invalid dns answer for {}", host))?
+    }
+}
+
+fn build_query(id: u16, host: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(host.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    // recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in host.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(anyhow!("invalid host: {}", host));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    // class IN
+    query.extend_from_slice(&[0, 1]);
+    Ok(query)
+}
+
+// None if malformed, addresses of A and AAAA records and their least ttl
+fn parse_answer(buf: &[u8], id: u16) -> Option<Result<(Vec<IpAddr>, u32)>> {
+    let u16_at = |i: usize| Some(u16::from_be_bytes([*buf.get(i)?, *buf.get(i + 1)?]));
+    // header of 12 bytes at least
+    if buf.len() < 12 || u16_at(0)? != id || buf[2] & 0x80 == 0 {
+        return None;
+    }
+    match buf[3] & 0x0f {
+        0 => (),
+        // no such name
+        3 => return Some(Ok((Vec::new(), 0))),
+        rcode => return Some(Err(anyhow!("dns server failed with rcode {}", rcode))),
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(buf, pos)? + 4;
+    }
+    let mut addrs = Vec::new();
+    let mut ttl = u32::MAX;
+    for _ in 0..answers {
+        pos = skip_name(buf, pos)?;
+        let rtype = u16_at(pos)?;
+        let rttl = u32::from(u16_at(pos + 4)?) << 16 | u32::from(u16_at(pos + 6)?);
+        let len = u16_at(pos + 8)? as usize;
+        let data = buf.get(pos + 10..pos + 10 + len)?;
+        pos += 10 + len;
+        // CNAME records are followed by those of the name they point to
+        let addr = match (rtype, len) {
+            (TYPE_A, 4) => {
+                let octets: [u8; 4] = [data[0], data[1], data[2], data[3]];
+                IpAddr::from(octets)
+            }
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(data);
+                IpAddr::from(octets)
+            }
+            _ => continue,
+        };
+        addrs.push(addr);
+        ttl = ttl.min(rttl);
+    }
+    Some(Ok((addrs, ttl)))
+}
+
+// position after a name, which may end with a compression pointer
+fn skip_name(buf: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *buf.get(pos)? as usize;
+        if len == 0 {
+            return Some(pos + 1);
+        }
+        if len & 0xc0 == 0xc0 {
+            return Some(pos + 2);
+        }
+        pos += 1 + len;
+    }
+}
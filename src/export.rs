@@ -0,0 +1,158 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use async_compression::futures::bufread::{
+    BrotliDecoder, DeflateDecoder, GzipDecoder, ZstdDecoder,
+};
+use futures::io::{AsyncReadExt, BufReader, Cursor};
+use http_types::{Method, Request, StatusCode, Url};
+use regex::bytes::Regex;
+
+use crate::server::Forward;
+
+// pages and files of a crawl at most
+const MAX_URLS: usize = 100_000;
+
+// crawl a mirror from start through rewriting like a client would, and write
+// what it gets under dir as static files, links leaving the mirror are not
+// followed, the number of files written is returned
+pub async fn crawl(forward: &Forward, start: Url, dir: &Path) -> Result<usize> {
+    let host = start
+        .host_str()
+        .ok_or_else(|| anyhow!("{} has no host", start))?
+        .to_string();
+    // attributes of html, and url() and @import of css
+    let links = Regex::new(concat!(
+        r#"(?i)(?:\s(?:href|src|poster|action)\s*=\s*["']?|url\(\s*["']?|@import\s+["'])"#,
+        r#"([^"'\s>)#]+)"#,
+    ))?;
+    let mut queue = VecDeque::new();
+    let mut seen = HashSet::new();
+    queue.push_back(start.clone());
+    seen.insert(start.to_string());
+    let mut written = 0;
+    while let Some(url) = queue.pop_front() {
+        let mut req = Request::new(Method::Get, url.clone());
+        req.insert_header("accept-encoding", "identity");
+        let mut resp = match forward.forward(req).await {
+            Ok(resp) => resp,
+            Err(err) => {
+                warn!("exporting {}: {}", url, err);
+                continue;
+            }
+        };
+        if resp.status() != StatusCode::Ok {
+            warn!("exporting {}: {}", url, resp.status());
+            continue;
+        }
+        let essence = resp
+            .content_type()
+            .map(|i| i.essence().to_string())
+            .unwrap_or_default();
+        let encoding = resp
+            .header("content-encoding")
+            .map(|i| i.as_str().to_string());
+        let body = resp.body_bytes().await.map_err(|err| anyhow!("{}", err))?;
+        let body = decode(body, encoding.as_deref()).await?;
+        let html = essence == "text/html";
+        let path = file_path(dir, &url, html);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, &body)?;
+        written += 1;
+        if !html && essence != "text/css" {
+            continue;
+        }
+        for link in links.captures_iter(&body) {
+            let link = String::from_utf8_lossy(&link[1]).replace("&amp;", "&");
+            let mut next = match url.join(&link) {
+                Ok(next) => next,
+                Err(_) => continue,
+            };
+            next.set_fragment(None);
+            if next.host_str() != Some(host.as_str()) || seen.len() >= MAX_URLS {
+                continue;
+            }
+            if seen.insert(next.to_string()) {
+                queue.push_back(next);
+            }
+        }
+    }
+    Ok(written)
+}
+
+// bodies of types not rewritten keep the encoding of target
+async fn decode(body: Vec<u8>, encoding: Option<&str>) -> Result<Vec<u8>> {
+    let encoding = match encoding {
+        None | Some("identity") => return Ok(body),
+        Some(encoding) => encoding,
+    };
+    let reader = BufReader::new(Cursor::new(body));
+    let mut decoded = Vec::new();
+    match encoding {
+        "gzip" => GzipDecoder::new(reader).read_to_end(&mut decoded).await?,
+        "br" => BrotliDecoder::new(reader).read_to_end(&mut decoded).await?,
+        "deflate" => {
+            DeflateDecoder::new(reader)
+                .read_to_end(&mut decoded)
+                .await?
+        }
+        "zstd" => ZstdDecoder::new(reader).read_to_end(&mut decoded).await?,
+        e => return Err(anyhow!("unhandled encoding: {}", e)),
+    };
+    Ok(decoded)
+}
+
+// `/` and `/a/` to `index.html` in them, pages without an extension too,
+// a query is kept in the file name as `%3F` and what follows
+fn file_path(dir: &Path, url: &Url, html: bool) -> PathBuf {
+    let segments: Vec<_> = url
+        .path_segments()
+        .map(|i| {
+            i.map(unescape)
+                .filter(|i| !i.is_empty() && i != "..")
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut path = dir.to_path_buf();
+    let directory =
+        url.path().ends_with('/') || html && !segments.last().map_or(false, |i| i.contains('.'));
+    let (mut name, parents) = match segments.split_last() {
+        Some((last, parents)) if !directory => (last.clone(), parents),
+        _ => ("index.html".to_string(), &segments[..]),
+    };
+    path.extend(parents);
+    if let Some(query) = url.query() {
+        name = format!("{}%3F{}", name, query.replace('/', "%2F"));
+    }
+    path.push(name);
+    path
+}
+
+// `%20` and the like of a path segment, files are named as servers look for them
+fn unescape(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|i| std::str::from_utf8(i).ok());
+        match hex.and_then(|i| u8::from_str_radix(i, 16).ok()) {
+            Some(b) if bytes[i] == b'%' && b != b'/' && b != 0 => {
+                unescaped.push(b);
+                i += 3;
+            }
+            _ => {
+                unescaped.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&unescaped).to_string()
+}
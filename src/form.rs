@@ -0,0 +1,85 @@
+use http_types::Request;
+
+use crate::rewrite::Replacements;
+
+// a longer form is sent as it is if multipart, or streamed through Rewriter
+const MAX_FORM_SIZE: usize = 4 * 1024 * 1024;
+
+// forms are rewritten whole, so targets get their length, and multipart ones
+// part by part, so boundaries and files are kept as they are, false if the
+// body is left to Rewriter
+pub async fn rewrite(req: &mut Request, replacements: &Replacements) -> http_types::Result<bool> {
+    let content_type = match req.content_type() {
+        Some(content_type) => content_type,
+        None => return Ok(false),
+    };
+    let multipart = match content_type.essence() {
+        "application/x-www-form-urlencoded" => false,
+        "multipart/form-data" => true,
+        _ => return Ok(false),
+    };
+    if req.len().map_or(true, |len| len > MAX_FORM_SIZE) {
+        return Ok(multipart);
+    }
+    let boundary = content_type
+        .param("boundary")
+        .map(|i| i.as_str().trim_matches('"').to_string());
+    let body = req.body_bytes().await?;
+    let body = match boundary {
+        Some(boundary) if multipart => rewrite_multipart(&body, &boundary, replacements),
+        // no parts to tell apart
+        None if multipart => body,
+        _ => replacements.replace_bytes(&body),
+    };
+    req.set_body(body);
+    Ok(true)
+}
+
+// the preamble, epilogue and binary parts are kept
+fn rewrite_multipart(body: &[u8], boundary: &str, replacements: &Replacements) -> Vec<u8> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let mut rewritten = Vec::with_capacity(body.len());
+    let mut rest = body;
+    let mut in_part = false;
+    loop {
+        let end = find(rest, &delimiter).unwrap_or_else(|| rest.len());
+        let (segment, next) = rest.split_at(end);
+        if in_part {
+            rewritten.extend(rewrite_part(segment, replacements));
+        } else {
+            rewritten.extend_from_slice(segment);
+        }
+        if next.is_empty() {
+            break;
+        }
+        rewritten.extend_from_slice(&delimiter);
+        rest = &next[delimiter.len()..];
+        // `--` follows the last delimiter
+        in_part = !rest.starts_with(b"--");
+    }
+    rewritten
+}
+
+// fields, and files of text types
+fn rewrite_part(part: &[u8], replacements: &Replacements) -> Vec<u8> {
+    let end = match find(part, b"\r\n\r\n") {
+        Some(end) => end + 4,
+        None => return part.to_vec(),
+    };
+    let (head, content) = part.split_at(end);
+    let lower = String::from_utf8_lossy(head).to_ascii_lowercase();
+    let content_type = lower
+        .lines()
+        .find_map(|i| i.strip_prefix("content-type:"))
+        .map(str::trim);
+    if !content_type.map_or(true, |i| i.starts_with("text/")) {
+        return part.to_vec();
+    }
+    let mut rewritten = head.to_vec();
+    rewritten.extend(replacements.replace_bytes(content));
+    rewritten
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|i| i == needle)
+}
@@ -0,0 +1,118 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::Result;
+use http_types::Request;
+
+use crate::{acl::Cidr, config::ForwardedConfig};
+
+// connection a request came from
+#[derive(Clone, Copy)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    pub tls: bool,
+}
+
+pub struct Forwarded {
+    config: ForwardedConfig,
+    trusted: Vec<Cidr>,
+}
+
+impl Forwarded {
+    pub fn new(config: &ForwardedConfig) -> Result<Forwarded> {
+        Ok(Forwarded {
+            config: config.clone(),
+            trusted: config
+                .trusted_proxies
+                .iter()
+                .map(|i| Cidr::parse(i))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    pub fn trusts(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|i| i.contains(ip))
+    }
+
+    // real client, X-Forwarded-For is walked from right while hops are trusted
+    pub fn client(&self, req: &Request, peer: Peer) -> SocketAddr {
+        if !self.trusts(peer.addr.ip()) {
+            return peer.addr;
+        }
+        let hops: Vec<IpAddr> = x_forwarded_for(req)
+            .split(',')
+            .filter_map(|i| i.trim().parse().ok())
+            .collect();
+        let mut client = peer.addr.ip();
+        for ip in hops.into_iter().rev() {
+            client = ip;
+            if !self.trusts(ip) {
+                break;
+            }
+        }
+        SocketAddr::new(client, 0)
+    }
+
+    // scheme the client used, X-Forwarded-Proto is believed from trusted proxies
+    pub fn proto(&self, req: &Request, peer: Peer) -> String {
+        let proto = req
+            .header("x-forwarded-proto")
+            .filter(|_| self.trusts(peer.addr.ip()))
+            .map(|i| i.as_str().trim().to_lowercase());
+        match proto {
+            Some(proto) => proto,
+            None if peer.tls => "https".to_string(),
+            None => "http".to_string(),
+        }
+    }
+
+    // add headers to request toward target, headers from untrusted clients are dropped,
+    // must be called before Host is changed to target
+    pub fn apply(&self, req: &mut Request, peer: Peer) {
+        let trusted = self.trusts(peer.addr.ip());
+        let proto = self.proto(req, peer);
+        let host = req
+            .header("host")
+            .map(|i| i.as_str().to_string())
+            .unwrap_or_default();
+        if self.config.x_forwarded_for {
+            let chain = x_forwarded_for(req);
+            let value = if trusted && !chain.is_empty() {
+                format!("{}, {}", chain, peer.addr.ip())
+            } else {
+                peer.addr.ip().to_string()
+            };
+            req.insert_header("x-forwarded-for", value);
+        }
+        if self.config.x_forwarded_proto {
+            req.insert_header("x-forwarded-proto", proto.as_str());
+        }
+        if self.config.forwarded {
+            let node = match peer.addr.ip() {
+                IpAddr::V4(ip) => ip.to_string(),
+                IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+            };
+            let mut element = format!("for={};proto={}", node, proto);
+            if !host.is_empty() {
+                element = format!("{};host=\"{}\"", element, host);
+            }
+            let value = match req.header("forwarded").filter(|_| trusted) {
+                Some(chain) => format!("{}, {}", chain.as_str(), element),
+                None => element,
+            };
+            req.insert_header("forwarded", value);
+        }
+    }
+}
+
+// all X-Forwarded-For headers joined
+fn x_forwarded_for(req: &Request) -> String {
+    req.header("x-forwarded-for")
+        .map(|values| {
+            values
+                .iter()
+                .map(|i| i.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .unwrap_or_default()
+}
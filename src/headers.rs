@@ -0,0 +1,87 @@
+use http_types::{headers::Headers, Response};
+
+use crate::config::{HeaderAction, HeaderRule};
+
+pub struct Vars<'a> {
+    pub mirror: &'a str,
+    pub target: &'a str,
+    pub origin: &'a str,
+}
+
+impl Vars<'_> {
+    pub fn render(&self, value: &str) -> String {
+        value
+            .replace("{mirror}", self.mirror)
+            .replace("{target}", self.target)
+            .replace("{origin}", self.origin)
+    }
+}
+
+// requests of path
+pub fn apply(rules: &[HeaderRule], headers: &mut Headers, path: &str, vars: &Vars) {
+    for rule in rules.iter().filter(|i| matches_path(i, path)) {
+        apply_rule(rule, headers, vars);
+    }
+}
+
+// responses to requests of path, matched by status and content type before
+// each rule, so a rule sees what earlier ones did
+pub fn apply_response(rules: &[HeaderRule], resp: &mut Response, path: &str, vars: &Vars) {
+    for rule in rules {
+        let status = u16::from(resp.status());
+        if !rule.status.is_empty() && !rule.status.contains(&status) {
+            continue;
+        }
+        if !rule.content_types.is_empty() {
+            let essence = resp.content_type().map(|i| i.essence().to_string());
+            let matched = essence.map_or(false, |essence| {
+                rule.content_types
+                    .iter()
+                    .any(|i| i.eq_ignore_ascii_case(&essence))
+            });
+            if !matched {
+                continue;
+            }
+        }
+        if matches_path(rule, path) {
+            apply_rule(rule, resp.as_mut(), vars);
+        }
+    }
+}
+
+fn matches_path(rule: &HeaderRule, path: &str) -> bool {
+    match rule.path.as_deref() {
+        None => true,
+        Some(pattern) => match pattern.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == pattern,
+        },
+    }
+}
+
+fn apply_rule(rule: &HeaderRule, headers: &mut Headers, vars: &Vars) {
+    let name = rule.name.as_str();
+    match rule.action {
+        HeaderAction::Set => {
+            headers.insert(name, vars.render(&rule.value));
+        }
+        HeaderAction::Add => {
+            headers.append(name, vars.render(&rule.value));
+        }
+        HeaderAction::Remove => {
+            headers.remove(name);
+        }
+        HeaderAction::Replace => {
+            let from = vars.render(&rule.from);
+            let to = vars.render(&rule.value);
+            let values: Vec<_> = match headers.get(name) {
+                Some(values) => values.iter().map(|i| i.as_str().replace(&from, &to)).collect(),
+                None => return,
+            };
+            headers.remove(name);
+            for value in values {
+                headers.append(name, value);
+            }
+        }
+    }
+}
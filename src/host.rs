@@ -0,0 +1,83 @@
+// inbound host of a request, matched against domain_name, by the authority of
+// an absolute-form target, or else Host. X-Forwarded-Host is never used, clients
+// could pick any domain by it. Over tls the server name of ClientHello, read
+// before the handshake, must be the same, or the request gets 421
+
+// host part of an authority, lowercased, without userinfo, port and trailing dot
+pub fn normalize(authority: &str) -> Option<String> {
+    let authority = authority.trim();
+    let authority = authority.rsplit('@').next()?;
+    let host = if authority.starts_with('[') {
+        &authority[..=authority.find(']')?]
+    } else {
+        authority.split(':').next()?
+    };
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    let valid = host
+        .bytes()
+        .all(|i| i.is_ascii_alphanumeric() || matches!(i, b'-' | b'.' | b'_' | b'[' | b']' | b':'));
+    if host.is_empty() || !valid {
+        return None;
+    }
+    Some(host)
+}
+
+// authority of an absolute-form request target takes precedence over Host,
+// as rfc 7230 section 5.4 requires
+pub fn of_request(target: &str, host: Option<&str>) -> Option<String> {
+    if !target.starts_with('/') {
+        if let Some(i) = target.find("://") {
+            let rest = &target[i + 3..];
+            let end = rest
+                .find(|i| matches!(i, '/' | '?' | '#'))
+                .unwrap_or_else(|| rest.len());
+            return normalize(&rest[..end]);
+        }
+    }
+    normalize(host?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes() {
+        assert_eq!(
+            normalize("Mirror.Test:8080").as_deref(),
+            Some("mirror.test")
+        );
+        assert_eq!(
+            normalize("user@mirror.test.").as_deref(),
+            Some("mirror.test")
+        );
+        assert_eq!(normalize("[::1]:80").as_deref(), Some("[::1]"));
+        assert_eq!(normalize(""), None);
+        assert_eq!(normalize("mirror.test/x"), None);
+    }
+
+    #[test]
+    fn absolute_form_over_host() {
+        let host = Some("other.test");
+        let of = |target| of_request(target, host);
+        assert_eq!(of("http://mirror.test/x").as_deref(), Some("mirror.test"));
+        assert_eq!(
+            of("https://Mirror.Test:443?q").as_deref(),
+            Some("mirror.test")
+        );
+        assert_eq!(of("/x").as_deref(), Some("other.test"));
+        assert_eq!(of("*").as_deref(), Some("other.test"));
+    }
+
+    #[test]
+    fn host_without_absolute_form() {
+        assert_eq!(
+            of_request("/", Some("mirror.test:80")).as_deref(),
+            Some("mirror.test")
+        );
+        assert_eq!(of_request("/", None), None);
+        // the target names no host
+        let host = of_request("/http://other.test", Some("mirror.test"));
+        assert_eq!(host.as_deref(), Some("mirror.test"));
+    }
+}
@@ -0,0 +1,392 @@
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures::io::AsyncRead;
+
+use crate::rewrite::Replacements;
+
+const CHUNK_SIZE: usize = 8 * 1024;
+// a longer `<...` is passed through as it is
+const MAX_TAG: usize = 64 * 1024;
+// a longer json script is passed through as it is
+const MAX_JSON: usize = 1024 * 1024;
+const JSON_TYPES: &[&str] = &["application/ld+json", "application/json", "importmap"];
+
+type Map = Box<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+// maps urls in attributes of html tags while the body is streaming through:
+// srcset, refresh of meta, and href, src, action, formaction and poster
+pub struct HtmlRewriter<R> {
+    inner: R,
+    map: Map,
+    strip_integrity: bool,
+    strip_canonical: bool,
+    json_replacements: Option<Arc<Replacements>>,
+    // `<...` not closed yet
+    tag: Vec<u8>,
+    // content of a json script not closed yet
+    json: Option<Vec<u8>>,
+    quote: Option<u8>,
+    output: Vec<u8>,
+    output_pos: usize,
+    eof: bool,
+}
+
+impl<R> HtmlRewriter<R> {
+    pub fn new<F>(inner: R, map: F) -> HtmlRewriter<R>
+    where
+        F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+    {
+        HtmlRewriter {
+            inner,
+            map: Box::new(map),
+            strip_integrity: false,
+            strip_canonical: false,
+            json_replacements: None,
+            tag: Vec::new(),
+            json: None,
+            quote: None,
+            output: Vec::new(),
+            output_pos: 0,
+            eof: false,
+        }
+    }
+
+    // remove `integrity` attributes as well
+    pub fn strip_integrity(mut self, strip: bool) -> HtmlRewriter<R> {
+        self.strip_integrity = strip;
+        self
+    }
+
+    // remove `<link rel=canonical>` as well
+    pub fn strip_canonical(mut self, strip: bool) -> HtmlRewriter<R> {
+        self.strip_canonical = strip;
+        self
+    }
+
+    // replace strings with escapes in json scripts like `<script type="application/ld+json">`,
+    // which replacements of the raw body miss, e.g. `https:\u002F\u002Forigin`
+    pub fn json_replacements(mut self, replacements: Arc<Replacements>) -> HtmlRewriter<R> {
+        self.json_replacements = Some(replacements);
+        self
+    }
+
+    fn process(&mut self, chunk: &[u8]) {
+        let mut output = Vec::with_capacity(chunk.len());
+        for &b in chunk {
+            if let Some(json) = &mut self.json {
+                json.push(b);
+                let end = json.len().saturating_sub(8);
+                if json[end..].eq_ignore_ascii_case(b"</script") {
+                    let content = &json[..end];
+                    let replacements = self.json_replacements.as_ref();
+                    let rewritten = std::str::from_utf8(content)
+                        .ok()
+                        .and_then(|i| rewrite_json(i, replacements?));
+                    match rewritten {
+                        Some(content) => output.extend_from_slice(content.as_bytes()),
+                        None => output.extend_from_slice(content),
+                    }
+                    self.tag = json[end..].to_vec();
+                    self.json = None;
+                } else if json.len() > MAX_JSON {
+                    output.append(json);
+                    self.json = None;
+                }
+                continue;
+            }
+            if self.tag.is_empty() {
+                if b == b'<' {
+                    self.tag.push(b);
+                } else {
+                    output.push(b);
+                }
+                continue;
+            }
+            self.tag.push(b);
+            match self.quote {
+                Some(quote) if b == quote => self.quote = None,
+                Some(_) => {}
+                // quotes of comments and doctype are not tracked
+                None if (b == b'"' || b == b'\'') && self.tag[1] != b'!' => self.quote = Some(b),
+                None if b == b'>' => {
+                    let tag = std::mem::take(&mut self.tag);
+                    if self.json_replacements.is_some() && is_json_script(&tag) {
+                        self.json = Some(Vec::new());
+                    }
+                    let strip = (self.strip_integrity, self.strip_canonical);
+                    match rewrite_tag(&tag, &self.map, strip) {
+                        Some(tag) => output.extend_from_slice(tag.as_bytes()),
+                        None => output.extend_from_slice(&tag),
+                    }
+                }
+                None => {}
+            }
+            if self.tag.len() > MAX_TAG {
+                output.append(&mut self.tag);
+                self.quote = None;
+            }
+        }
+        if self.eof {
+            if let Some(mut json) = self.json.take() {
+                output.append(&mut json);
+            }
+            output.append(&mut self.tag);
+        }
+        self.output = output;
+        self.output_pos = 0;
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HtmlRewriter<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        loop {
+            if self.output_pos < self.output.len() {
+                let n = buf.len().min(self.output.len() - self.output_pos);
+                let pos = self.output_pos;
+                buf[..n].copy_from_slice(&self.output[pos..pos + n]);
+                self.output_pos += n;
+                return Poll::Ready(Ok(n));
+            }
+            if self.eof {
+                return Poll::Ready(Ok(0));
+            }
+            let mut chunk = [0; CHUNK_SIZE];
+            let n = match Pin::new(&mut self.inner).poll_read(cx, &mut chunk) {
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            if n == 0 {
+                self.eof = true;
+            }
+            self.process(&chunk[..n]);
+        }
+    }
+}
+
+// None if nothing is changed, strip tells whether to remove integrity
+// attributes and canonical links
+fn rewrite_tag(tag: &[u8], map: &Map, strip: (bool, bool)) -> Option<String> {
+    let (strip_integrity, strip_canonical) = strip;
+    let tag = std::str::from_utf8(tag).ok()?;
+    if !tag[1..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let name_end = tag[1..]
+        .find(|c: char| c.is_ascii_whitespace() || c == '/' || c == '>')
+        .map_or(tag.len(), |i| i + 1);
+    let name = tag[1..name_end].to_ascii_lowercase();
+    let attrs = parse_attrs(tag, name_end);
+    let refresh = name == "meta"
+        && attrs
+            .iter()
+            .any(|(k, v, ..)| k == "http-equiv" && v.eq_ignore_ascii_case("refresh"));
+    let canonical = name == "link"
+        && attrs.iter().any(|(k, v, ..)| {
+            k == "rel" && v.split_whitespace().any(|i| i.eq_ignore_ascii_case("canonical"))
+        });
+    if canonical && strip_canonical {
+        return Some(String::new());
+    }
+    let mut edits = Vec::new();
+    for (k, v, start, end, attr_start) in attrs {
+        if k == "integrity" && strip_integrity {
+            // with the closing quote
+            let end = if tag[end..].starts_with(|c: char| c == '"' || c == '\'') {
+                end + 1
+            } else {
+                end
+            };
+            edits.push((attr_start, end, String::new()));
+            continue;
+        }
+        let value = match k.as_str() {
+            "srcset" | "imagesrcset" => map_srcset(&v, map),
+            "content" if refresh => map_refresh(&v, map),
+            "href" | "src" | "action" | "formaction" | "poster" => map(&v),
+            _ => None,
+        };
+        if let Some(value) = value {
+            edits.push((start, end, value));
+        }
+    }
+    if edits.is_empty() {
+        return None;
+    }
+    let mut tag = tag.to_string();
+    for (start, end, value) in edits.into_iter().rev() {
+        tag.replace_range(start..end, &value);
+    }
+    Some(tag)
+}
+
+// `<script type="application/ld+json">` and the like
+fn is_json_script(tag: &[u8]) -> bool {
+    let tag = match std::str::from_utf8(tag) {
+        Ok(tag) => tag,
+        Err(_) => return false,
+    };
+    // bytes are compared, a tag may have multibyte text at any offset
+    let name_end = "<script".len();
+    let bytes = tag.as_bytes();
+    if bytes.len() <= name_end
+        || !bytes[..name_end].eq_ignore_ascii_case(b"<script")
+        || !bytes[name_end].is_ascii_whitespace()
+    {
+        return false;
+    }
+    parse_attrs(tag, name_end).iter().any(|(k, v, ..)| {
+        k == "type" && JSON_TYPES.iter().any(|i| v.trim().eq_ignore_ascii_case(i))
+    })
+}
+
+// None if nothing is changed, string literals with unicode escapes are decoded,
+// replaced and encoded again, others are left to replacements of the raw body
+fn rewrite_json(json: &str, replacements: &Replacements) -> Option<String> {
+    let bytes = json.as_bytes();
+    let mut result = String::with_capacity(json.len());
+    let mut last = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'"' {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        i += 1;
+        while i < bytes.len() && bytes[i] != b'"' {
+            if bytes[i] == b'\\' {
+                i += 1;
+            }
+            i += 1;
+        }
+        if i >= bytes.len() {
+            break;
+        }
+        i += 1;
+        let literal = &json[start..i];
+        if !literal.contains("\\u") {
+            continue;
+        }
+        let value: String = match serde_json::from_str(literal) {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        let replaced = replacements.replace(&value);
+        if replaced == value {
+            continue;
+        }
+        result.push_str(&json[last..start]);
+        // not closing the script
+        let literal = serde_json::to_string(&replaced).ok()?;
+        result.push_str(&literal.replace("</", "<\\/"));
+        last = i;
+    }
+    if last == 0 {
+        return None;
+    }
+    result.push_str(&json[last..]);
+    Some(result)
+}
+
+// (lowercase name, value, start and end of value, start of name) of attributes with a value
+fn parse_attrs(tag: &str, from: usize) -> Vec<(String, String, usize, usize, usize)> {
+    let bytes = tag.as_bytes();
+    let len = bytes.len();
+    let is_space = |b: u8| b.is_ascii_whitespace();
+    let mut attrs = Vec::new();
+    let mut i = from;
+    while i < len {
+        while i < len && (is_space(bytes[i]) || bytes[i] == b'/') {
+            i += 1;
+        }
+        if i >= len || bytes[i] == b'>' {
+            break;
+        }
+        let name_start = i;
+        while i < len && !is_space(bytes[i]) && !b"=>/".contains(&bytes[i]) {
+            i += 1;
+        }
+        let name = tag[name_start..i].to_ascii_lowercase();
+        while i < len && is_space(bytes[i]) {
+            i += 1;
+        }
+        if i < len && bytes[i] == b'=' {
+            i += 1;
+            while i < len && is_space(bytes[i]) {
+                i += 1;
+            }
+            let (start, end) = if i < len && (bytes[i] == b'"' || bytes[i] == b'\'') {
+                let quote = bytes[i];
+                let start = i + 1;
+                i = start;
+                while i < len && bytes[i] != quote {
+                    i += 1;
+                }
+                let end = i;
+                i += 1;
+                (start, end)
+            } else {
+                let start = i;
+                while i < len && !is_space(bytes[i]) && bytes[i] != b'>' {
+                    i += 1;
+                }
+                (start, i)
+            };
+            let value = tag[start..end.min(len)].to_string();
+            attrs.push((name, value, start, end.min(len), name_start));
+        } else if i == name_start {
+            // a stray `=`
+            i += 1;
+        }
+    }
+    attrs
+}
+
+// `a.png 1x, /b.png 480w`
+fn map_srcset(value: &str, map: &Map) -> Option<String> {
+    let mut changed = false;
+    let candidates: Vec<String> = value
+        .split(',')
+        .map(|candidate| {
+            let url_start = candidate.len() - candidate.trim_start().len();
+            let url_end = candidate[url_start..]
+                .find(char::is_whitespace)
+                .map_or(candidate.len(), |i| url_start + i);
+            match map(&candidate[url_start..url_end]) {
+                Some(url) => {
+                    changed = true;
+                    format!("{}{}{}", &candidate[..url_start], url, &candidate[url_end..])
+                }
+                None => candidate.to_string(),
+            }
+        })
+        .collect();
+    if changed {
+        Some(candidates.join(","))
+    } else {
+        None
+    }
+}
+
+// `5; url=/next`
+fn map_refresh(value: &str, map: &Map) -> Option<String> {
+    let i = value.to_ascii_lowercase().find("url=")? + "url=".len();
+    let rest = value[i..].trim_start();
+    let quote = rest.chars().next().filter(|c| *c == '\'' || *c == '"');
+    let url = match quote {
+        Some(quote) => rest[1..].trim_end().trim_end_matches(quote),
+        None => rest.trim_end(),
+    };
+    let url = map(url)?;
+    let quote = quote.map(String::from).unwrap_or_default();
+    Some(format!("{}{}{}{}", &value[..i], quote, url, quote))
+}
@@ -0,0 +1,205 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::io::{AsyncRead, AsyncWrite};
+use smol::Timer;
+
+use crate::pool::Conn;
+
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+// targets ignoring `Expect: 100-continue` get the body after this
+const CONTINUE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// a connection to target for one exchange, interim 1xx responses like
+// `103 Early Hints` before the final one are dropped, async-h1 would take them
+// as the response, `101 Switching Protocols` is a final one
+#[derive(Clone)]
+pub struct SkipInterim {
+    inner: Conn,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    // read from target until the final head begins
+    head: Vec<u8>,
+    done: bool,
+}
+
+impl SkipInterim {
+    pub fn new(inner: Conn) -> SkipInterim {
+        SkipInterim {
+            inner,
+            state: Arc::new(Mutex::new(State {
+                head: Vec::new(),
+                done: false,
+            })),
+        }
+    }
+
+    // body of a request with `Expect: 100-continue`, sent once target answers
+    // `100 Continue`, and ended empty if its final response comes instead
+    pub fn gate<R>(&self, body: R) -> Gate<R> {
+        Gate {
+            body,
+            conn: self.clone(),
+            timer: None,
+            open: false,
+            refused: false,
+        }
+    }
+
+    // reads ahead of async-h1 until `100 Continue`, true, or the final head,
+    // false, which is left for async-h1
+    fn poll_continue(&self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        let mut inner = self.inner.clone();
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.done {
+                return Poll::Ready(Ok(false));
+            }
+            if state.head.len() >= 12 {
+                if !is_interim(&state.head[..12]) {
+                    return Poll::Ready(Ok(false));
+                }
+                let end = state.head.windows(4).position(|i| i == b"\r\n\r\n");
+                if let Some(end) = end {
+                    let proceed = &state.head[9..12] == b"100";
+                    state.head.drain(..end + 4);
+                    if proceed {
+                        return Poll::Ready(Ok(true));
+                    }
+                    continue;
+                }
+                if state.head.len() > MAX_HEAD_SIZE {
+                    return Poll::Ready(Ok(false));
+                }
+            }
+            let mut chunk = [0; 4096];
+            let n = match Pin::new(&mut inner).poll_read(cx, &mut chunk) {
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            if n == 0 {
+                state.done = true;
+            }
+            state.head.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+pub struct Gate<R> {
+    body: R,
+    conn: SkipInterim,
+    timer: Option<Timer>,
+    open: bool,
+    refused: bool,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Gate<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.refused {
+            return Poll::Ready(Ok(0));
+        }
+        if !this.open {
+            match this.conn.poll_continue(cx) {
+                Poll::Ready(Ok(true)) => this.open = true,
+                Poll::Ready(Ok(false)) => {
+                    this.refused = true;
+                    return Poll::Ready(Ok(0));
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => {
+                    let timer = this
+                        .timer
+                        .get_or_insert_with(|| Timer::after(CONTINUE_TIMEOUT));
+                    if Pin::new(timer).poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                    this.open = true;
+                }
+            }
+        }
+        Pin::new(&mut this.body).poll_read(cx, buf)
+    }
+}
+
+impl AsyncRead for SkipInterim {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut state = this.state.lock().unwrap();
+        loop {
+            if state.done {
+                if state.head.is_empty() {
+                    return Pin::new(&mut this.inner).poll_read(cx, buf);
+                }
+                let n = buf.len().min(state.head.len());
+                buf[..n].copy_from_slice(&state.head[..n]);
+                state.head.drain(..n);
+                return Poll::Ready(Ok(n));
+            }
+            // `HTTP/1.1 103`
+            if state.head.len() >= 12 {
+                if !is_interim(&state.head[..12]) {
+                    state.done = true;
+                    continue;
+                }
+                let end = state.head.windows(4).position(|i| i == b"\r\n\r\n");
+                if let Some(end) = end {
+                    state.head.drain(..end + 4);
+                    continue;
+                }
+                if state.head.len() > MAX_HEAD_SIZE {
+                    state.done = true;
+                    continue;
+                }
+            }
+            let mut chunk = [0; 4096];
+            let n = match Pin::new(&mut this.inner).poll_read(cx, &mut chunk) {
+                Poll::Ready(Ok(n)) => n,
+                other => return other,
+            };
+            if n == 0 {
+                state.done = true;
+            }
+            state.head.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+impl AsyncWrite for SkipInterim {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+fn is_interim(status_line: &[u8]) -> bool {
+    status_line.starts_with(b"HTTP/1.") && status_line[9] == b'1' && &status_line[9..12] != b"101"
+}
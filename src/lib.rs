@@ -1,6 +1,50 @@
 #[macro_use]
 extern crate log;
 
-mod config;
-mod constants;
+mod access_log;
+mod acl;
+mod acme;
+mod admin;
+mod balance;
+mod breaker;
+mod cache;
+mod cassette;
+mod charset;
+mod coalesce;
+pub mod config;
+mod cookie;
+mod cookie_jar;
+mod cors;
+mod disk_cache;
+mod dns;
+mod export;
+mod form;
+mod forwarded;
+mod headers;
+mod host;
+pub mod html;
+mod interim;
+mod limit;
+pub mod middleware;
+mod optimize;
+mod overrides;
+mod path_filter;
+mod path_rules;
+mod pool;
+mod prefetch;
+mod proxy;
+mod proxy_protocol;
+mod quota;
+mod rate_limit;
+mod request_id;
+mod responder;
+pub mod rewrite;
 pub mod server;
+mod shutdown;
+mod sniff;
+mod tcp;
+mod timeout;
+mod timing;
+mod tls;
+mod trailers;
+mod websocket;
@@ -0,0 +1,298 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::IpAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use async_std::io::BufReader;
+use futures::io::AsyncRead;
+use http_types::{Body, Response};
+use smol::Timer;
+
+// most bytes read at once, so a throttled body is sent smoothly
+const CHUNK_SIZE: usize = 16 * 1024;
+// reads in a row before a body yields to other tasks
+const BUDGET: usize = 64;
+
+// body fails once more than limit bytes are read from it
+pub fn limit_size(resp: &mut Response, limit: u64) {
+    let body = resp.take_body();
+    let len = body.len();
+    let body = SizeLimit {
+        inner: body,
+        remaining: limit,
+    };
+    resp.set_body(Body::from_reader(BufReader::new(body), len));
+}
+
+struct SizeLimit<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for SizeLimit<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(n)) => n,
+            other => return other,
+        };
+        if n as u64 > self.remaining {
+            let err = io::Error::new(io::ErrorKind::Other, "response too large");
+            return Poll::Ready(Err(err));
+        }
+        self.remaining -= n as u64;
+        Poll::Ready(Ok(n))
+    }
+}
+
+// things in use at once, e.g. connections of clients, up to max
+pub struct Slots {
+    max: usize,
+    used: AtomicUsize,
+}
+
+impl Slots {
+    pub fn new(max: usize) -> Slots {
+        Slots {
+            max,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    // None if all are in use
+    pub fn acquire(self: &Arc<Self>) -> Option<Slot> {
+        let used = self.used.fetch_add(1, Ordering::SeqCst);
+        // released on drop either way
+        let slot = Slot(self.clone());
+        if used >= self.max {
+            return None;
+        }
+        Some(slot)
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+}
+
+pub struct Slot(Arc<Slots>);
+
+impl Slot {
+    // keep the slot until the body of response is sent
+    pub fn attach(self, resp: &mut Response) {
+        let body = resp.take_body();
+        let len = body.len();
+        let body = Holding {
+            inner: body,
+            _slot: self,
+        };
+        resp.set_body(Body::from_reader(BufReader::new(body), len));
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.used.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct Holding<R> {
+    inner: R,
+    _slot: Slot,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Holding<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+// slots of every target, by origin
+pub struct PerTarget {
+    max: usize,
+    slots: Mutex<HashMap<String, Arc<Slots>>>,
+}
+
+impl PerTarget {
+    pub fn new(max: usize) -> PerTarget {
+        PerTarget {
+            max,
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn acquire(&self, origin: &str) -> Option<Slot> {
+        let mut slots = self.slots.lock().unwrap();
+        // targets of wildcard domains come and go
+        if slots.len() > 1024 {
+            slots.retain(|_, i| i.used() > 0);
+        }
+        let max = self.max;
+        let slots = slots
+            .entry(origin.to_string())
+            .or_insert_with(|| Arc::new(Slots::new(max)));
+        slots.acquire()
+    }
+}
+
+// a body that is always ready, e.g. from cache or a fast target, yields now
+// and then so it does not keep its thread from other connections
+pub fn budget(resp: &mut Response) {
+    let body = resp.take_body();
+    let len = body.len();
+    let body = Budgeted {
+        inner: body,
+        used: 0,
+    };
+    resp.set_body(Body::from_reader(BufReader::new(body), len));
+}
+
+struct Budgeted<R> {
+    inner: R,
+    used: usize,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Budgeted<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.used >= BUDGET {
+            self.used = 0;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if poll.is_pending() {
+            self.used = 0;
+        } else {
+            self.used += 1;
+        }
+        poll
+    }
+}
+
+// token bucket of bytes, holding at most a second of rate
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+    }
+
+    // time to wait before reading more
+    fn delay(&mut self) -> Option<Duration> {
+        self.refill();
+        if self.tokens >= 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(-self.tokens / self.rate))
+    }
+
+    fn take(&mut self, n: usize) {
+        self.refill();
+        self.tokens -= n as f64;
+    }
+}
+
+// bytes per second of each client, shared by all its responses
+pub struct Bandwidth {
+    rate: f64,
+    buckets: Mutex<HashMap<IpAddr, Weak<Mutex<Bucket>>>>,
+}
+
+impl Bandwidth {
+    pub fn new(rate: u64) -> Bandwidth {
+        Bandwidth {
+            rate: rate.max(1) as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn throttle(&self, client: IpAddr, resp: &mut Response) {
+        let bucket = self.bucket(client);
+        let body = resp.take_body();
+        let len = body.len();
+        let body = Throttled {
+            inner: body,
+            bucket,
+            timer: None,
+        };
+        resp.set_body(Body::from_reader(BufReader::new(body), len));
+    }
+
+    fn bucket(&self, client: IpAddr) -> Arc<Mutex<Bucket>> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if let Some(bucket) = buckets.get(&client).and_then(Weak::upgrade) {
+            return bucket;
+        }
+        // clients without responses in flight
+        buckets.retain(|_, i| i.strong_count() > 0);
+        let bucket = Arc::new(Mutex::new(Bucket {
+            rate: self.rate,
+            tokens: self.rate,
+            updated: Instant::now(),
+        }));
+        buckets.insert(client, Arc::downgrade(&bucket));
+        bucket
+    }
+}
+
+struct Throttled<R> {
+    inner: R,
+    bucket: Arc<Mutex<Bucket>>,
+    timer: Option<Timer>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Throttled<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            if let Some(timer) = &mut self.timer {
+                if Pin::new(timer).poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                self.timer = None;
+            }
+            let delay = self.bucket.lock().unwrap().delay();
+            match delay {
+                Some(delay) => self.timer = Some(Timer::after(delay)),
+                None => break,
+            }
+        }
+        let len = buf.len().min(CHUNK_SIZE);
+        let n = match Pin::new(&mut self.inner).poll_read(cx, &mut buf[..len]) {
+            Poll::Ready(Ok(n)) => n,
+            other => return other,
+        };
+        self.bucket.lock().unwrap().take(n);
+        Poll::Ready(Ok(n))
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use http_types::{Request, Response};
+
+use crate::{
+    access_log,
+    config::AccessLogConfig,
+    request_id::RequestId,
+    server::{ClientAddr, Forward},
+};
+
+// a stage of handling requests, calls next to go on to the following layers
+// and forwarding, or answers by itself
+#[async_trait]
+pub trait Layer: Send + Sync + 'static {
+    async fn handle(&self, req: Request, next: Next<'_>) -> http_types::Result<Response>;
+}
+
+// layers left, ending with forwarding to target
+pub struct Next<'a> {
+    layers: &'a [Arc<dyn Layer>],
+    forward: &'a Forward,
+}
+
+impl<'a> Next<'a> {
+    pub fn new(layers: &'a [Arc<dyn Layer>], forward: &'a Forward) -> Next<'a> {
+        Next { layers, forward }
+    }
+
+    pub async fn run(self, req: Request) -> http_types::Result<Response> {
+        match self.layers.split_first() {
+            Some((layer, layers)) => {
+                let next = Next {
+                    layers,
+                    forward: self.forward,
+                };
+                layer.handle(req, next).await
+            }
+            None => self.forward.forward(req).await,
+        }
+    }
+
+    pub fn forward(&self) -> &Forward {
+        self.forward
+    }
+}
+
+// records responses into access log
+pub struct AccessLog {
+    log: Arc<access_log::AccessLog>,
+}
+
+impl AccessLog {
+    pub fn new(config: &AccessLogConfig) -> Result<AccessLog> {
+        Ok(AccessLog {
+            log: Arc::new(access_log::AccessLog::new(config)?),
+        })
+    }
+}
+
+#[async_trait]
+impl Layer for AccessLog {
+    async fn handle(&self, req: Request, next: Next<'_>) -> http_types::Result<Response> {
+        let client = match req.ext().get::<ClientAddr>() {
+            Some(client) => client.0,
+            None => return next.run(req).await,
+        };
+        let entry = access_log::Entry::new(&req, client);
+        let mut resp = next.run(req).await?;
+        self.log.record(entry, &mut resp);
+        Ok(resp)
+    }
+}
+
+// errors of later layers become error pages
+pub struct ErrorPages;
+
+#[async_trait]
+impl Layer for ErrorPages {
+    async fn handle(&self, req: Request, next: Next<'_>) -> http_types::Result<Response> {
+        let forward = next.forward;
+        let id = req.ext().get::<RequestId>().cloned();
+        let id = id.as_ref().map_or("", |i| i.0.as_str());
+        match next.run(req).await {
+            Ok(resp) => Ok(resp),
+            Err(err) => Ok(forward.error_response(&err, id)),
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use anyhow::{anyhow, Result};
+use futures::io::{AsyncReadExt, Cursor};
+use http_types::{Body, Response};
+use image::{imageops::FilterType, io::Reader, GenericImageView, ImageFormat, ImageOutputFormat};
+
+use crate::config::ImageConfig;
+
+// re-encodes jpeg and png images, smaller if too large
+pub struct Optimizer {
+    content_types: Vec<String>,
+    quality: u8,
+    max_width: Option<u32>,
+    max_height: Option<u32>,
+    max_size: u64,
+    max_pixels: u64,
+}
+
+impl Optimizer {
+    pub fn new(config: &ImageConfig) -> Optimizer {
+        Optimizer {
+            content_types: config.content_types.clone(),
+            quality: config.quality,
+            max_width: config.max_width,
+            max_height: config.max_height,
+            max_size: config.max_size,
+            max_pixels: config.max_pixels,
+        }
+    }
+
+    pub fn applies(&self, resp: &Response) -> bool {
+        let essence = match resp.content_type() {
+            Some(content_type) => content_type.essence().to_string(),
+            None => return false,
+        };
+        format(&essence).is_some()
+            && self.content_types.contains(&essence)
+            && resp.header("content-encoding").is_none()
+            && resp.len().map_or(true, |len| len as u64 <= self.max_size)
+    }
+
+    // the image as it is if it can't be decoded or gets no smaller
+    pub async fn optimize(&self, resp: &mut Response) -> Result<()> {
+        let essence = match resp.content_type() {
+            Some(content_type) => content_type.essence().to_string(),
+            None => return Ok(()),
+        };
+        let format = match format(&essence) {
+            Some(format) => format,
+            None => return Ok(()),
+        };
+        let mut body = resp.take_body();
+        let len = body.len();
+        let mut buf = Vec::new();
+        (&mut body)
+            .take(self.max_size + 1)
+            .read_to_end(&mut buf)
+            .await?;
+        if buf.len() as u64 > self.max_size {
+            let rest = async_std::io::BufReader::new(Cursor::new(buf).chain(body));
+            resp.set_body(Body::from_reader(rest, len));
+            return Ok(());
+        }
+        let (quality, max_width, max_height) = (self.quality, self.max_width, self.max_height);
+        let max_pixels = self.max_pixels;
+        let image = buf.clone();
+        let result = smol::unblock!(encode(
+            &image, format, quality, max_width, max_height, max_pixels
+        ));
+        let image = match result {
+            Ok(image) if image.len() < buf.len() => image,
+            Ok(_) => buf,
+            Err(err) => {
+                debug!("optimizing image: {}", err);
+                buf
+            }
+        };
+        resp.set_body(image);
+        // bytes differ from those of target, ranges of them would not match
+        resp.remove_header("accept-ranges");
+        if let Some(etag) = resp.header("etag").map(|i| i.as_str().to_string()) {
+            if etag.starts_with('"') {
+                resp.insert_header("etag", format!("W/{}", etag));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn format(essence: &str) -> Option<ImageFormat> {
+    match essence {
+        "image/jpeg" => Some(ImageFormat::Jpeg),
+        "image/png" => Some(ImageFormat::Png),
+        _ => None,
+    }
+}
+
+// within max_width and max_height, aspect ratio kept, an image of more than
+// max_pixels is not decoded
+fn encode(
+    image: &[u8],
+    format: ImageFormat,
+    quality: u8,
+    max_width: Option<u32>,
+    max_height: Option<u32>,
+    max_pixels: u64,
+) -> Result<Vec<u8>> {
+    let mut reader = Reader::new(std::io::Cursor::new(image));
+    reader.set_format(format);
+    let (width, height) = reader.into_dimensions()?;
+    if u64::from(width) * u64::from(height) > max_pixels {
+        return Err(anyhow!(
+            "{}x{} is more than {} pixels",
+            width,
+            height,
+            max_pixels
+        ));
+    }
+    let mut image = image::load_from_memory_with_format(image, format)?;
+    let (width, height) = image.dimensions();
+    let (max_width, max_height) = (max_width.unwrap_or(width), max_height.unwrap_or(height));
+    if width > max_width || height > max_height {
+        image = image.resize(max_width, max_height, FilterType::Triangle);
+    }
+    let output = match format {
+        ImageFormat::Jpeg => ImageOutputFormat::Jpeg(quality),
+        _ => ImageOutputFormat::Png,
+    };
+    let mut buf = Vec::new();
+    image.write_to(&mut buf, output)?;
+    Ok(buf)
+}
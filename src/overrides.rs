@@ -0,0 +1,48 @@
+use std::path::{Path, PathBuf};
+
+use async_std::{fs::File, io::BufReader};
+use http_types::{mime, Body, Mime, Response, StatusCode};
+
+// file under dir for url path, None if there is none or path is not plain
+fn file_path(dir: &str, path: &str) -> Option<PathBuf> {
+    let mut file = PathBuf::from(dir);
+    for segment in path.split('/').filter(|i| !i.is_empty()) {
+        if segment == "." || segment == ".." || segment.contains('%') || segment.contains('\\') {
+            return None;
+        }
+        file.push(segment);
+    }
+    Some(file)
+}
+
+fn content_type(path: &Path) -> Mime {
+    let extension = path.extension().and_then(|i| i.to_str()).unwrap_or_default();
+    match extension.to_lowercase().as_str() {
+        "html" | "htm" => mime::HTML,
+        "css" => mime::CSS,
+        "js" | "mjs" => mime::JAVASCRIPT,
+        "json" => mime::JSON,
+        "txt" => mime::PLAIN,
+        "xml" => mime::XML,
+        "svg" => mime::SVG,
+        "png" => mime::PNG,
+        "jpg" | "jpeg" => mime::JPEG,
+        "ico" => mime::ICO,
+        _ => mime::BYTE_STREAM,
+    }
+}
+
+// a file in overrides directory served instead of forwarding
+pub async fn find(dir: &str, path: &str) -> Option<Response> {
+    let path = file_path(dir, path)?;
+    let file = File::open(&path).await.ok()?;
+    let metadata = file.metadata().await.ok()?;
+    if !metadata.is_file() {
+        return None;
+    }
+    let mut resp = Response::new(StatusCode::Ok);
+    let len = metadata.len() as usize;
+    resp.set_body(Body::from_reader(BufReader::new(file), Some(len)));
+    resp.set_content_type(content_type(&path));
+    Some(resp)
+}
@@ -0,0 +1,98 @@
+use std::convert::TryFrom;
+
+use anyhow::{anyhow, Result};
+use http_types::{Response, StatusCode};
+use regex::Regex;
+
+// a glob where `*` matches anything, `/` too, or a regex after `~`
+fn pattern(s: &str) -> Result<Regex> {
+    let pattern = match s.strip_prefix('~') {
+        Some(regex) => regex.to_string(),
+        None => {
+            let parts: Vec<_> = s.split('*').map(regex::escape).collect();
+            format!("^{}$", parts.join(".*"))
+        }
+    };
+    Ok(Regex::new(&pattern)?)
+}
+
+// paths of requests forwarded to target, others are answered at once
+pub struct PathFilter {
+    allow: Vec<Regex>,
+    deny: Vec<Regex>,
+    status: StatusCode,
+}
+
+impl PathFilter {
+    // None if both are empty
+    pub fn new(allow: &[String], deny: &[String], status: u16) -> Result<Option<PathFilter>> {
+        if allow.is_empty() && deny.is_empty() {
+            return Ok(None);
+        }
+        let status =
+            StatusCode::try_from(status).map_err(|_| anyhow!("invalid status: {}", status))?;
+        let parse = |list: &[String]| list.iter().map(|i| pattern(i)).collect::<Result<_>>();
+        Ok(Some(PathFilter {
+            allow: parse(allow)?,
+            deny: parse(deny)?,
+            status,
+        }))
+    }
+
+    // deny takes precedence, everything is allowed if allow is empty, paths are
+    // matched as targets see them, so `/%61dmin` or `/x/../admin` is `/admin`
+    pub fn check(&self, path: &str) -> Option<Response> {
+        let path = normalize(path);
+        let path = path.as_str();
+        let denied = self.deny.iter().any(|i| i.is_match(path));
+        if !denied && (self.allow.is_empty() || self.allow.iter().any(|i| i.is_match(path))) {
+            return None;
+        }
+        Some(Response::new(self.status))
+    }
+}
+
+// unreserved characters percent-decoded, and dot segments removed
+fn normalize(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = String::with_capacity(path.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            let c = hex
+                .and_then(|i| u8::from_str_radix(i, 16).ok())
+                .map(char::from);
+            if let Some(c) = c.filter(|c| c.is_ascii_alphanumeric() || "-._~".contains(*c)) {
+                decoded.push(c);
+                i += 3;
+                continue;
+            }
+        }
+        let end = path[i..].find('%').map_or(path.len(), |n| i + n.max(1));
+        decoded.push_str(&path[i..end]);
+        i = end;
+    }
+    let mut segments: Vec<&str> = Vec::new();
+    let mut parts = decoded.split('/').peekable();
+    while let Some(segment) = parts.next() {
+        let last = parts.peek().is_none();
+        match segment {
+            "." => {}
+            ".." => {
+                if segments.len() > 1 {
+                    segments.pop();
+                }
+            }
+            _ => {
+                segments.push(segment);
+                continue;
+            }
+        }
+        // `/a/..` is `/`, `/a/` with the trailing slash
+        if last {
+            segments.push("");
+        }
+    }
+    segments.join("/")
+}
@@ -0,0 +1,49 @@
+use crate::config::PathRule;
+
+// paths of requests mapped before they are sent to target, `*` at the end
+// of both from and to stands for the rest of path
+#[derive(Default)]
+pub struct PathRules {
+    rules: Vec<PathRule>,
+}
+
+impl PathRules {
+    pub fn new(rules: &[PathRule]) -> PathRules {
+        PathRules {
+            rules: rules.to_vec(),
+        }
+    }
+
+    // path for target and query to append
+    pub fn map(&self, path: &str) -> Option<(String, Option<&str>)> {
+        self.rules.iter().find_map(|rule| {
+            let path = replace(path, &rule.from, &rule.to)?;
+            Some((path, rule.query.as_deref()))
+        })
+    }
+
+    // path of mirror for a path of target
+    pub fn unmap(&self, path: &str) -> Option<String> {
+        self.rules
+            .iter()
+            .find_map(|rule| replace(path, &rule.to, &rule.from))
+    }
+
+    // (target, mirror) path prefixes, to map links in bodies back
+    pub fn prefixes(&self) -> Vec<(&str, &str)> {
+        self.rules
+            .iter()
+            .filter_map(|rule| Some((rule.to.strip_suffix('*')?, rule.from.strip_suffix('*')?)))
+            .collect()
+    }
+}
+
+fn replace(path: &str, from: &str, to: &str) -> Option<String> {
+    match (from.strip_suffix('*'), to.strip_suffix('*')) {
+        (Some(from), Some(to)) => path
+            .strip_prefix(from)
+            .map(|rest| format!("{}{}", to, rest)),
+        _ if path == from => Some(to.to_string()),
+        _ => None,
+    }
+}
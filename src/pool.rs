@@ -0,0 +1,98 @@
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::io::AsyncRead;
+use http_types::{Body, Response};
+
+use crate::{config::PoolConfig, server::Stream};
+
+pub type Conn = async_dup::Arc<async_dup::Mutex<Box<dyn Stream>>>;
+
+pub struct Pool {
+    idle_timeout: Duration,
+    max_idle: usize,
+    idle: Mutex<HashMap<String, Vec<(Conn, Instant)>>>,
+}
+
+impl Pool {
+    pub fn new(config: &PoolConfig) -> Pool {
+        Pool {
+            idle_timeout: Duration::from_secs(config.idle_timeout),
+            max_idle: config.max_idle,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Conn> {
+        let mut idle = self.idle.lock().unwrap();
+        let conns = idle.get_mut(key)?;
+        while let Some((conn, since)) = conns.pop() {
+            if since.elapsed() < self.idle_timeout {
+                return Some(conn);
+            }
+        }
+        None
+    }
+
+    fn put(&self, key: String, conn: Conn) {
+        if self.max_idle == 0 {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        let conns = idle.entry(key).or_insert_with(Vec::new);
+        conns.retain(|(_, since)| since.elapsed() < self.idle_timeout);
+        if conns.len() >= self.max_idle {
+            conns.remove(0);
+        }
+        conns.push((conn, Instant::now()));
+    }
+
+    // give the connection back after the body of response is read to the end
+    pub fn release(self: &Arc<Self>, key: String, conn: Conn, resp: &mut Response) {
+        if self.max_idle == 0 {
+            return;
+        }
+        let body = resp.take_body();
+        let len = body.len();
+        let guard = Guard {
+            inner: body,
+            pool: self.clone(),
+            key,
+            conn: Some(conn),
+        };
+        let guard = async_std::io::BufReader::new(guard);
+        resp.set_body(Body::from_reader(guard, len));
+    }
+}
+
+struct Guard<R> {
+    inner: R,
+    pool: Arc<Pool>,
+    key: String,
+    conn: Option<Conn>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Guard<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let n = match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(n)) => n,
+            other => return other,
+        };
+        if n == 0 && !buf.is_empty() {
+            if let Some(conn) = self.conn.take() {
+                let key = self.key.clone();
+                self.pool.put(key, conn);
+            }
+        }
+        Poll::Ready(Ok(n))
+    }
+}
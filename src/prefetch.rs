@@ -0,0 +1,117 @@
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures::io::AsyncRead;
+use http_types::{Body, Response, Url};
+use once_cell::sync::Lazy;
+use regex::bytes::Regex;
+
+// a longer page is not looked into
+const MAX_PAGE_SIZE: usize = 1024 * 1024;
+
+static TAG: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)<(?:script|img|link)\s[^>]*>").unwrap());
+static ATTR: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"(?i)\s(?:src|href)\s*=\s*["']([^"'\s]+)["']"#).unwrap());
+static REL: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)\srel\s*=\s*.?(?:stylesheet|preload|modulepreload|icon)").unwrap()
+});
+
+type Callback = Box<dyn FnOnce(Vec<u8>) + Send>;
+
+// called with html of a page once it's sent, set by the server which has
+// what prefetching needs
+#[derive(Clone, Default)]
+pub struct Hook(Arc<Mutex<Option<Callback>>>);
+
+impl Hook {
+    pub fn set<F>(&self, callback: F)
+    where
+        F: FnOnce(Vec<u8>) + Send + 'static,
+    {
+        *self.0.lock().unwrap() = Some(Box::new(callback));
+    }
+}
+
+// record html of a page while it's sent, a Hook is put into extensions
+pub fn attach(resp: &mut Response) {
+    let hook = Hook::default();
+    let body = resp.take_body();
+    let len = body.len();
+    let tee = Tee {
+        inner: body,
+        hook: hook.clone(),
+        html: Vec::new(),
+        done: false,
+    };
+    let tee = async_std::io::BufReader::new(tee);
+    resp.set_body(Body::from_reader(tee, len));
+    resp.ext_mut().insert(hook);
+}
+
+struct Tee<R> {
+    inner: R,
+    hook: Hook,
+    html: Vec<u8>,
+    done: bool,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Tee<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let n = match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(n)) => n,
+            other => return other,
+        };
+        if self.done {
+            return Poll::Ready(Ok(n));
+        }
+        if n == 0 {
+            self.done = true;
+            let html = std::mem::take(&mut self.html);
+            let callback = self.hook.0.lock().unwrap().take();
+            if let Some(callback) = callback {
+                callback(html);
+            }
+        } else if self.html.len() + n > MAX_PAGE_SIZE {
+            self.done = true;
+            self.html = Vec::new();
+        } else {
+            self.html.extend_from_slice(&buf[..n]);
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+// scripts, images, stylesheets and preloads of a page on the same host, at most max
+pub fn links(html: &[u8], page: &Url, max: usize) -> Vec<Url> {
+    let mut links: Vec<Url> = Vec::new();
+    for tag in TAG.find_iter(html) {
+        let tag = tag.as_bytes();
+        if tag[1..5].eq_ignore_ascii_case(b"link") && !REL.is_match(tag) {
+            continue;
+        }
+        let link = match ATTR.captures(tag).and_then(|i| i.get(1)) {
+            Some(link) => String::from_utf8_lossy(link.as_bytes()).replace("&amp;", "&"),
+            None => continue,
+        };
+        let url = match page.join(&link) {
+            Ok(url) => url,
+            Err(_) => continue,
+        };
+        // scheme of page may not be what the client used
+        if url.host_str() != page.host_str() || links.contains(&url) {
+            continue;
+        }
+        links.push(url);
+        if links.len() >= max {
+            break;
+        }
+    }
+    links
+}
@@ -0,0 +1,80 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{anyhow, Result};
+use futures::io::{AsyncRead, AsyncReadExt};
+
+const SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+// longest v1 header, including CRLF
+const MAX_V1: usize = 107;
+
+// read PROXY protocol v1 or v2 header sent by a load balancer before anything else,
+// no byte after it is consumed. None for LOCAL or UNKNOWN, e.g. health checks
+pub async fn read<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    // every header is at least this long
+    let mut head = vec![0; SIGNATURE.len()];
+    stream.read_exact(&mut head).await?;
+    if head.starts_with(b"PROXY ") {
+        while !head.ends_with(b"\r\n") {
+            if head.len() >= MAX_V1 {
+                return Err(anyhow!("PROXY v1 header too long"));
+            }
+            let mut byte = [0];
+            stream.read_exact(&mut byte).await?;
+            head.push(byte[0]);
+        }
+        return parse_v1(&head);
+    }
+    if head != SIGNATURE {
+        return Err(anyhow!("missing PROXY header"));
+    }
+    let mut fixed = [0; 4];
+    stream.read_exact(&mut fixed).await?;
+    let len = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+    let mut addrs = vec![0; len];
+    stream.read_exact(&mut addrs).await?;
+    parse_v2(fixed[0], fixed[1], &addrs)
+}
+
+fn parse_v1(head: &[u8]) -> Result<Option<SocketAddr>> {
+    let head = std::str::from_utf8(head)?.trim_end();
+    let parts: Vec<_> = head.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "TCP4", src, _, port, _] | ["PROXY", "TCP6", src, _, port, _] => {
+            let ip: IpAddr = src.parse()?;
+            Ok(Some(SocketAddr::new(ip, port.parse()?)))
+        }
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        _ => Err(anyhow!("invalid PROXY v1 header: {}", head)),
+    }
+}
+
+fn parse_v2(version_command: u8, family: u8, addrs: &[u8]) -> Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(anyhow!(
+            "unsupported PROXY version: {}",
+            version_command >> 4
+        ));
+    }
+    match version_command & 0x0f {
+        0 => return Ok(None),
+        1 => (),
+        command => return Err(anyhow!("invalid PROXY v2 command: {}", command)),
+    }
+    let port = |i: usize| u16::from_be_bytes([addrs[i], addrs[i + 1]]);
+    // source address comes first, then destination address and ports
+    match family >> 4 {
+        1 if addrs.len() >= 12 => {
+            let mut ip = [0; 4];
+            ip.copy_from_slice(&addrs[..4]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port(8))))
+        }
+        2 if addrs.len() >= 36 => {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addrs[..16]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port(32))))
+        }
+        1 | 2 => Err(anyhow!("truncated PROXY v2 addresses")),
+        // unix or unspecified
+        _ => Ok(None),
+    }
+}
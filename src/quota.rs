@@ -0,0 +1,130 @@
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use chrono::{Datelike, NaiveDate, Utc};
+use futures::io::AsyncRead;
+use http_types::{Body, Response};
+
+use crate::config::QuotaConfig;
+
+// bytes of bodies served by mirror domain, of the UTC day and month,
+// kept over reloads but not restarts
+#[derive(Default)]
+pub struct Usage {
+    domains: Mutex<HashMap<String, Bytes>>,
+}
+
+struct Bytes {
+    date: NaiveDate,
+    day: u64,
+    month: u64,
+}
+
+impl Bytes {
+    // counts of a past day or month start over
+    fn roll(&mut self, today: NaiveDate) {
+        if self.date == today {
+            return;
+        }
+        if (self.date.year(), self.date.month()) != (today.year(), today.month()) {
+            self.month = 0;
+        }
+        self.day = 0;
+        self.date = today;
+    }
+}
+
+impl Usage {
+    pub fn add(&self, domain: &str, n: u64) {
+        let today = Utc::today().naive_utc();
+        let mut domains = self.domains.lock().unwrap();
+        let bytes = domains.entry(domain.to_string()).or_insert(Bytes {
+            date: today,
+            day: 0,
+            month: 0,
+        });
+        bytes.roll(today);
+        bytes.day += n;
+        bytes.month += n;
+    }
+
+    // bytes of today and this month by domain
+    pub fn domains(&self) -> Vec<(String, u64, u64)> {
+        let today = Utc::today().naive_utc();
+        let mut domains = self.domains.lock().unwrap();
+        domains
+            .iter_mut()
+            .map(|(domain, bytes)| {
+                bytes.roll(today);
+                (domain.clone(), bytes.day, bytes.month)
+            })
+            .collect()
+    }
+
+    // seconds until the exhausted quota starts over, None if not exhausted
+    pub fn check(&self, domain: &str, quota: &QuotaConfig) -> Option<i64> {
+        let now = Utc::now().naive_utc();
+        let today = now.date();
+        let mut domains = self.domains.lock().unwrap();
+        let bytes = domains.get_mut(domain)?;
+        bytes.roll(today);
+        let reset = if quota.monthly.map_or(false, |i| bytes.month >= i) {
+            let (year, month) = match today.month() {
+                12 => (today.year() + 1, 1),
+                month => (today.year(), month + 1),
+            };
+            NaiveDate::from_ymd(year, month, 1)
+        } else if quota.daily.map_or(false, |i| bytes.day >= i) {
+            today.succ()
+        } else {
+            return None;
+        };
+        Some((reset.and_hms(0, 0, 0) - now).num_seconds() + 1)
+    }
+
+    // bytes of body are added once it's sent, or the client went away
+    pub fn count(self: &Arc<Self>, domain: String, resp: &mut Response) {
+        let body = resp.take_body();
+        let len = body.len();
+        let counter = Counter {
+            inner: body,
+            usage: self.clone(),
+            domain,
+            bytes: 0,
+        };
+        let counter = async_std::io::BufReader::new(counter);
+        resp.set_body(Body::from_reader(counter, len));
+    }
+}
+
+struct Counter<R> {
+    inner: R,
+    usage: Arc<Usage>,
+    domain: String,
+    bytes: u64,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Counter<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let n = match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(n)) => n,
+            other => return other,
+        };
+        self.bytes += n as u64;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<R> Drop for Counter<R> {
+    fn drop(&mut self) {
+        self.usage.add(&self.domain, self.bytes);
+    }
+}
@@ -0,0 +1,64 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config::{RateLimitConfig, RateLimitKey};
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// token bucket, refilled by `rate` tokens per second up to `burst`
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    key: RateLimitKey,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            rate: config.rate,
+            burst: config.burst.unwrap_or(config.rate).max(1.0),
+            key: config.key,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Err with time to wait if limit exceeded
+    pub fn check(&self, client: &str, domain: &str) -> Result<(), Duration> {
+        let key = match self.key {
+            RateLimitKey::Client => client.to_string(),
+            RateLimitKey::Domain => domain.to_string(),
+            RateLimitKey::ClientDomain => format!("{} {}", client, domain),
+        };
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > 100_000 {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, i| {
+                i.tokens + now.duration_since(i.updated).as_secs_f64() * rate < burst
+            });
+        }
+        let burst = self.burst;
+        let bucket = buckets.entry(key).or_insert_with(|| Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        if self.rate <= 0.0 {
+            return Err(Duration::from_secs(3600));
+        }
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+    }
+}
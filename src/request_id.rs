@@ -0,0 +1,29 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// id of a request, inserted into extensions of request
+#[derive(Clone)]
+pub struct RequestId(pub String);
+
+// unique among processes of a host, and over restarts
+pub fn generate() -> String {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|i| i.as_millis())
+        .unwrap_or_default();
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:x}-{:x}-{:x}", millis, std::process::id(), n)
+}
+
+// an id from a trusted proxy is kept if it looks sane
+pub fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 128
+        && id
+            .bytes()
+            .all(|i| i.is_ascii_alphanumeric() || matches!(i, b'-' | b'_' | b'.' | b':'))
+}
@@ -0,0 +1,62 @@
+use std::{convert::TryFrom, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use http_types::{Method, Request, Response, StatusCode};
+
+use crate::config::ResponderConfig;
+
+// a fixed response to requests matching methods and path, sent without
+// contacting target
+pub struct Responder {
+    methods: Vec<Method>,
+    path: String,
+    status: StatusCode,
+    headers: Vec<(String, String)>,
+    body: Option<String>,
+}
+
+impl Responder {
+    pub fn new(config: &ResponderConfig) -> Result<Responder> {
+        let methods = config
+            .methods
+            .iter()
+            .map(|i| Method::from_str(i).map_err(|_| anyhow!("invalid method: {}", i)))
+            .collect::<Result<_>>()?;
+        let status = StatusCode::try_from(config.status)
+            .map_err(|_| anyhow!("invalid status: {}", config.status))?;
+        Ok(Responder {
+            methods,
+            path: config.path.clone(),
+            status,
+            headers: config
+                .headers
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            body: config.body.clone(),
+        })
+    }
+
+    pub fn respond(&self, req: &Request) -> Option<Response> {
+        if !self.methods.is_empty() && !self.methods.contains(&req.method()) {
+            return None;
+        }
+        let path = req.url().path();
+        let matched = match self.path.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == self.path,
+        };
+        if !matched {
+            return None;
+        }
+        let mut resp = Response::new(self.status);
+        if let Some(body) = &self.body {
+            resp.set_body(body.as_str());
+        }
+        // after body, which sets Content-Type
+        for (name, value) in &self.headers {
+            resp.insert_header(name.as_str(), value.as_str());
+        }
+        Some(resp)
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use aho_corasick::{AhoCorasick, AhoCorasickBuilder, MatchKind};
+use anyhow::Result;
+use futures::io::AsyncRead;
+use regex::bytes::Regex;
+
+use crate::{config::RewriteRule, headers::Vars};
+
+const CHUNK_SIZE: usize = 8 * 1024;
+// a longer line is sent in parts, as without lines
+const MAX_LINE_SIZE: usize = 64 * 1024;
+
+// (from, to) pairs matched in one pass, the longest one wins at the leftmost position
+pub struct Replacements {
+    matcher: AhoCorasick,
+    to: Vec<String>,
+    max_len: usize,
+}
+
+impl Replacements {
+    pub fn new(pairs: Vec<(String, String)>) -> Replacements {
+        let (from, to): (Vec<_>, Vec<_>) = pairs
+            .into_iter()
+            .filter(|(from, _)| !from.is_empty())
+            .unzip();
+        let matcher = AhoCorasickBuilder::new()
+            .match_kind(MatchKind::LeftmostLongest)
+            .build(&from);
+        Replacements {
+            matcher,
+            to,
+            max_len: from.iter().map(|i| i.len()).max().unwrap_or_default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.to.is_empty()
+    }
+
+    pub fn replace(&self, s: &str) -> String {
+        self.matcher.replace_all(s, &self.to)
+    }
+
+    pub fn replace_bytes(&self, s: &[u8]) -> Vec<u8> {
+        self.matcher.replace_all_bytes(s, &self.to)
+    }
+}
+
+// replace domain names while the body is streaming through, the tail of
+// every chunk is kept back until a match across two chunks can be decided
+pub struct Rewriter<R> {
+    inner: R,
+    replacements: Arc<Replacements>,
+    window: usize,
+    lines: bool,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    output_pos: usize,
+    eof: bool,
+}
+
+impl<R> Rewriter<R> {
+    pub fn new(inner: R, replacements: Arc<Replacements>) -> Rewriter<R> {
+        let window = replacements.max_len.saturating_sub(1);
+        Rewriter {
+            inner,
+            replacements,
+            window,
+            lines: false,
+            input: Vec::new(),
+            output: Vec::new(),
+            output_pos: 0,
+            eof: false,
+        }
+    }
+
+    // sends every complete line at once, for streams like text/event-stream
+    pub fn by_line(inner: R, replacements: Arc<Replacements>) -> Rewriter<R> {
+        Rewriter {
+            lines: true,
+            ..Rewriter::new(inner, replacements)
+        }
+    }
+
+    fn process(&mut self) {
+        let limit = if self.eof {
+            self.input.len()
+        } else if self.lines {
+            let limit = self
+                .input
+                .iter()
+                .rposition(|i| *i == b'\n' || *i == b'\r')
+                .map_or(0, |i| i + 1);
+            if self.input.len() - limit > MAX_LINE_SIZE {
+                self.input.len() - self.window
+            } else {
+                limit
+            }
+        } else {
+            self.input.len().saturating_sub(self.window)
+        };
+        let mut output = Vec::with_capacity(limit);
+        let mut pos = 0;
+        // a match starting before limit has all its bytes in input
+        for m in self.replacements.matcher.find_iter(&self.input) {
+            if m.start() >= limit {
+                break;
+            }
+            output.extend_from_slice(&self.input[pos..m.start()]);
+            output.extend_from_slice(self.replacements.to[m.pattern()].as_bytes());
+            pos = m.end();
+        }
+        if pos < limit {
+            output.extend_from_slice(&self.input[pos..limit]);
+            pos = limit;
+        }
+        self.input.drain(..pos);
+        self.output = output;
+        self.output_pos = 0;
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Rewriter<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        loop {
+            if self.output_pos < self.output.len() {
+                let n = buf.len().min(self.output.len() - self.output_pos);
+                let pos = self.output_pos;
+                buf[..n].copy_from_slice(&self.output[pos..pos + n]);
+                self.output_pos += n;
+                return Poll::Ready(Ok(n));
+            }
+            if self.eof {
+                return Poll::Ready(Ok(0));
+            }
+            let mut chunk = [0; CHUNK_SIZE];
+            let n = match Pin::new(&mut self.inner).poll_read(cx, &mut chunk) {
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            if n == 0 {
+                self.eof = true;
+            } else {
+                self.input.extend_from_slice(&chunk[..n]);
+            }
+            self.process();
+        }
+    }
+}
+
+// user rules run on the whole body, as a regex may match text of any length
+pub struct RegexRule {
+    regex: Regex,
+    // `$1` for capture groups, and `{mirror}`, `{target}` and `{origin}`
+    replace: String,
+    content_types: Vec<String>,
+}
+
+impl RegexRule {
+    pub fn new(rule: &RewriteRule) -> Result<RegexRule> {
+        Ok(RegexRule {
+            regex: Regex::new(&rule.pattern)?,
+            replace: rule.replace.clone(),
+            content_types: rule.content_types.clone(),
+        })
+    }
+
+    // rules without content types apply to the rewritten ones
+    pub fn applies(&self, essence: &str, rewrite_content_types: &[String]) -> bool {
+        let types = if self.content_types.is_empty() {
+            rewrite_content_types
+        } else {
+            &self.content_types
+        };
+        types.iter().any(|i| i == essence)
+    }
+}
+
+pub fn apply_rules(rules: &[&RegexRule], body: Vec<u8>, vars: &Vars) -> Vec<u8> {
+    let mut body = body;
+    for rule in rules {
+        let replace = vars.render(&rule.replace);
+        body = rule.regex.replace_all(&body, replace.as_bytes()).into_owned();
+    }
+    body
+}
+
+// (from, to) and its json and javascript escaped, percent-encoded and unicode forms
+pub fn encoded_forms(from: &str, to: &str) -> Vec<(String, String)> {
+    let mut forms = vec![(from.to_string(), to.to_string())];
+    if from.contains('/') {
+        forms.push((from.replace('/', "\\/"), to.replace('/', "\\/")));
+        // of javascript and json strings
+        for slash in &["\\u002F", "\\u002f", "\\x2F", "\\x2f"] {
+            forms.push((from.replace('/', slash), to.replace('/', slash)));
+        }
+    }
+    if from.contains('/') || from.contains(':') {
+        for (slash, colon) in &[("%2F", "%3A"), ("%2f", "%3a")] {
+            let encode = |s: &str| s.replace('/', slash).replace(':', colon);
+            forms.push((encode(from), encode(to)));
+        }
+    }
+    if from.contains("xn--") {
+        let (host, rest) = match from.find(|c| c == '/' || c == ':') {
+            Some(i) => from.split_at(i),
+            None => (from, ""),
+        };
+        if let (unicode, Ok(())) = idna::domain_to_unicode(host) {
+            forms.push((format!("{}{}", unicode, rest), to.to_string()));
+        }
+    }
+    forms
+}
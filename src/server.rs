@@ -1,24 +1,109 @@
 use std::{
+    borrow::Cow,
     collections::HashMap,
     convert::{TryFrom, TryInto},
-    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    fs::{self, Permissions},
+    future::Future,
+    net::{IpAddr, SocketAddr, TcpListener},
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        io::{FromRawFd, RawFd},
+        net::UnixListener,
+    },
+    path::Path,
+    sync::{Arc, Mutex, RwLock, Weak},
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Error, Result};
 use async_compression::futures::bufread::{
     BrotliDecoder, BrotliEncoder, DeflateDecoder, DeflateEncoder, GzipDecoder, GzipEncoder,
+    ZstdDecoder, ZstdEncoder,
+};
+use async_native_tls::{TlsAcceptor, TlsConnector};
+use encoding_rs::UTF_8;
+use futures::{
+    future::{self, select, try_join_all, Either, FutureExt},
+    io::{self, AsyncReadExt, AsyncWrite, AsyncWriteExt},
 };
 use http_types::{
-    headers::HeaderValue, Body, Error as HttpError, Request, Response, StatusCode, Url,
+    headers::{HeaderValue, HeaderValues},
+    mime, Body, Error as HttpError, Method, Request, Response, StatusCode, Url,
 };
+use signal_hook::{iterator::Signals, SIGHUP};
 use smol::{io::AsyncRead, Async, Task};
 
-use crate::constants::{CONFIG, FORWARD};
+use crate::{
+    access_log::Upstream,
+    acl::{Acl, Auth},
+    acme::{self, Acme},
+    admin,
+    balance::Balancer,
+    breaker::{self, Breaker},
+    cache::{self, Cache, Conditions},
+    cassette::Cassette,
+    coalesce::{Coalescer, Role},
+    charset::{self, Transcoder},
+    config::{
+        CanonicalPolicy, Config, CspPolicy, DomainName, DomainOptions, HealthCheckConfig,
+        HealthCheckMethod, HstsPolicy, RetryConfig, Targets, TcpConfig, UnixConfig, ENCODINGS,
+    },
+    cookie,
+    cookie_jar::CookieJar,
+    cors::Cors,
+    dns::Resolver,
+    export,
+    form,
+    forwarded::{Forwarded, Peer},
+    headers::{self, Vars},
+    host,
+    html::HtmlRewriter,
+    interim::SkipInterim,
+    limit::{self, Bandwidth, PerTarget, Slots},
+    middleware::{self, Layer, Next},
+    optimize::Optimizer,
+    overrides,
+    path_filter::PathFilter,
+    path_rules::PathRules,
+    pool::{Conn, Pool},
+    prefetch,
+    proxy::{Dialer, Proxy},
+    proxy_protocol,
+    quota::Usage,
+    rate_limit::RateLimiter,
+    request_id::{self, RequestId},
+    responder::Responder,
+    rewrite::{self, RegexRule, Replacements, Rewriter},
+    shutdown::{self, Shutdown},
+    sniff,
+    tcp,
+    timeout::{self, IoTimeout, Timeouts},
+    timing::Timing,
+    tls::{self, Certs},
+    trailers::{self, Trailing},
+    websocket::{Rewind, Upgrades},
+};
+
+// address of client, inserted into extensions of request
+pub struct ClientAddr(pub SocketAddr);
+
+// server name of tls ClientHello, inserted into extensions of request, the
+// domain routed to by Host must be the same
+#[derive(Clone)]
+pub struct ServerName(pub String);
 
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> Stream for T {}
+
+#[derive(Clone)]
 struct Target {
     scheme: String,
     host: String,
     port: u16,
+    // base path of target, without trailing `/`
+    path: String,
+    settings: Arc<Settings>,
 }
 
 impl Target {
@@ -34,18 +119,83 @@ impl Target {
         self.port
     }
 
-    async fn address(&self) -> Result<SocketAddr> {
-        let host = self.host.to_string();
-        let port = self.port;
-        smol::unblock!((host.as_str(), port)
-            .to_socket_addrs()?
-            .next()
-            .ok_or(anyhow!("invalid domain")))
+    // with read and write timeouts, for http
+    async fn connect(&self, timing: Option<&Timing>) -> Result<Box<dyn Stream>> {
+        let timeouts = &self.settings.timeouts;
+        let stream = self.handshake(timing).await?;
+        Ok(Box::new(IoTimeout::new(stream, timeouts.read, timeouts.write)))
+    }
+
+    // through proxy and tls, within connect timeout
+    async fn handshake(&self, timing: Option<&Timing>) -> Result<Box<dyn Stream>> {
+        timeout::within(self.settings.timeouts.connect, async {
+            let stream = self
+                .settings
+                .proxy
+                .connect(self.host(), self.port(), &self.settings.dialer, timing)
+                .await?;
+            tcp::configure(stream.get_ref(), &self.settings.tcp)?;
+            let start = Instant::now();
+            let stream: Box<dyn Stream> = match self.scheme() {
+                "https" => {
+                    let tls = self.settings.options.tls.as_ref();
+                    let domain = tls.and_then(|i| i.sni.as_deref()).unwrap_or(self.host());
+                    let stream: Box<dyn Stream> = match &self.settings.tls {
+                        Some(connector) => Box::new(connector.connect(domain, stream).await?),
+                        None => Box::new(async_native_tls::connect(domain, stream).await?),
+                    };
+                    if let Some(timing) = timing {
+                        timing.since("tls", start);
+                    }
+                    stream
+                }
+                "http" => Box::new(stream),
+                s => return Err(anyhow!("unsupported scheme: {}", s)),
+            };
+            Ok::<_, Error>(stream)
+        })
+        .await
+    }
+
+    fn pool_key(&self) -> String {
+        // connections made with other tls options or local address are not shared
+        format!(
+            "{} {:?} {:?} {:?}",
+            self.origin(),
+            self.settings.proxy,
+            self.settings.options.tls,
+            self.settings.dialer.local
+        )
+    }
+
+    fn origin(&self) -> String {
+        format!("{}://{}", self.scheme, self.host_with_port())
     }
 
-    fn fuse_request(&self, req: Request) -> Result<Request> {
+    // body_replacements are (mirror, origin) pairs to rewrite request body
+    fn fuse_request(
+        &self,
+        req: Request,
+        body_replacements: Option<Arc<Replacements>>,
+    ) -> Result<Request> {
         let mut req = req;
         req.insert_header("host", self.host());
+        // framing of body is written by encoder from its length, chunked if unknown,
+        // `Expect: 100-continue` is kept for a body, which is held back until target
+        // answers `100 Continue`, the client gets its own from async-h1 once its body
+        // is read, so only when target asked for it
+        let expect = req.len() != Some(0) && expects_continue(&req);
+        for name in &["content-length", "transfer-encoding", "expect"] {
+            req.remove_header(*name);
+        }
+        if expect {
+            req.insert_header("expect", "100-continue");
+        }
+        if let Some(replacements) = body_replacements {
+            let body = req.take_body();
+            let body = async_std::io::BufReader::new(Rewriter::new(body, replacements));
+            req.set_body(Body::from_reader(body, None));
+        }
         let dest_url = req.url_mut();
         dest_url
             .set_scheme(self.scheme())
@@ -54,9 +204,42 @@ impl Target {
         dest_url
             .set_port(Some(self.port))
             .map_err(|_| anyhow!("set port error"))?;
+        if let Some((path, query)) = self.settings.path_rules.map(dest_url.path()) {
+            dest_url.set_path(&path);
+            if let Some(query) = query {
+                let query = match dest_url.query() {
+                    Some(q) if !q.is_empty() => format!("{}&{}", q, query),
+                    _ => query.to_string(),
+                };
+                dest_url.set_query(Some(&query));
+            }
+        }
+        if !self.path.is_empty() {
+            let path = format!("{}{}", self.path, dest_url.path());
+            dest_url.set_path(&path);
+        }
         Ok(req)
     }
 
+    // port is ignored when scheme differs, e.g. redirect from http to https
+    fn same_origin(&self, url: &Url) -> bool {
+        url.scheme() != self.scheme || url.port_or_known_default() == Some(self.port)
+    }
+
+    fn serves(&self, url: &Url) -> bool {
+        url.host_str() == Some(self.host())
+            && self.same_origin(url)
+            && url.path().starts_with(&self.path)
+    }
+
+    // every target of a list, the first one is self
+    fn upstreams(&self) -> Vec<&Target> {
+        match &self.settings.balancer {
+            Some(balancer) => balancer.targets().collect(),
+            None => vec![self],
+        }
+    }
+
     fn host_with_port(&self) -> String {
         if (self.scheme == "http" && self.port == 80)
             || (self.scheme == "https" && self.port == 443)
@@ -86,202 +269,2655 @@ impl TryFrom<&str> for Target {
             scheme: url.scheme().to_string(),
             host: host.to_string(),
             port,
+            path: url.path().trim_end_matches('/').to_string(),
+            settings: Arc::new(Settings::default()),
         })
     }
 }
 
-pub struct Forward<'a> {
-    domain: HashMap<&'a str, Target>,
+// settings of a mirror domain, shared by its targets
+#[derive(Default)]
+struct Settings {
+    options: DomainOptions,
+    proxy: Proxy,
+    rate_limiter: Option<RateLimiter>,
+    acl: Option<Acl>,
+    auth: Option<Auth>,
+    timeouts: Timeouts,
+    retry: Option<RetryConfig>,
+    breaker: Option<Breaker>,
+    optimizer: Option<Optimizer>,
+    // cached entries stale for less are served
+    stale_while_revalidate: Duration,
+    stale_if_error: Duration,
+    // for a list of targets, or a target with health check
+    balancer: Option<Arc<Balancer<Target>>>,
+    maintenance_page: Option<String>,
+    rewrite_rules: Vec<RegexRule>,
+    path_rules: PathRules,
+    path_filter: Option<PathFilter>,
+    noindex: bool,
+    responders: Vec<Responder>,
+    cors: Option<Cors>,
+    tls: Option<TlsConnector>,
+    tcp: TcpConfig,
+    dialer: Dialer,
 }
 
-impl<'a> Forward<'a> {
-    pub fn new(domain_name: &'a HashMap<String, String>) -> Result<Forward<'a>> {
-        let mut domain = HashMap::new();
-        for (k, v) in domain_name {
-            let target = v.as_str().try_into()?;
-            domain.insert(k.as_str(), target);
+impl Settings {
+    // global_proxy and resolver are built from config
+    fn new(
+        options: Option<&DomainOptions>,
+        global_proxy: &Proxy,
+        resolver: &Option<Arc<Resolver>>,
+        config: &Config,
+    ) -> Result<Settings> {
+        let options = options.cloned().unwrap_or_default();
+        let proxy = match options.proxy.as_ref().or(options.socks5_server.as_ref()) {
+            Some(server) => Proxy::parse(server)?,
+            None => global_proxy.clone(),
+        };
+        Ok(Settings {
+            proxy,
+            rate_limiter: options.rate_limit.as_ref().map(RateLimiter::new),
+            acl: Acl::new(&options.allow, &options.deny)?,
+            auth: Auth::new(&options.basic_auth, &options.bearer_tokens),
+            timeouts: Timeouts::new(options.timeout.as_ref(), &config.timeout),
+            retry: options.retry.clone().or_else(|| config.retry.clone()),
+            breaker: options
+                .circuit_breaker
+                .as_ref()
+                .or(config.circuit_breaker.as_ref())
+                .map(Breaker::new),
+            optimizer: options
+                .image
+                .as_ref()
+                .or(config.image.as_ref())
+                .map(Optimizer::new),
+            stale_while_revalidate: Duration::from_secs(
+                options.stale_while_revalidate.unwrap_or_else(|| {
+                    config.cache.as_ref().map_or(0, |i| i.stale_while_revalidate)
+                }),
+            ),
+            stale_if_error: Duration::from_secs(options.stale_if_error.unwrap_or_else(|| {
+                config.cache.as_ref().map_or(0, |i| i.stale_if_error)
+            })),
+            balancer: None,
+            tls: options.tls.as_ref().map(tls::connector).transpose()?,
+            path_rules: PathRules::new(&options.path_rules),
+            path_filter: PathFilter::new(
+                &options.allow_paths,
+                &options.deny_paths,
+                options.blocked_status.unwrap_or(404),
+            )?,
+            noindex: options.noindex.unwrap_or(config.noindex),
+            tcp: config.tcp.clone(),
+            dialer: Dialer {
+                local: options
+                    .bind_address
+                    .as_ref()
+                    .or(config.bind_address.as_ref())
+                    .map(|i| i.parse::<IpAddr>())
+                    .transpose()?,
+                resolver: resolver.clone(),
+            },
+            cors: options.cors.as_ref().map(Cors::new),
+            responders: options
+                .responders
+                .iter()
+                .map(Responder::new)
+                .collect::<Result<_>>()?,
+            rewrite_rules: options
+                .rewrite_rules
+                .iter()
+                .map(RegexRule::new)
+                .collect::<Result<_>>()?,
+            maintenance_page: options
+                .maintenance_page
+                .as_ref()
+                .or(config.maintenance_page.as_ref())
+                .map(std::fs::read_to_string)
+                .transpose()?,
+            options,
+        })
+    }
+
+    // all targets are down
+    fn unavailable(&self) -> Response {
+        let mut resp = Response::new(StatusCode::ServiceUnavailable);
+        if let Some(page) = &self.maintenance_page {
+            resp.set_body(page.as_str());
+            resp.set_content_type(mime::HTML);
         }
-        Ok(Forward { domain })
+        resp
     }
+}
 
-    pub async fn forward(&self, req: Request) -> http_types::Result<Response> {
-        let url = req.url();
-        let domain = match url.domain() {
-            Some(h) => h,
-            None => return Err(http_error("missing domain".to_string())),
-        };
-        match self.domain.get(domain) {
-            Some(domain) => self.request(req, domain).await,
-            None => return Err(http_error("invalid domain, check config file".to_string())),
+// `*.x.com: *.google.com` maps `a.x.com` to `a.google.com`
+struct Wildcard {
+    // `.x.com`
+    suffix: String,
+    // target url with `*` in host
+    target: String,
+    // `.google.com`, with port if not default
+    target_suffix: String,
+    // target with `wildcard` as the variable label
+    placeholder: Target,
+    settings: Arc<Settings>,
+}
+
+impl Wildcard {
+    fn new(key: &str, target: &str) -> Result<Wildcard> {
+        if target.matches('*').count() != 1 {
+            return Err(anyhow!("wildcard domain {} needs a wildcard target", key));
         }
+        let placeholder: Target = target.replace('*', "wildcard").as_str().try_into()?;
+        let target_suffix = placeholder
+            .host_with_port()
+            .strip_prefix("wildcard")
+            .filter(|i| i.starts_with('.'))
+            .ok_or(anyhow!("invalid wildcard target: {}", target))?
+            .to_string();
+        Ok(Wildcard {
+            suffix: key[1..].to_string(),
+            target: target.to_string(),
+            target_suffix,
+            placeholder,
+            settings: Arc::new(Settings::default()),
+        })
     }
 
-    async fn request(&self, req: Request, target: &Target) -> http_types::Result<Response> {
-        let host = target.host();
-        let addr = target
-            .address()
-            .await
-            .map_err(|_| http_error("invalid target".to_string()))?;
-        let req = target
-            .fuse_request(req)
-            .map_err(|e| http_error(e.to_string()))?;
+    fn mirror_host(&self, url: &Url) -> Option<String> {
+        let host_suffix = &self.placeholder.host["wildcard".len()..];
+        let label = url.host_str()?.strip_suffix(host_suffix)?;
+        if label.is_empty() || !self.placeholder.same_origin(url) {
+            return None;
+        }
+        Some(format!("{}{}", label, self.suffix))
+    }
 
-        let stream = match &CONFIG.socks5_server {
-            Some(server) => {
-                let server = server.clone();
-                let server = smol::unblock!(server
-                    .to_socket_addrs()?
-                    .next()
-                    .ok_or(anyhow!("invalid host")))?;
-                socks5::connect_without_auth(server, (host.to_string(), target.port()).into())
-                    .await?
-            }
-            None => Async::<TcpStream>::connect(addr).await?,
-        };
+    fn target(&self, domain: &str) -> Option<Target> {
+        let label = domain.strip_suffix(&self.suffix)?;
+        if label.is_empty() {
+            return None;
+        }
+        let mut target: Target = self.target.replace('*', label).as_str().try_into().ok()?;
+        target.settings = self.settings.clone();
+        Some(target)
+    }
+}
+
+// `x.com/gh: github.com` maps `x.com/gh/*` to `github.com/*`
+struct Route {
+    domain: String,
+    // `/gh`
+    prefix: String,
+    target: Target,
+}
+
+impl Route {
+    fn matches(&self, domain: &str, path: &str) -> bool {
+        self.domain == domain
+            && path.starts_with(&self.prefix)
+            && matches!(path[self.prefix.len()..].chars().next(), None | Some('/'))
+    }
+}
 
-        let mut resp = match target.scheme() {
-            "https" => {
-                let stream = async_native_tls::connect(host, stream).await?;
-                async_h1::connect(stream, req).await?
+// let links relative to root stay under the prefix of route
+fn relative_replacements(prefix: &str, forms: &[&str]) -> Vec<(String, String)> {
+    let mut replacements = Vec::new();
+    for attr in forms {
+        for quote in &["\"", "'", ""] {
+            if *attr != "url(" && quote.is_empty() {
+                continue;
             }
-            "http" => async_h1::connect(stream, req).await?,
-            s => return Err(http_error(format!("unsupported scheme: {}", s))),
-        };
+            let from = format!("{}{}/", attr, quote);
+            let to = format!("{}{}{}/", attr, quote, prefix);
+            // protocol relative urls are left as they are
+            let keep = format!("{}{}//", attr, quote);
+            replacements.push((from, to));
+            replacements.push((keep.clone(), keep));
+        }
+    }
+    replacements
+}
 
-        if let Some(location) = resp.header("location") {
-            let mut location = location.as_str().to_string();
-            for (k, v) in &self.domain {
-                location = location.replace(&v.host_with_port(), k);
+// links to paths mapped by path rules of target are mapped back, with prefix
+// of route where it's not added by relative_replacements or HtmlRewriter
+fn path_replacements(
+    target: &Target,
+    mirror: &str,
+    prefix: &str,
+    html: bool,
+) -> Vec<(String, String)> {
+    let mut replacements = Vec::new();
+    for (to, from) in target.settings.path_rules.prefixes() {
+        // absolute ones point to mirror already
+        let base = format!("{}{}", mirror, prefix);
+        replacements.push((format!("{}{}", base, to), format!("{}{}", base, from)));
+        for attr in &["href=", "src=", "action=", "url(", "@import "] {
+            for quote in &["\"", "'", ""] {
+                if *attr != "url(" && quote.is_empty() {
+                    continue;
+                }
+                let prefix = if html && attr.ends_with('=') { "" } else { prefix };
+                let link = format!("{}{}{}", attr, quote, to);
+                replacements.push((link, format!("{}{}{}{}", attr, quote, prefix, from)));
+                // protocol relative urls are left as they are
+                let keep = format!("{}{}//", attr, quote);
+                replacements.push((keep.clone(), keep));
             }
-            resp.insert_header("location", location);
         }
+    }
+    replacements
+}
 
-        if let Some(referer) = resp.header("referer") {
-            let mut referer = referer.as_str().to_string();
-            for (k, v) in &self.domain {
-                referer = referer.replace(&v.host_with_port(), k);
+// origin host to (target, mirror host, path prefix of mirror), longer target paths first
+type ReverseIndex = HashMap<String, Vec<(Target, String, String)>>;
+
+// (origin, mirror) pairs with their encoded forms, and the reverse index,
+// fails if an origin is mapped to more than one mirror
+fn build_index(
+    domain: &HashMap<String, Target>,
+    wildcard: &[Wildcard],
+    routes: &[Route],
+) -> Result<(Vec<(String, String)>, ReverseIndex)> {
+    // routes before domains
+    let mut mirrors = Vec::new();
+    for r in routes {
+        for u in r.target.upstreams() {
+            mirrors.push((u, &r.target.settings, r.domain.as_str(), r.prefix.as_str()));
+        }
+    }
+    for (k, v) in domain {
+        for u in v.upstreams() {
+            mirrors.push((u, &v.settings, k.as_str(), ""));
+        }
+    }
+    let mut origins: HashMap<String, String> = HashMap::new();
+    let mut reverse = ReverseIndex::new();
+    for (target, settings, host, prefix) in mirrors {
+        let origin = format!("{}{}", target.host_with_port(), target.path);
+        let mirror = format!("{}{}", host, prefix);
+        if let Some(other) = origins.get(&origin) {
+            if *other != mirror {
+                return Err(anyhow!("{} and {} both map to {}", other, mirror, origin));
             }
-            resp.insert_header("referer", referer);
         }
+        origins.insert(origin, mirror);
+        reverse
+            .entry(target.host.clone())
+            .or_insert_with(Vec::new)
+            .push((
+                // targets of a list come without settings of domain
+                Target {
+                    settings: settings.clone(),
+                    ..target.clone()
+                },
+                host.to_string(),
+                prefix.to_string(),
+            ));
+    }
+    for mirrors in reverse.values_mut() {
+        mirrors.sort_by(|a, b| b.0.path.len().cmp(&a.0.path.len()));
+    }
+    let mut replacements: Vec<_> = origins.into_iter().collect();
+    for w in wildcard {
+        replacements.push((w.target_suffix.clone(), w.suffix.clone()));
+    }
+    let replacements = replacements
+        .iter()
+        .flat_map(|(from, to)| rewrite::encoded_forms(from, to))
+        .collect();
+    Ok((replacements, reverse))
+}
 
-        if let Some(cookie) = resp.header("set-cookie") {
-            let cookie: Vec<_> = cookie
+pub struct Forward {
+    domain: HashMap<String, Target>,
+    wildcard: Vec<Wildcard>,
+    routes: Vec<Route>,
+    // origin to mirror, used to rewrite headers and bodies
+    replacements: Arc<Replacements>,
+    // mirror to origin, used to rewrite request bodies
+    request_replacements: Arc<Replacements>,
+    reverse: ReverseIndex,
+    cache: Option<Arc<Cache>>,
+    pool: Arc<Pool>,
+    rewrite_content_types: Vec<String>,
+    rewrite_request_content_types: Vec<String>,
+    csp: CspPolicy,
+    rate_limiter: Option<RateLimiter>,
+    acl: Option<Acl>,
+    forwarded: Forwarded,
+    // contents by status code or `default`
+    error_pages: HashMap<String, String>,
+    rewrite_rules: Vec<RegexRule>,
+    responders: Vec<Responder>,
+    bandwidth: Option<Bandwidth>,
+    per_target: Option<PerTarget>,
+    jar: Option<CookieJar>,
+    cassette: Option<Cassette>,
+    coalescer: Option<Coalescer>,
+    // replaced by that of the current one when this becomes current
+    usage: Arc<Usage>,
+    // where this is built from
+    config: Config,
+}
+
+impl Forward {
+    pub fn new(config: &Config) -> Result<Forward> {
+        let mut domain = HashMap::new();
+        let mut wildcard = Vec::new();
+        let mut routes = Vec::new();
+        let global_proxy = match config.proxy.as_ref().or(config.socks5_server.as_ref()) {
+            Some(server) => Proxy::parse(server)?,
+            None => Proxy::Direct,
+        };
+        let resolver = config.dns.as_ref().map(Resolver::new).transpose()?.map(Arc::new);
+        for (k, v) in &config.domain_name {
+            let mut settings = Settings::new(v.options(), &global_proxy, &resolver, config)?;
+            let targets: Vec<_> = v
+                .targets()
                 .iter()
-                .map(|i| {
-                    let i = i.as_str();
-                    let i: Vec<_> = i
-                        .split(';')
-                        .filter(|i| {
-                            let i = i.trim_start();
-                            !(i.len() > 7 && i[..7].to_lowercase() == "domain=")
-                        })
-                        .collect();
-                    let i = i.join(";");
-                    unsafe { HeaderValue::from_bytes_unchecked(i.as_bytes().to_vec()) }
-                })
+                .map(|i| i.trim_end_matches("/*"))
                 .collect();
-            resp.insert_header("set-cookie", cookie.as_slice());
+            if targets.is_empty() {
+                return Err(anyhow!("empty target list of {}", k));
+            }
+            let health_check = settings.options.health_check.is_some();
+            if targets.len() > 1 || health_check {
+                if k.starts_with("*.") {
+                    return Err(anyhow!(
+                        "wildcard {} can not have a target list or health check",
+                        k
+                    ));
+                }
+                let targets = targets
+                    .iter()
+                    .map(|i| Target::try_from(*i))
+                    .collect::<Result<_>>()?;
+                settings.balancer = Some(Arc::new(Balancer::new(targets, &settings.options)));
+            }
+            let settings = Arc::new(settings);
+            if health_check {
+                watch_health(Arc::downgrade(&settings));
+            }
+            let v = v.targets()[0].as_str();
+            if let Some(i) = k.find('/') {
+                let mut target: Target = targets[0].try_into()?;
+                target.settings = settings;
+                routes.push(Route {
+                    domain: k[..i].to_string(),
+                    prefix: k[i..].trim_end_matches("/*").trim_end_matches('/').to_string(),
+                    target,
+                });
+                continue;
+            }
+            if k.starts_with("*.") {
+                let mut w = Wildcard::new(k, v)?;
+                w.settings = settings;
+                wildcard.push(w);
+                continue;
+            }
+            let mut target: Target = v.try_into()?;
+            target.settings = settings;
+            domain.insert(k.to_string(), target);
         }
+        // longest suffix wins
+        wildcard.sort_by(|a, b| b.suffix.len().cmp(&a.suffix.len()));
+        // longest prefix wins
+        routes.sort_by(|a, b| b.prefix.len().cmp(&a.prefix.len()));
+        let (replacements, reverse) = build_index(&domain, &wildcard, &routes)?;
+        Ok(Forward {
+            domain,
+            wildcard,
+            routes,
+            request_replacements: Arc::new(Replacements::new(
+                replacements
+                    .iter()
+                    .map(|(origin, mirror)| (mirror.clone(), origin.clone()))
+                    .collect(),
+            )),
+            replacements: Arc::new(Replacements::new(replacements)),
+            reverse,
+            cache: config
+                .cache
+                .as_ref()
+                .map(|i| Cache::new(i).map(Arc::new))
+                .transpose()?,
+            pool: Arc::new(Pool::new(&config.pool)),
+            rewrite_content_types: config.rewrite_content_types.clone(),
+            rewrite_request_content_types: config.rewrite_request_content_types.clone(),
+            csp: config.content_security_policy,
+            rate_limiter: config.rate_limit.as_ref().map(RateLimiter::new),
+            acl: Acl::new(&config.allow, &config.deny)?,
+            forwarded: Forwarded::new(&config.forwarded)?,
+            error_pages: config
+                .error_pages
+                .iter()
+                .map(|(k, v)| Ok((k.to_string(), std::fs::read_to_string(v)?)))
+                .collect::<Result<_>>()?,
+            rewrite_rules: config
+                .rewrite_rules
+                .iter()
+                .map(RegexRule::new)
+                .collect::<Result<_>>()?,
+            responders: config
+                .responders
+                .iter()
+                .map(Responder::new)
+                .collect::<Result<_>>()?,
+            bandwidth: config.response_limit.bandwidth.map(Bandwidth::new),
+            per_target: config.concurrency.max_requests_per_target.map(PerTarget::new),
+            jar: config.cookie.jar.as_ref().map(CookieJar::new),
+            cassette: config.cassette.as_ref().map(Cassette::new).transpose()?,
+            coalescer: if config.coalesce {
+                Some(Coalescer::default())
+            } else {
+                None
+            },
+            usage: Arc::new(Usage::default()),
+            config: config.clone(),
+        })
+    }
 
-        if resp.status() == StatusCode::NotModified {
-            return Ok(resp);
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn usage(&self) -> &Usage {
+        &self.usage
+    }
+
+    pub fn cache(&self) -> Option<&Cache> {
+        self.cache.as_deref()
+    }
+
+    // warm cache with subresources of a page, one by one, with the encodings
+    // of the client as they are part of keys
+    pub async fn prefetch(&self, page: Url, accept_encoding: Option<String>, html: Vec<u8>) {
+        let max = match self.cache().and_then(|i| i.prefetch()) {
+            Some(config) => config.max_links,
+            None => return,
+        };
+        for url in prefetch::links(&html, &page, max) {
+            let mut req = Request::new(Method::Get, url.clone());
+            if let Some(accept_encoding) = &accept_encoding {
+                req.insert_header("accept-encoding", accept_encoding.as_str());
+            }
+            let result = match self.forward(req).await {
+                Ok(mut resp) => resp.body_bytes().await.map(|_| ()),
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                debug!("prefetching {}: {}", url, err);
+            }
         }
+    }
 
-        Coder::De.code(&mut resp);
+    pub fn client(&self, req: &Request, peer: Peer) -> SocketAddr {
+        self.forwarded.client(req, peer)
+    }
 
-        // replace domain
-        if let Some(content_type) = resp.content_type() {
-            match content_type.essence() {
-                "text/html"
-                | "text/javascript"
-                | "application/json"
-                | "application/manifest+json" => match resp.body_string().await {
-                    Ok(mut body) => {
-                        for (k, v) in &self.domain {
-                            body = body.replace(&v.host_with_port(), k);
-                        }
-                        resp.set_body(body);
-                    }
-                    Err(_) => error!("can not convert body to utf-8 string"),
-                },
-                _ => (),
+    // by error page of its status if any
+    // request id, kept from a trusted proxy or generated
+    pub fn request_id(&self, req: &Request, peer: Peer) -> String {
+        let header = self.config.request_id.header.as_str();
+        let trusted = self.forwarded.trusts(peer.addr.ip());
+        match req.header(header) {
+            Some(id) if trusted && request_id::is_valid(id.as_str()) => id.as_str().to_string(),
+            _ => request_id::generate(),
+        }
+    }
+
+    pub fn error_response(&self, err: &HttpError, id: &str) -> Response {
+        let status = err.status();
+        let code = u16::from(status).to_string();
+        let detail = if self.config.hide_error_detail {
+            error!("request {}: {}", id, err);
+            String::new()
+        } else {
+            err.to_string()
+        };
+        let mut resp = Response::new(status);
+        let page = self
+            .error_pages
+            .get(&code)
+            .or_else(|| self.error_pages.get("default"));
+        match page {
+            Some(page) => {
+                let body = page
+                    .replace("{status}", &code)
+                    .replace("{reason}", status.canonical_reason())
+                    .replace("{detail}", &escape_html(&detail))
+                    .replace("{request_id}", id);
+                resp.set_body(body);
+                resp.set_content_type(mime::HTML);
             }
+            None if detail.is_empty() => resp.set_body(status.canonical_reason()),
+            None => resp.set_body(detail),
         }
+        resp
+    }
 
-        Coder::En.code(&mut resp);
+    fn target(&self, domain: &str) -> Option<Cow<Target>> {
+        if let Some(target) = self.domain.get(domain) {
+            return Some(Cow::Borrowed(target));
+        }
+        self.wildcard
+            .iter()
+            .find_map(|w| w.target(domain))
+            .map(Cow::Owned)
+    }
 
-        Ok(resp)
+    // mirror host and path for a url of target
+    fn mirror_of(&self, url: &Url) -> Option<(String, String)> {
+        let mirror = url
+            .host_str()
+            .and_then(|host| self.reverse.get(host))
+            .and_then(|mirrors| mirrors.iter().find(|(target, ..)| target.serves(url)));
+        let unmap = |settings: &Settings, path: &str| {
+            settings
+                .path_rules
+                .unmap(path)
+                .unwrap_or_else(|| path.to_string())
+        };
+        if let Some((target, host, prefix)) = mirror {
+            let path = unmap(&target.settings, &url.path()[target.path.len()..]);
+            return Some((host.clone(), format!("{}{}", prefix, path)));
+        }
+        self.wildcard.iter().find_map(|w| {
+            let host = w.mirror_host(url)?;
+            Some((host, unmap(&w.settings, url.path())))
+        })
     }
-}
 
-enum Coder {
-    De,
-    En,
-}
+    fn rewrite_location(&self, location: &str, upstream_url: &Url, mirror_url: &Url) -> String {
+        let mut url = match upstream_url.join(location) {
+            Ok(url) => url,
+            Err(_) => return location.to_string(),
+        };
+        let (host, path) = match self.mirror_of(&url) {
+            Some(mirror) => mirror,
+            None => return url.to_string(),
+        };
+        url.set_path(&path);
+        // keep scheme and port of mirror by a relative location
+        if mirror_url.host_str() == Some(host.as_str()) {
+            let mut location = url.path().to_string();
+            if let Some(query) = url.query() {
+                location.push('?');
+                location.push_str(query);
+            }
+            if let Some(fragment) = url.fragment() {
+                location.push('#');
+                location.push_str(fragment);
+            }
+            return location;
+        }
+        if url.set_host(Some(&host)).is_err() || url.set_port(None).is_err() {
+            return location.to_string();
+        }
+        url.to_string()
+    }
 
-impl Coder {
-    fn set_body<T>(resp: &mut Response, coder: T)
-    where
-        T: AsyncRead + Unpin + Send + Sync + 'static,
-    {
-        let coder = async_std::io::BufReader::new(coder);
-        let body = Body::from_reader(coder, None);
-        resp.set_body(body);
+    // urls in `<...>` of a Link header
+    fn rewrite_link(&self, link: &str, upstream_url: &Url, mirror_url: &Url) -> String {
+        let mut rewritten = String::new();
+        let mut rest = link;
+        while let Some(start) = rest.find('<') {
+            let end = match rest[start..].find('>') {
+                Some(i) => start + i,
+                None => break,
+            };
+            rewritten.push_str(&rest[..=start]);
+            let url = &rest[start + 1..end];
+            rewritten.push_str(&self.rewrite_location(url, upstream_url, mirror_url));
+            rest = &rest[end..];
+        }
+        rewritten.push_str(rest);
+        rewritten
     }
 
-    fn code(&self, resp: &mut Response) {
-        if let Some(encoding) = resp.header("content-encoding") {
-            let encoding = encoding.as_str();
-            match encoding {
-                "gzip" => {
-                    let body = resp.take_body();
-                    match self {
-                        Coder::En => Coder::set_body(resp, GzipEncoder::new(body)),
-                        Coder::De => Coder::set_body(resp, GzipDecoder::new(body)),
-                    }
+    // map a mirror url to url of target
+    fn target_url(&self, url: &Url) -> Option<Url> {
+        let domain = url.host_str()?;
+        let path = url.path();
+        let (target, path) = match self.routes.iter().find(|r| r.matches(domain, path)) {
+            Some(r) => (Cow::Borrowed(&r.target), &path[r.prefix.len()..]),
+            None => (self.target(domain)?, path),
+        };
+        let path = match target.settings.path_rules.map(path) {
+            Some((path, _)) => format!("{}{}", target.path, path),
+            None => format!("{}{}", target.path, path),
+        };
+        let mut url = url.clone();
+        url.set_scheme(target.scheme()).ok()?;
+        url.set_host(Some(target.host())).ok()?;
+        if target.host_with_port() == target.host {
+            url.set_port(None).ok()?;
+        } else {
+            url.set_port(Some(target.port())).ok()?;
+        }
+        url.set_path(&path);
+        Some(url)
+    }
+
+    // Referer and Origin sent by browser point to mirror
+    fn map_request_headers(&self, req: &mut Request) {
+        let referer = req
+            .header("referer")
+            .and_then(|i| Url::parse(i.as_str()).ok())
+            .and_then(|i| self.target_url(&i));
+        if let Some(referer) = referer {
+            req.insert_header("referer", referer.to_string());
+        }
+        let origin = req
+            .header("origin")
+            .and_then(|i| Url::parse(i.as_str()).ok())
+            .and_then(|i| self.target_url(&i));
+        if let Some(origin) = origin {
+            req.insert_header("origin", origin.origin().ascii_serialization());
+        }
+        // etags are weakened when bodies are compressed
+        if let Some(if_none_match) = req.header("if-none-match") {
+            let if_none_match: Vec<_> = if_none_match
+                .as_str()
+                .split(',')
+                .map(|i| cache::weak(i.trim()))
+                .collect();
+            req.insert_header("if-none-match", if_none_match.join(", "));
+        }
+    }
+
+    // mirror domain for `Domain=` of a cookie set by target
+    fn cookie_domain(&self, domain: &str, target: &Target, mirror_host: &str) -> Option<String> {
+        let domain = domain.trim_start_matches('.').to_lowercase();
+        let dot_domain = format!(".{}", domain);
+        // shared by subdomains of wildcard
+        for w in &self.wildcard {
+            let host_suffix = &w.placeholder.host["wildcard".len()..];
+            if dot_domain == host_suffix {
+                return Some(w.suffix[1..].to_string());
+            }
+            if let Some(label) = dot_domain.strip_suffix(host_suffix) {
+                return Some(format!("{}{}", &label[1..], w.suffix));
+            }
+        }
+        let covers = |host: &str| host == domain || host.ends_with(&dot_domain);
+        if covers(&target.host) {
+            return Some(mirror_host.to_string());
+        }
+        self.domain
+            .iter()
+            .find(|(_, v)| v.upstreams().iter().any(|u| covers(&u.host)))
+            .map(|(k, _)| k.to_string())
+    }
+
+    // mirror to origin, if body of request needs rewriting
+    fn request_body_replacements(&self, req: &Request) -> Option<Arc<Replacements>> {
+        let content_type = req.content_type()?;
+        if req.header("content-encoding").is_some()
+            || !self
+                .rewrite_request_content_types
+                .iter()
+                .any(|i| i == content_type.essence())
+        {
+            return None;
+        }
+        Some(self.request_replacements.clone())
+    }
+
+    // snippets inserted before `</head>` and `</body>` of html
+    fn injections(&self, target: &Target) -> Vec<(String, String)> {
+        let domain = target.settings.options.inject.as_ref();
+        let global = &self.config.inject;
+        let mut injections = Vec::new();
+        let head = domain.and_then(|i| i.head.as_ref()).or(global.head.as_ref());
+        let body = domain.and_then(|i| i.body.as_ref()).or(global.body.as_ref());
+        for (snippet, tag) in vec![(head, "head"), (body, "body")] {
+            // empty one of domain disables the global one
+            let snippet = match snippet {
+                Some(snippet) if !snippet.is_empty() => snippet,
+                _ => continue,
+            };
+            for tag in &[format!("</{}>", tag), format!("</{}>", tag.to_uppercase())] {
+                injections.push((tag.clone(), format!("{}{}", snippet, tag)));
+            }
+        }
+        injections
+    }
+
+    fn replace_domains(&self, s: &str) -> String {
+        self.replacements.replace(s)
+    }
+
+    pub async fn forward(&self, mut req: Request) -> http_types::Result<Response> {
+        // key is taken before path prefix of route is stripped
+        let key = self.cache.as_ref().and_then(|_| Cache::key(&req));
+        let url = req.url().clone();
+        let (target, prefix, domain) = self.resolve(&mut req)?;
+        // requests of clients, not those of prefetching or refreshing
+        let client = req.ext().get::<Peer>().is_some();
+        let mut resp = self.forward_to(req, key, &target, prefix, &domain).await?;
+        if client {
+            self.usage.count(domain, &mut resp);
+        }
+        if let Some(refresh) = resp.ext_mut().get_mut::<cache::Refresh>() {
+            *refresh.request().url_mut() = url;
+        }
+        if target.settings.noindex {
+            resp.insert_header("x-robots-tag", "noindex, nofollow");
+        }
+        Ok(resp)
+    }
+
+    async fn forward_to(
+        &self,
+        mut req: Request,
+        key: Option<String>,
+        target: &Target,
+        prefix: &str,
+        domain: &str,
+    ) -> http_types::Result<Response> {
+        if let Some(resp) = self.check(&req, target, domain) {
+            return Ok(resp);
+        }
+        if let Some(dir) = &target.settings.options.overrides {
+            if matches!(req.method(), Method::Get | Method::Head) {
+                if let Some(resp) = overrides::find(dir, req.url().path()).await {
+                    return Ok(resp);
                 }
-                "br" => {
-                    let body = resp.take_body();
-                    match self {
-                        Coder::En => Coder::set_body(resp, BrotliEncoder::new(body)),
-                        Coder::De => Coder::set_body(resp, BrotliDecoder::new(body)),
-                    }
+            }
+        }
+        let (cache, key) = match (&self.cache, key) {
+            (Some(cache), Some(key)) => (cache, key),
+            _ => return self.coalesced(req, target, prefix).await,
+        };
+        // responses to requests with cookies may be personal, unless target says otherwise
+        let cookie = req.header("cookie").is_some();
+        let cached = cache.lookup(&key).await.filter(|i| !cookie || i.is_public());
+        let entry = cached.clone().filter(|i| i.is_fresh() || i.has_validators());
+        let conditions = Conditions::of(&req);
+        if let Some(entry) = &entry {
+            if entry.is_fresh() {
+                return Ok(entry.response_to(&conditions));
+            }
+        }
+        let settings = &target.settings;
+        let revalidation = req.ext().get::<cache::Revalidation>().is_some();
+        if let Some(cached) = &cached {
+            if !revalidation && cached.is_fresh_within(settings.stale_while_revalidate) {
+                let mut resp = cached.response_to(&conditions);
+                if let Some(refresh) = cache.start_refresh(&key, copy_request(&req)) {
+                    resp.ext_mut().insert(refresh);
                 }
-                "deflate" => {
-                    let body = resp.take_body();
-                    match self {
-                        Coder::En => Coder::set_body(resp, DeflateEncoder::new(body)),
-                        Coder::De => Coder::set_body(resp, DeflateDecoder::new(body)),
-                    }
+                return Ok(resp);
+            }
+        }
+        if let Some(entry) = &entry {
+            entry.add_validators(&mut req);
+        }
+        let result = self.coalesced(req, target, prefix).await;
+        let open = matches!(&result, Ok(resp) if resp.ext().get::<breaker::Open>().is_some());
+        let failed = match &result {
+            Ok(resp) => resp.status().is_server_error(),
+            Err(_) => true,
+        };
+        // a copy however stale is better than an error while the circuit is open
+        if let Some(cached) = cached {
+            if open || (failed && cached.is_fresh_within(settings.stale_if_error)) {
+                return Ok(cached.response_to(&conditions));
+            }
+        }
+        let mut resp = result?;
+        if resp.status() == StatusCode::NotModified {
+            // 304 to our own revalidation, the client may still need the body
+            if entry.is_some() {
+                if let Some(entry) = cache.refresh(&key, &resp) {
+                    return Ok(entry.response_to(&conditions));
                 }
-                e => error!("unhandled encoding: {}", e),
             }
+            return Ok(resp);
+        }
+        if !cookie || cache::is_public(&resp) {
+            cache.store(key, &mut resp);
         }
+        Ok(resp)
     }
-}
 
-fn http_error(error: String) -> HttpError {
-    HttpError::from_str(StatusCode::InternalServerError, error)
-}
+    // identical requests at once share one request to target, path prefix
+    // of route tells apart those of routes
+    async fn coalesced(
+        &self,
+        req: Request,
+        target: &Target,
+        prefix: &str,
+    ) -> http_types::Result<Response> {
+        let (coalescer, key) = match (&self.coalescer, Coalescer::key(&req)) {
+            (Some(coalescer), Some(key)) => (coalescer, format!("{} {}", prefix, key)),
+            _ => return self.request(req, target, prefix).await,
+        };
+        let flight = match coalescer.join(&key) {
+            Role::Leader(flight) => flight,
+            Role::Follower(shared) => match shared.await {
+                Ok(shared) => return Ok(shared.response()),
+                Err(_) => return self.request(req, target, prefix).await,
+            },
+        };
+        let mut resp = self.request(req, target, prefix).await?;
+        flight.share(&mut resp).await?;
+        Ok(resp)
+    }
 
-async fn serve(req: Request) -> http_types::Result<Response> {
-    FORWARD.forward(req).await
-}
+    // fetch a stale entry into cache again, the stale one is not served to it
+    pub async fn refresh(&self, mut refresh: cache::Refresh) {
+        let mut req = copy_request(refresh.request());
+        req.ext_mut().insert(cache::Revalidation);
+        let url = req.url().clone();
+        let result = match self.forward(req).await {
+            Ok(mut resp) => resp.body_bytes().await.map(|_| ()),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            debug!("refreshing {}: {}", url, err);
+        }
+    }
 
-pub fn run() -> Result<()> {
-    smol::run(async {
-        let addr: SocketAddr = CONFIG.listen_address.as_str().parse()?;
-        let listener = Async::<TcpListener>::bind(addr)?;
-        loop {
-            let (stream, _) = listener.accept().await?;
-            let stream = async_dup::Arc::new(stream);
-            let task = Task::spawn(async move {
-                if let Err(err) = async_h1::accept(stream, serve).await {
-                    error!("Connection error: {:#?}", err);
-                }
-            });
+    // target, path prefix of route and mirror domain of request,
+    // path prefix is stripped from url of request
+    fn resolve(&self, req: &mut Request) -> http_types::Result<(Cow<Target>, &str, String)> {
+        let url = req.url();
+        let domain = match url.host_str() {
+            Some(h) => h.to_string(),
+            None => return Err(http_error("missing domain".to_string())),
+        };
+        let path = url.path().to_string();
+        if let Some(route) = self.routes.iter().find(|r| r.matches(&domain, &path)) {
+            req.url_mut().set_path(&path[route.prefix.len()..]);
+            return Ok((Cow::Borrowed(&route.target), route.prefix.as_str(), domain));
+        }
+        match self.target(&domain) {
+            Some(target) => Ok((target, "", domain)),
+            None => Err(http_error("invalid domain, check config file".to_string())),
+        }
+    }
 
-            task.detach();
+    // a response to send instead of forwarding, if request is not allowed
+    fn check(&self, req: &Request, target: &Target, domain: &str) -> Option<Response> {
+        // a connection is for the domain of its tls handshake only
+        if let Some(ServerName(name)) = req.ext().get::<ServerName>() {
+            if name != domain {
+                return Some(Response::new(StatusCode::MisdirectedRequest));
+            }
         }
-    })
+        let client = req.ext().get::<ClientAddr>().map(|i| i.0.ip());
+        for acl in self.acl.iter().chain(target.settings.acl.iter()) {
+            if let Some(ip) = client {
+                if !acl.permits(ip) {
+                    return Some(Response::new(StatusCode::Forbidden));
+                }
+            }
+        }
+        if target.settings.noindex && req.url().path() == "/robots.txt" {
+            let mut resp = Response::new(StatusCode::Ok);
+            resp.set_body("User-agent: *\nDisallow: /\n");
+            resp.set_content_type(mime::PLAIN);
+            return Some(resp);
+        }
+        if let Some(filter) = &target.settings.path_filter {
+            if let Some(resp) = filter.check(req.url().path()) {
+                return Some(resp);
+            }
+        }
+        let methods = &target.settings.options.methods;
+        let method = req.method().to_string();
+        if !methods.is_empty() && !methods.iter().any(|i| i.eq_ignore_ascii_case(&method)) {
+            let mut resp = Response::new(StatusCode::MethodNotAllowed);
+            let allow: Vec<_> = methods.iter().map(|i| i.to_uppercase()).collect();
+            resp.insert_header("allow", allow.join(", "));
+            return Some(resp);
+        }
+        // preflights come without credentials
+        if let Some(resp) = target.settings.cors.as_ref().and_then(|i| i.preflight(req)) {
+            return Some(resp);
+        }
+        let responder = target
+            .settings
+            .responders
+            .iter()
+            .chain(&self.responders)
+            .find_map(|i| i.respond(req));
+        if let Some(resp) = responder {
+            return Some(resp);
+        }
+        if let Some(resp) = target.settings.auth.as_ref().and_then(|i| i.check(req)) {
+            return Some(resp);
+        }
+        if let Some(resp) = self.quota(target, domain) {
+            return Some(resp);
+        }
+        self.limit(req, target, domain)
+    }
+
+    // status of quota once bytes of the day or month are served
+    fn quota(&self, target: &Target, domain: &str) -> Option<Response> {
+        let quota = target
+            .settings
+            .options
+            .quota
+            .as_ref()
+            .or(self.config.quota.as_ref())?;
+        let retry_after = self.usage.check(domain, quota)?;
+        let mut resp = Response::new(quota.status);
+        resp.insert_header("retry-after", retry_after.to_string());
+        Some(resp)
+    }
+
+    // 429 if rate limit exceeded
+    fn limit(&self, req: &Request, target: &Target, domain: &str) -> Option<Response> {
+        let limiter = target
+            .settings
+            .rate_limiter
+            .as_ref()
+            .or(self.rate_limiter.as_ref())?;
+        let client = req
+            .ext()
+            .get::<ClientAddr>()
+            .map(|i| i.0.ip().to_string())
+            .unwrap_or_default();
+        let retry_after = limiter.check(&client, domain).err()?;
+        let mut resp = Response::new(StatusCode::TooManyRequests);
+        let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        resp.insert_header("retry-after", secs.to_string());
+        Some(resp)
+    }
+
+    pub async fn tunnel<S: Stream>(
+        &self,
+        head: Vec<u8>,
+        mut client: S,
+        peer: Peer,
+        server_name: Option<ServerName>,
+    ) -> Result<()> {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut req = httparse::Request::new(&mut headers);
+        let len = match req.parse(&head)? {
+            httparse::Status::Complete(len) => len,
+            httparse::Status::Partial => return Err(anyhow!("incomplete request head")),
+        };
+        let host = req
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case("host"))
+            .map(|h| String::from_utf8_lossy(h.value).to_string());
+        let domain = host::of_request(req.path.unwrap_or("/"), host.as_deref())
+            .ok_or(anyhow!("missing host"))?;
+
+        // upgrades are routed and let through by the same checks as other requests
+        let mut checked = upgrade_request(&req, &domain)?;
+        let (target, _, domain) = self
+            .resolve(&mut checked)
+            .map_err(|err| anyhow!("{}", err))?;
+        let client_addr = self.client(&checked, peer);
+        checked.ext_mut().insert(peer);
+        checked.ext_mut().insert(ClientAddr(client_addr));
+        if let Some(server_name) = server_name {
+            checked.ext_mut().insert(server_name);
+        }
+        if let Some(resp) = self.check(&checked, &target, &domain) {
+            write_response(&mut client, resp).await?;
+            return Ok(());
+        }
+
+        // another target of the balancer is tried when one can't be connected
+        let attempts = target.upstreams().len();
+        let mut connected = None;
+        for _ in 0..attempts {
+            let lease = match &target.settings.balancer {
+                Some(balancer) => match balancer.pick() {
+                    Some(lease) => Some(lease),
+                    None => break,
+                },
+                None => None,
+            };
+            let picked = match &lease {
+                Some(lease) => Target {
+                    settings: target.settings.clone(),
+                    ..lease.target().clone()
+                },
+                None => target.as_ref().clone(),
+            };
+            let breaker = picked.settings.breaker.as_ref();
+            if !breaker.map_or(true, |i| i.allow(&picked.origin())) {
+                continue;
+            }
+            // without read timeout, websocket may be idle for long
+            let result = picked.handshake(None).await;
+            if let Some(lease) = &lease {
+                if lease.report(result.is_ok()) {
+                    warn!("{} is down", picked.origin());
+                }
+            }
+            if let Some(breaker) = breaker {
+                if breaker.report(&picked.origin(), result.is_ok()) {
+                    warn!("circuit of {} is open", picked.origin());
+                }
+            }
+            match result {
+                Ok(upstream) => {
+                    connected = Some((upstream, picked, lease));
+                    break;
+                }
+                Err(err) => warn!("WebSocket to {}: {}", picked.origin(), err),
+            }
+        }
+        // the lease counts the tunnel as active until it's closed
+        let (mut upstream, target, _lease) = match connected {
+            Some(connected) => connected,
+            None => {
+                write_response(&mut client, target.settings.unavailable()).await?;
+                return Ok(());
+            }
+        };
+
+        // path without prefix of route, as of other requests
+        let url = checked.url();
+        let mut path = format!("{}{}", target.path, url.path());
+        if let Some(query) = url.query() {
+            path = format!("{}?{}", path, query);
+        }
+        let mut upstream_head = format!("{} {} HTTP/1.1\r\n", req.method.unwrap_or("GET"), path);
+        for header in req.headers.iter() {
+            let value = if header.name.eq_ignore_ascii_case("host") {
+                target.host_with_port()
+            } else if header.name.eq_ignore_ascii_case("origin") {
+                target.origin()
+            } else {
+                String::from_utf8_lossy(header.value).to_string()
+            };
+            upstream_head.push_str(&format!("{}: {}\r\n", header.name, value));
+        }
+        upstream_head.push_str("\r\n");
+
+        upstream.write_all(upstream_head.as_bytes()).await?;
+        // bytes the client already sent after the head
+        upstream.write_all(&head[len..]).await?;
+
+        let (client_reader, mut client_writer) = client.split();
+        let (upstream_reader, mut upstream_writer) = upstream.split();
+        let client_to_upstream = io::copy(client_reader, &mut upstream_writer);
+        let upstream_to_client = io::copy(upstream_reader, &mut client_writer);
+        futures::pin_mut!(client_to_upstream, upstream_to_client);
+        match select(client_to_upstream, upstream_to_client).await {
+            Either::Left((r, _)) | Either::Right((r, _)) => r?,
+        };
+        Ok(())
+    }
+
+    // GET and HEAD without body are retried by retry policy of target
+    async fn send_with_retry(
+        &self,
+        req: Request,
+        target: &Target,
+    ) -> http_types::Result<Response> {
+        let retry = match &target.settings.retry {
+            Some(retry)
+                if retry.count > 0
+                    && matches!(req.method(), Method::Get | Method::Head)
+                    && req.len() == Some(0) =>
+            {
+                retry
+            }
+            _ => return self.send(req, target).await,
+        };
+        let mut backoff = Duration::from_millis(retry.backoff);
+        let mut req = req;
+        for _ in 0..retry.count {
+            let next = copy_request(&req);
+            let result = self.send(req, target).await;
+            if !should_retry(retry, &result) {
+                return result;
+            }
+            async_std::task::sleep(backoff).await;
+            backoff *= 2;
+            req = next;
+        }
+        self.send(req, target).await
+    }
+
+    // 504 if target timed out before head of response is received
+    async fn send(&self, req: Request, target: &Target) -> http_types::Result<Response> {
+        let cassette = match &self.cassette {
+            Some(cassette) => cassette,
+            None => return self.exchange_within(req, target).await,
+        };
+        let mut req = req;
+        let key = Cassette::key(&mut req).await?;
+        if cassette.replaying() {
+            return cassette.replay(&key).await;
+        }
+        let mut resp = self.exchange_within(req, target).await?;
+        cassette.record(&key, &mut resp).await?;
+        Ok(resp)
+    }
+
+    async fn exchange_within(&self, req: Request, target: &Target) -> http_types::Result<Response> {
+        let total = target.settings.timeouts.total;
+        timeout::within(total, self.exchange(req, target))
+            .await
+            .map_err(timeout::gateway_timeout)
+    }
+
+    // reuse an idle connection to target if possible,
+    // ttfb is from sending the request to the head of response
+    async fn exchange(&self, req: Request, target: &Target) -> http_types::Result<Response> {
+        let key = target.pool_key();
+        let reusable = req.len() == Some(0) && !is_close(req.header("connection"));
+        let timing = req.ext().get::<Timing>().cloned();
+        let mut req = req;
+        if reusable {
+            if let Some(conn) = self.pool.get(&key) {
+                let retry = copy_request(&req);
+                let start = Instant::now();
+                match async_h1::connect(SkipInterim::new(conn.clone()), req).await {
+                    Ok(mut resp) => {
+                        if let Some(timing) = &timing {
+                            timing.since("ttfb", start);
+                        }
+                        if !is_close(resp.header("connection")) {
+                            self.pool.release(key, conn, &mut resp);
+                        }
+                        return Ok(resp);
+                    }
+                    // connection may be closed by target when idle
+                    Err(_) => req = retry,
+                }
+            }
+        }
+        let stream = target.connect(timing.as_ref()).await?;
+        let conn: Conn = async_dup::Arc::new(async_dup::Mutex::new(stream));
+        let skip = SkipInterim::new(conn.clone());
+        if expects_continue(&req) {
+            // chunked, a body refused by target ends early
+            let body = async_std::io::BufReader::new(skip.gate(req.take_body()));
+            req.set_body(Body::from_reader(body, None));
+        }
+        let start = Instant::now();
+        let mut resp = async_h1::connect(skip, req).await?;
+        if let Some(timing) = &timing {
+            timing.since("ttfb", start);
+        }
+        if reusable && !is_close(resp.header("connection")) {
+            self.pool.release(key, conn, &mut resp);
+        }
+        Ok(resp)
+    }
+
+    // prefix is the path prefix of the matched route, empty for domain
+    async fn request(
+        &self,
+        req: Request,
+        target: &Target,
+        prefix: &str,
+    ) -> http_types::Result<Response> {
+        let lease = match &target.settings.balancer {
+            Some(balancer) => match balancer.pick() {
+                Some(lease) => Some(lease),
+                None => return Ok(target.settings.unavailable()),
+            },
+            None => None,
+        };
+        let picked;
+        let target = match &lease {
+            Some(lease) => {
+                picked = Target {
+                    settings: target.settings.clone(),
+                    ..lease.target().clone()
+                };
+                &picked
+            }
+            None => target,
+        };
+        if let Some(breaker) = &target.settings.breaker {
+            if !breaker.allow(&target.origin()) {
+                let mut resp = target.settings.unavailable();
+                resp.ext_mut().insert(breaker::Open);
+                return Ok(resp);
+            }
+        }
+        let slot = match &self.per_target {
+            Some(per_target) => match per_target.acquire(&target.origin()) {
+                Some(slot) => Some(slot),
+                None => return Ok(Response::new(StatusCode::ServiceUnavailable)),
+            },
+            None => None,
+        };
+        let mirror_url = req.url().clone();
+        let ranged = req.header("range").is_some();
+        let head = req.method() == Method::Head;
+        let accept_encoding = req.header("accept-encoding").map(|i| i.as_str().to_string());
+        let request_origin = req.header("origin").map(|i| i.as_str().to_string());
+        let timing = req.ext().get::<Timing>().cloned();
+        let https = match req.ext().get::<Peer>().copied() {
+            Some(peer) => self.forwarded.proto(&req, peer) == "https",
+            None => mirror_url.scheme() == "https",
+        };
+        let mut req = req;
+        self.map_request_headers(&mut req);
+        if !self.config.pass_accept_encoding {
+            let encodings = &self.config.upstream_encodings;
+            let encodings = accept_encodings(accept_encoding.as_deref(), encodings);
+            req.insert_header("accept-encoding", encodings);
+        }
+        if self.config.request_id.forward {
+            if let Some(RequestId(id)) = req.ext().get::<RequestId>().cloned() {
+                req.insert_header(self.config.request_id.header.as_str(), id);
+            }
+        }
+        if let Some(peer) = req.ext().get::<Peer>().copied() {
+            self.forwarded.apply(&mut req, peer);
+        }
+        let mut body_replacements = self.request_body_replacements(&req);
+        if let Some(replacements) = &body_replacements {
+            if form::rewrite(&mut req, replacements).await? {
+                body_replacements = None;
+            }
+        }
+        let req = target
+            .fuse_request(req, body_replacements)
+            .map_err(|e| http_error(e.to_string()))?;
+        let mirror_host = mirror_url.host_str().unwrap_or_default();
+        let target_host = target.host_with_port();
+        let origin = target.origin();
+        let vars = Vars {
+            mirror: mirror_host,
+            target: &target_host,
+            origin: &origin,
+        };
+        let mut req = req;
+        let rules = &target.settings.options.request_headers;
+        headers::apply(rules, req.as_mut(), mirror_url.path(), &vars);
+        let upstream_url = req.url().clone();
+        let upstream_host = upstream_url.host_str().unwrap_or_default();
+        // cookies of the session in jar are sent instead of the one naming it
+        let mut session = None;
+        if let Some(jar) = &self.jar {
+            let (id, rest) = jar.take_session(req.header("cookie").map(|i| i.as_str()));
+            let stored = id
+                .as_deref()
+                .and_then(|id| jar.cookies(id, upstream_host, upstream_url.path()));
+            let cookie: Vec<_> = rest.into_iter().chain(stored).collect();
+            req.remove_header("cookie");
+            if !cookie.is_empty() {
+                req.insert_header("cookie", cookie.join("; "));
+            }
+            session = id;
+        }
+        let result = self.send_with_retry(req, target).await;
+        let ok = match &result {
+            Ok(resp) => !matches!(u16::from(resp.status()), 502..=504),
+            Err(_) => false,
+        };
+        if let Some(lease) = &lease {
+            if lease.report(ok) {
+                warn!("{} is down", target.origin());
+            }
+        }
+        if let Some(breaker) = &target.settings.breaker {
+            if breaker.report(&target.origin(), ok) {
+                warn!("circuit of {} is open", target.origin());
+            }
+        }
+        let mut resp = result?;
+        if let Some(max_size) = self.config.response_limit.max_size {
+            if resp.len().map_or(false, |len| len as u64 > max_size) {
+                return Err(HttpError::from_str(StatusCode::BadGateway, "response too large"));
+            }
+            // cut when body turns out larger, as head is sent already
+            limit::limit_size(&mut resp, max_size);
+        }
+        if let Some(lease) = lease {
+            lease.attach(&mut resp);
+        }
+        if let Some(slot) = slot {
+            slot.attach(&mut resp);
+        }
+        resp.ext_mut().insert(Upstream(target.origin()));
+        // for timers of body set up below
+        if let Some(timing) = timing {
+            resp.ext_mut().insert(timing);
+        }
+        // framing is written by encoder from the body, its length if known or
+        // chunked, as rewriting and coding below change it, a response to HEAD
+        // has no body to tell the length by
+        resp.remove_header("transfer-encoding");
+        if !head || self.may_change_body(&resp, target) {
+            resp.remove_header("content-length");
+        }
+
+        if let Some(location) = resp.header("location") {
+            let location = self.rewrite_location(location.as_str(), &upstream_url, &mirror_url);
+            resp.insert_header("location", location);
+        }
+
+        if let Some(link) = resp.header("link") {
+            let strip = self.config.canonical == CanonicalPolicy::Strip;
+            let link: Vec<_> = link
+                .iter()
+                .map(|i| self.rewrite_link(i.as_str(), &upstream_url, &mirror_url))
+                .map(|i| if strip { remove_canonical(&i) } else { i })
+                .filter(|i| !i.is_empty())
+                .collect();
+            resp.remove_header("link");
+            for i in link {
+                resp.append_header("link", i);
+            }
+        }
+
+        // trailers of a chunked body come once it's read, see trailers::Trailing
+        if resp.header("trailer").is_some() && !head {
+            let receiver = resp.recv_trailers();
+            resp.ext_mut().insert(trailers::Pending::new(receiver));
+        }
+
+        // alternative services of origin, even with hosts mapped, are not served
+        // by the mirror and browsers would switch to them
+        resp.remove_header("alt-svc");
+
+        if let Some(referer) = resp.header("referer") {
+            let referer = self.replace_domains(referer.as_str());
+            resp.insert_header("referer", referer);
+        }
+
+        for name in &[
+            "content-security-policy",
+            "content-security-policy-report-only",
+        ] {
+            match self.csp {
+                CspPolicy::Keep => (),
+                CspPolicy::Drop => {
+                    resp.remove_header(*name);
+                }
+                CspPolicy::Rewrite => {
+                    if let Some(csp) = resp.header(*name) {
+                        let csp: Vec<_> = csp
+                            .iter()
+                            .map(|i| self.replace_domains(i.as_str()))
+                            .collect();
+                        resp.remove_header(*name);
+                        for i in csp {
+                            resp.append_header(*name, i);
+                        }
+                    }
+                }
+            }
+        }
+
+        if self.config.strip_upgrade_insecure_requests {
+            strip_upgrade_insecure_requests(&mut resp);
+        }
+
+        match self.config.strict_transport_security {
+            HstsPolicy::Keep => (),
+            HstsPolicy::Drop => {
+                resp.remove_header("strict-transport-security");
+            }
+            HstsPolicy::Rewrite => {
+                if let Some(hsts) = resp.header("strict-transport-security") {
+                    let hsts = remove_directives(hsts.as_str(), &["includesubdomains", "preload"]);
+                    resp.insert_header("strict-transport-security", hsts);
+                }
+            }
+        }
+
+        // each cookie in a value of its own, as some targets fold them by commas
+        if let Some(jar) = &self.jar {
+            if let Some(cookie) = resp.remove_header("set-cookie") {
+                let id = match &session {
+                    Some(id) => id.clone(),
+                    None => jar.new_session()?,
+                };
+                for i in cookie.iter().flat_map(|i| cookie::split(i.as_str())) {
+                    jar.store(&id, upstream_host, upstream_url.path(), i);
+                }
+                if session.is_none() {
+                    resp.insert_header("set-cookie", jar.session_cookie(&id, https));
+                }
+            }
+        } else if let Some(cookie) = resp.header("set-cookie") {
+            let cookie: Vec<_> = cookie
+                .iter()
+                .flat_map(|i| cookie::split(i.as_str()))
+                .map(|i| {
+                    let i = cookie::rewrite(
+                        i,
+                        |domain| self.cookie_domain(domain, target, mirror_host),
+                        prefix,
+                        https,
+                        &self.config.cookie,
+                    );
+                    unsafe { HeaderValue::from_bytes_unchecked(i.into_bytes()) }
+                })
+                .collect();
+            resp.insert_header("set-cookie", cookie.as_slice());
+        }
+
+        if let Some(cors) = &target.settings.cors {
+            cors.apply(request_origin.as_deref(), &mut resp);
+        }
+
+        let rules = &target.settings.options.response_headers;
+        headers::apply_response(rules, &mut resp, mirror_url.path(), &vars);
+
+        if resp.status() == StatusCode::NotModified {
+            return Ok(resp);
+        }
+        // offsets of Content-Range are of the body as it is
+        if ranged || resp.status() == StatusCode::PartialContent {
+            return Ok(resp);
+        }
+        if let Some(optimizer) = &target.settings.optimizer {
+            if !head && optimizer.applies(&resp) {
+                optimizer.optimize(&mut resp).await?;
+                return Ok(resp);
+            }
+        }
+        // media and archives go without looking into
+        if self.passthrough(&resp) {
+            return Ok(resp);
+        }
+        // events are streamed as they come, not through Coder
+        if resp.content_type().map_or(false, |i| i.essence() == "text/event-stream") {
+            if resp.header("content-encoding").is_none() && !self.replacements.is_empty() {
+                let body = resp.take_body();
+                let rewriter = Rewriter::by_line(body, self.replacements.clone());
+                Coder::set_body(&mut resp, rewriter);
+            }
+            return Ok(resp);
+        }
+
+        let path = upstream_url.path();
+        self.rewrite_body(&mut resp, target, prefix, path, &vars).await?;
+        if !head {
+            self.compress(&mut resp, accept_encoding.as_deref());
+        }
+
+        Ok(resp)
+    }
+
+    // whether rewrite_body or compress may give the body of resp another length
+    fn may_change_body(&self, resp: &Response, target: &Target) -> bool {
+        let essence = match resp.content_type() {
+            Some(content_type) => content_type.essence().to_string(),
+            None => return false,
+        };
+        if let Some(optimizer) = &target.settings.optimizer {
+            if optimizer.applies(resp) {
+                return true;
+            }
+        }
+        if self.passthrough(resp) {
+            return false;
+        }
+        let compressed = self.config.compress.as_ref().map_or(false, |i| {
+            let content_types = i.content_types.as_ref().unwrap_or(&self.rewrite_content_types);
+            content_types.contains(&essence)
+        });
+        matches!(
+            essence.as_str(),
+            "text/html" | "application/gzip" | "application/x-gzip"
+        ) || compressed
+            || self.rewrite_content_types.contains(&essence)
+            || self
+                .rewrite_rules
+                .iter()
+                .chain(&target.settings.rewrite_rules)
+                .any(|i| i.applies(&essence, &self.rewrite_content_types))
+    }
+
+    // of passthrough_content_types, and not to be rewritten
+    fn passthrough(&self, resp: &Response) -> bool {
+        let content_type = match resp.content_type() {
+            Some(content_type) => content_type,
+            None => return false,
+        };
+        let essence = content_type.essence();
+        !self.rewrite_content_types.iter().any(|i| i == essence)
+            && self
+                .config
+                .passthrough_content_types
+                .iter()
+                .any(|i| match i.strip_suffix("/*") {
+                    Some(kind) => essence.split('/').next() == Some(kind),
+                    None => i == essence,
+                })
+    }
+
+    // compress a response target sent plain, by an encoding the client accepts
+    fn compress(&self, resp: &mut Response, accept_encoding: Option<&str>) {
+        let config = match &self.config.compress {
+            Some(config) => config,
+            None => return,
+        };
+        if resp.header("content-encoding").is_some()
+            || matches!(u16::from(resp.status()), 204 | 304)
+            || resp.len().map_or(false, |len| (len as u64) < config.min_size)
+        {
+            return;
+        }
+        let content_types = config
+            .content_types
+            .as_ref()
+            .unwrap_or(&self.rewrite_content_types);
+        let compressible = resp
+            .content_type()
+            .map_or(false, |i| content_types.iter().any(|t| t == i.essence()));
+        if !compressible {
+            return;
+        }
+        let encoding = match accept_encoding.and_then(|i| negotiate(i, &config.encodings)) {
+            Some(encoding) => encoding,
+            None => return,
+        };
+        resp.insert_header("content-encoding", encoding);
+        resp.append_header("vary", "accept-encoding");
+        // bytes differ from those the strong etag was made for
+        if let Some(etag) = resp.header("etag").map(|i| i.as_str().to_string()) {
+            if etag.starts_with('"') {
+                resp.insert_header("etag", format!("W/{}", etag));
+            }
+        }
+        Coder::En.code(resp);
+        time_body(resp, "encode");
+    }
+
+    // replace domain names, inject snippets and run rewrite rules,
+    // binary bodies are left as they are
+    async fn rewrite_body(
+        &self,
+        resp: &mut Response,
+        target: &Target,
+        prefix: &str,
+        path: &str,
+        vars: &Vars<'_>,
+    ) -> http_types::Result<()> {
+        let content_type = match resp.content_type() {
+            Some(content_type) => content_type,
+            None => return Ok(()),
+        };
+        // gzipped sitemaps are rewritten as xml and gzipped again
+        let gzip = matches!(content_type.essence(), "application/gzip" | "application/x-gzip");
+        let gzipped_xml =
+            gzip && path.ends_with(".xml.gz") && resp.header("content-encoding").is_none();
+        let essence = if gzipped_xml {
+            "application/xml"
+        } else {
+            content_type.essence()
+        };
+        let html = essence == "text/html";
+        let rewrite = self.rewrite_content_types.iter().any(|i| i == essence);
+        let rules: Vec<_> = self
+            .rewrite_rules
+            .iter()
+            .chain(&target.settings.rewrite_rules)
+            .filter(|i| i.applies(essence, &self.rewrite_content_types))
+            .collect();
+        if !html && !rewrite && rules.is_empty() {
+            return Ok(());
+        }
+        // may come with pass_accept_encoding
+        if let Some(encoding) = resp.header("content-encoding") {
+            if !ENCODINGS.contains(&encoding.as_str()) {
+                return Ok(());
+            }
+        }
+
+        Coder::De.code(resp);
+        if gzipped_xml {
+            let body = resp.take_body();
+            Coder::set_body(resp, GzipDecoder::new(body));
+        }
+        time_body(resp, "decode");
+
+        let head = sniff::peek(resp, sniff::HEAD_SIZE).await?;
+        let charset = content_type.param("charset").map(|i| i.to_string());
+        let encoding = charset::detect(charset.as_deref(), html, &head);
+        if sniff::is_binary(&head, encoding) {
+            if gzipped_xml {
+                let body = resp.take_body();
+                Coder::set_body(resp, GzipEncoder::new(body));
+            }
+            Coder::En.code(resp);
+            return Ok(());
+        }
+        // ranges would not match the rewritten body
+        resp.remove_header("accept-ranges");
+        // rewritten as utf-8, charset of Content-Type overrides <meta charset>
+        if encoding != UTF_8 {
+            let body = resp.take_body();
+            Coder::set_body(resp, Transcoder::new(body, encoding));
+            let mime = format!("{}; charset=utf-8", essence);
+            resp.set_content_type(mime.parse()?);
+        }
+
+        // applied after domain names are replaced
+        let mut replacements = Vec::new();
+        if rewrite {
+            replacements.extend(path_replacements(target, vars.mirror, prefix, html));
+        }
+        if rewrite && !prefix.is_empty() {
+            // attributes of html are left to HtmlRewriter
+            let forms: &[&str] = if html {
+                &["url(", "@import "]
+            } else {
+                &["href=", "src=", "action=", "url(", "@import "]
+            };
+            replacements.extend(relative_replacements(prefix, forms));
+        }
+        if html {
+            replacements.extend(self.injections(target));
+        }
+        if rewrite && !self.replacements.is_empty() {
+            let body = resp.take_body();
+            Coder::set_body(resp, Rewriter::new(body, self.replacements.clone()));
+        }
+        if !replacements.is_empty() {
+            let body = resp.take_body();
+            let replacements = Arc::new(Replacements::new(replacements));
+            Coder::set_body(resp, Rewriter::new(body, replacements));
+        }
+        let strip_integrity = self.config.strip_integrity;
+        let strip_canonical = self.config.canonical == CanonicalPolicy::Strip;
+        let json = rewrite && !self.replacements.is_empty();
+        if html && (!prefix.is_empty() || strip_integrity || strip_canonical || json) {
+            let prefix = prefix.to_string();
+            let body = resp.take_body();
+            let rewriter = HtmlRewriter::new(body, move |url| {
+                if !prefix.is_empty() && url.starts_with('/') && !url.starts_with("//") {
+                    Some(format!("{}{}", prefix, url))
+                } else {
+                    None
+                }
+            });
+            let mut rewriter = rewriter
+                .strip_integrity(strip_integrity)
+                .strip_canonical(strip_canonical);
+            if json {
+                rewriter = rewriter.json_replacements(self.replacements.clone());
+            }
+            Coder::set_body(resp, rewriter);
+        }
+        if !rules.is_empty() {
+            let max_size = self.config.response_limit.max_size.unwrap_or(RULES_MAX_SIZE);
+            let mut body = resp.take_body();
+            let mut buf = Vec::new();
+            (&mut body).take(max_size + 1).read_to_end(&mut buf).await?;
+            if buf.len() as u64 > max_size {
+                debug!("{} too large for rewrite rules, sent without them", path);
+                Coder::set_body(resp, io::Cursor::new(buf).chain(body));
+            } else {
+                resp.set_body(rewrite::apply_rules(&rules, buf, vars));
+            }
+        }
+        if gzipped_xml {
+            let body = resp.take_body();
+            Coder::set_body(resp, GzipEncoder::new(body));
+        }
+        let prefetch = self.cache().map_or(false, |i| i.prefetch().is_some());
+        if html && prefetch && resp.status() == StatusCode::Ok {
+            prefetch::attach(resp);
+        }
+        time_body(resp, "rewrite");
+
+        Coder::En.code(resp);
+        if resp.header("content-encoding").is_some() {
+            time_body(resp, "encode");
+        }
+        Ok(())
+    }
+}
+
+// bytes of a body read whole for rewrite rules, without max_size of response_limit
+const RULES_MAX_SIZE: u64 = 16 * 1024 * 1024;
+
+// entries of a Link header without those of rel=canonical
+fn remove_canonical(link: &str) -> String {
+    let mut entries = Vec::new();
+    let mut start = 0;
+    for (i, _) in link.match_indices(',') {
+        if link[i + 1..].trim_start().starts_with('<') {
+            entries.push(&link[start..i]);
+            start = i + 1;
+        }
+    }
+    entries.push(&link[start..]);
+    entries
+        .into_iter()
+        .map(|i| i.trim())
+        .filter(|entry| {
+            let params = entry.rfind('>').map_or("", |i| &entry[i + 1..]);
+            !params.split(';').any(|param| {
+                let param = param.trim();
+                let rel = match param.find('=') {
+                    Some(i) if param[..i].trim().eq_ignore_ascii_case("rel") => &param[i + 1..],
+                    _ => return false,
+                };
+                rel.trim_matches(|c: char| c == '"' || c.is_whitespace())
+                    .split_whitespace()
+                    .any(|i| i.eq_ignore_ascii_case("canonical"))
+            })
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// `;` separated directives of a header without those named, case insensitive
+fn remove_directives(value: &str, names: &[&str]) -> String {
+    value
+        .split(';')
+        .map(|i| i.trim())
+        .filter(|i| {
+            let name = i.split_whitespace().next().unwrap_or_default();
+            !i.is_empty() && !names.iter().any(|n| n.eq_ignore_ascii_case(name))
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+// browsers would leave a mirror on http for the https one
+fn strip_upgrade_insecure_requests(resp: &mut Response) {
+    for name in &[
+        "content-security-policy",
+        "content-security-policy-report-only",
+    ] {
+        let csp: Vec<_> = match resp.header(*name) {
+            Some(csp) => csp
+                .iter()
+                .map(|i| remove_directives(i.as_str(), &["upgrade-insecure-requests"]))
+                .filter(|i| !i.is_empty())
+                .collect(),
+            None => continue,
+        };
+        resp.remove_header(*name);
+        for i in csp {
+            resp.append_header(*name, i);
+        }
+    }
+}
+
+// by Accept-Encoding, an encoding is accepted if its q or that of `*` is above 0
+fn accepts(accept_encoding: &str, encoding: &str) -> bool {
+    let accepted: Vec<(&str, f32)> = accept_encoding
+        .split(',')
+        .filter_map(|i| {
+            let mut parts = i.split(';');
+            let name = parts.next()?.trim();
+            let q = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse().ok())
+                .unwrap_or(1.0);
+            Some((name, q))
+        })
+        .collect();
+    accepted
+        .iter()
+        .find(|(i, _)| i.eq_ignore_ascii_case(encoding))
+        .or_else(|| accepted.iter().find(|(i, _)| *i == "*"))
+        .map_or(false, |(_, q)| *q > 0.0)
+}
+
+// first of encodings the client accepts
+fn negotiate<'a>(accept_encoding: &str, encodings: &'a [String]) -> Option<&'a str> {
+    encodings
+        .iter()
+        .map(|i| i.as_str())
+        .find(|i| accepts(accept_encoding, i))
+}
+
+// Accept-Encoding toward target, of encodings the client accepts too,
+// as the body may be sent to client in the same encoding
+fn accept_encodings(accept_encoding: Option<&str>, encodings: &[String]) -> String {
+    let accept_encoding = accept_encoding.unwrap_or_default();
+    let encodings: Vec<_> = encodings
+        .iter()
+        .map(|i| i.as_str())
+        .filter(|i| accepts(accept_encoding, i))
+        .collect();
+    if encodings.is_empty() {
+        return "identity".to_string();
+    }
+    encodings.join(", ")
+}
+
+enum Coder {
+    De,
+    En,
+}
+
+impl Coder {
+    // of unknown length, sent chunked
+    fn set_body<T>(resp: &mut Response, coder: T)
+    where
+        T: AsyncRead + Unpin + Send + Sync + 'static,
+    {
+        let coder = async_std::io::BufReader::new(coder);
+        let body = Body::from_reader(coder, None);
+        resp.set_body(body);
+    }
+
+    fn code(&self, resp: &mut Response) {
+        if let Some(encoding) = resp.header("content-encoding") {
+            let encoding = encoding.as_str();
+            match encoding {
+                "gzip" => {
+                    let body = resp.take_body();
+                    match self {
+                        Coder::En => Coder::set_body(resp, GzipEncoder::new(body)),
+                        Coder::De => Coder::set_body(resp, GzipDecoder::new(body)),
+                    }
+                }
+                "br" => {
+                    let body = resp.take_body();
+                    match self {
+                        Coder::En => Coder::set_body(resp, BrotliEncoder::new(body)),
+                        Coder::De => Coder::set_body(resp, BrotliDecoder::new(body)),
+                    }
+                }
+                "deflate" => {
+                    let body = resp.take_body();
+                    match self {
+                        Coder::En => Coder::set_body(resp, DeflateEncoder::new(body)),
+                        Coder::De => Coder::set_body(resp, DeflateDecoder::new(body)),
+                    }
+                }
+                "zstd" => {
+                    let body = resp.take_body();
+                    match self {
+                        Coder::En => Coder::set_body(resp, ZstdEncoder::new(body)),
+                        Coder::De => Coder::set_body(resp, ZstdDecoder::new(body)),
+                    }
+                }
+                e => error!("unhandled encoding: {}", e),
+            }
+        }
+    }
+}
+
+// time spent reading the body as it is now, if the response is timed
+fn time_body(resp: &mut Response, stage: &'static str) {
+    let timing = match resp.ext().get::<Timing>() {
+        Some(timing) => timing.clone(),
+        None => return,
+    };
+    let body = resp.take_body();
+    let len = body.len();
+    let timer = async_std::io::BufReader::new(timing.body(stage, body));
+    resp.set_body(Body::from_reader(timer, len));
+}
+
+fn is_close(connection: Option<&HeaderValues>) -> bool {
+    connection.map_or(false, |i| i.as_str().eq_ignore_ascii_case("close"))
+}
+
+// a request without body, used to retry on another connection
+fn copy_request(req: &Request) -> Request {
+    let mut copy = Request::new(req.method(), req.url().clone());
+    for (name, values) in req.iter() {
+        let values: Vec<_> = values.iter().cloned().collect();
+        copy.insert_header(name.clone(), values.as_slice());
+    }
+    if let Some(timing) = req.ext().get::<Timing>() {
+        copy.ext_mut().insert(timing.clone());
+    }
+    copy
+}
+
+fn expects_continue(req: &Request) -> bool {
+    req.header("expect")
+        .map_or(false, |i| i.as_str().eq_ignore_ascii_case("100-continue"))
+}
+
+// the request of an upgrade head, as checks of a domain see it
+fn upgrade_request(head: &httparse::Request, domain: &str) -> Result<Request> {
+    let method = head
+        .method
+        .unwrap_or("GET")
+        .parse::<Method>()
+        .map_err(|err| anyhow!("{}", err))?;
+    let url = Url::parse(&format!("http://{}/", domain))?.join(head.path.unwrap_or("/"))?;
+    let mut req = Request::new(method, url);
+    for header in head.headers.iter() {
+        if let Ok(value) = HeaderValue::from_bytes(header.value.to_vec()) {
+            req.append_header(header.name, value);
+        }
+    }
+    Ok(req)
+}
+
+// a response to an upgrade request not let through, the connection is closed after it
+async fn write_response<S: Stream>(stream: &mut S, mut resp: Response) -> Result<()> {
+    let body = resp.body_bytes().await.map_err(|err| anyhow!("{}", err))?;
+    let status = resp.status();
+    let mut head = format!("HTTP/1.1 {} {}\r\n", status, status.canonical_reason());
+    for (name, values) in resp.iter() {
+        let name = name.as_str();
+        if name == "content-length" || name == "transfer-encoding" || name == "connection" {
+            continue;
+        }
+        for value in values.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    head.push_str(&format!("content-length: {}\r\n", body.len()));
+    head.push_str("connection: close\r\n\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+// check targets of a domain periodically, until the domain is gone by reload
+fn watch_health(settings: Weak<Settings>) {
+    let task = Task::spawn(async move {
+        while let Some(current) = settings.upgrade() {
+            let (check, balancer) = match (&current.options.health_check, &current.balancer) {
+                (Some(check), Some(balancer)) => (check.clone(), balancer.clone()),
+                _ => break,
+            };
+            for (index, target) in balancer.targets().enumerate() {
+                let target = Target {
+                    settings: current.clone(),
+                    ..target.clone()
+                };
+                let up = check_health(&target, &check).await;
+                if balancer.set_up(index, up) {
+                    if up {
+                        info!("{} is up", target.origin());
+                    } else {
+                        warn!("{} is down", target.origin());
+                    }
+                }
+            }
+            drop(current);
+            async_std::task::sleep(Duration::from_secs(check.interval)).await;
+        }
+    });
+
+    task.detach();
+}
+
+// up if target responds without 5xx
+async fn check_health(target: &Target, check: &HealthCheckConfig) -> bool {
+    let mut url = match Url::parse(&target.origin()) {
+        Ok(url) => url,
+        Err(_) => return false,
+    };
+    url.set_path(&format!("{}{}", target.path, check.path));
+    let method = match check.method {
+        HealthCheckMethod::Head => Method::Head,
+        HealthCheckMethod::Get => Method::Get,
+    };
+    let mut req = Request::new(method, url);
+    req.insert_header("host", target.host());
+    req.insert_header("connection", "close");
+    let limit = target
+        .settings
+        .timeouts
+        .total
+        .or_else(|| Some(Duration::from_secs(check.interval)));
+    let result = timeout::within(limit, async {
+        let conn: Conn = async_dup::Arc::new(async_dup::Mutex::new(target.connect(None).await?));
+        async_h1::connect(SkipInterim::new(conn), req).await
+    })
+    .await;
+    matches!(result, Ok(resp) if !resp.status().is_server_error())
+}
+
+fn should_retry(retry: &RetryConfig, result: &http_types::Result<Response>) -> bool {
+    match result {
+        Ok(resp) => retry.statuses.contains(&u16::from(resp.status())),
+        Err(err) if err.status() == StatusCode::GatewayTimeout => retry.on_timeout,
+        Err(_) => retry.on_error,
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn http_error(error: String) -> HttpError {
+    HttpError::from_str(StatusCode::InternalServerError, error)
+}
+
+// a mirror server, its state is its own so several can run in one process
+pub struct Server {
+    config: Config,
+    forward: RwLock<Arc<Forward>>,
+    layers: Vec<Arc<dyn Layer>>,
+    shutdown: Arc<Shutdown>,
+    // connections of clients, if limited
+    connections: Option<Arc<Slots>>,
+    started: Instant,
+    // serialize changes of domain mapping
+    update: Mutex<()>,
+    // reloaded on SIGHUP
+    config_file: Option<String>,
+    // by name, sharing shutdown
+    sites: Vec<(String, Arc<Server>)>,
+    acme: Option<Arc<Acme>>,
+    // of listen_tls_address, connections are closed while there is none
+    acceptor: Arc<RwLock<Option<TlsAcceptor>>>,
+    // by server name, taking precedence over acceptor
+    certs: Certs,
+}
+
+impl Server {
+    pub fn builder() -> Builder {
+        Builder::new()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn forward(&self) -> Arc<Forward> {
+        self.forward.read().unwrap().clone()
+    }
+
+    pub fn replace_forward(&self, forward: Forward) {
+        let mut forward = forward;
+        let mut current = self.forward.write().unwrap();
+        forward.usage = current.usage.clone();
+        *current = Arc::new(forward);
+    }
+
+    // rebuild domain mapping, f changes a copy of the current config
+    pub fn update<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Config),
+    {
+        let _lock = self.update.lock().unwrap();
+        let mut config = self.forward().config().clone();
+        f(&mut config);
+        config.validate()?;
+        self.replace_forward(Forward::new(&config)?);
+        Ok(())
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    // stop accepting, requests in flight are drained by start
+    pub fn shutdown(&self) {
+        self.shutdown.start();
+    }
+
+    pub fn sites(&self) -> impl Iterator<Item = (&str, &Arc<Server>)> {
+        self.sites.iter().map(|(name, site)| (name.as_str(), site))
+    }
+
+    // sites added or removed take a restart
+    fn reload(&self) -> Result<()> {
+        let file = match &self.config_file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let config = Config::from_file(file)?;
+        let mut forwards = Vec::new();
+        for (name, site) in &self.sites {
+            if let Some(config) = config.sites.get(name) {
+                forwards.push((site, Forward::new(config)?));
+            }
+        }
+        let _lock = self.update.lock().unwrap();
+        self.replace_forward(Forward::new(&config)?);
+        for (site, forward) in forwards {
+            site.replace_forward(forward);
+        }
+        Ok(())
+    }
+
+    // rebuild the domain mapping from the config file on SIGHUP
+    fn watch_reload(self: &Arc<Self>) -> Result<()> {
+        let signals = Signals::new(&[SIGHUP])?;
+        let server = Arc::downgrade(self);
+        std::thread::spawn(move || {
+            for _ in signals.forever() {
+                let server = match server.upgrade() {
+                    Some(server) => server,
+                    None => return,
+                };
+                match server.reload() {
+                    Ok(()) => info!("config reloaded"),
+                    Err(err) => error!("reload config error: {}", err),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    // write a mirror as static files instead of serving, crawled from start,
+    // e.g. `http://mirror.example.com/`
+    pub fn export(&self, start: &str, dir: &str) -> Result<usize> {
+        let start = start.parse()?;
+        let forward = self.forward();
+        smol::run(export::crawl(&forward, start, Path::new(dir)))
+    }
+
+    // serve until SIGINT or SIGTERM, reload on SIGHUP if built from a config file
+    pub fn run(self: &Arc<Self>) -> Result<()> {
+        if self.config_file.is_some() {
+            self.watch_reload()?;
+        }
+        shutdown::watch(self.shutdown.clone())?;
+        // tasks are spawned to a global queue run by every thread
+        let threads = self.config.threads.unwrap_or_else(num_cpus::get).max(1);
+        for _ in 1..threads {
+            std::thread::spawn(|| smol::run(future::pending::<()>()));
+        }
+        smol::run(self.clone().start())
+    }
+
+    // serve with sites until shutdown, then wait for requests in flight
+    pub async fn start(self: Arc<Self>) -> Result<()> {
+        let mut servers = vec![self.clone().serve_all(inherited()?).boxed()];
+        for (_, site) in &self.sites {
+            servers.push(site.clone().serve_all(Vec::new()).boxed());
+        }
+        try_join_all(servers).await?;
+        Ok(())
+    }
+
+    // every listener of config, and those inherited
+    async fn serve_all(self: Arc<Self>, inherited: Vec<Async<TcpListener>>) -> Result<()> {
+        let config = &self.config;
+        let mut listeners = Vec::new();
+        for addr in config.listen_address.as_slice() {
+            listeners.push(self.clone().listen(bind(addr, &config.tcp)?).boxed());
+        }
+        for listener in inherited {
+            listeners.push(self.clone().listen(listener).boxed());
+        }
+        if let Some(unix) = &config.listen_unix {
+            listeners.push(self.clone().listen_unix(unix.clone()).boxed());
+        }
+        if let Some(addrs) = &config.listen_tls_address {
+            if let (Some(cert_file), Some(key_file)) = (&config.cert_file, &config.key_file) {
+                *self.acceptor.write().unwrap() = Some(tls::acceptor(cert_file, key_file)?);
+            }
+            // replaces the one of cert_file once it has a certificate
+            if let Some(acme) = &self.acme {
+                acme::watch(acme.clone(), Arc::downgrade(&self.acceptor));
+            }
+            for addr in addrs.as_slice() {
+                let listener = bind(addr, &config.tcp)?;
+                listeners.push(self.clone().listen_tls(listener).boxed());
+            }
+        }
+        if let Some(redirect) = &config.https_redirect {
+            for addr in redirect.listen_address.as_slice() {
+                let listener = bind(addr, &config.tcp)?;
+                listeners.push(self.clone().listen_redirect(listener).boxed());
+            }
+        }
+        if let Some(addr) = &config.admin_address {
+            listeners.push(admin::listen(self.clone(), addr.clone()).boxed());
+        }
+        try_join_all(listeners).await?;
+
+        let timeout = Duration::from_secs(config.shutdown_timeout);
+        if !self.shutdown.drain(timeout).await {
+            return Err(anyhow!(
+                "shutdown timed out, {} requests in flight",
+                self.shutdown.in_flight()
+            ));
+        }
+        Ok(())
+    }
+
+    pub async fn or_shutdown<F: Future>(&self, fut: F) -> Option<F::Output> {
+        self.shutdown.or_shutdown(fut).await
+    }
+
+    async fn serve(
+        &self,
+        req: Request,
+        peer: Peer,
+        server_name: Option<ServerName>,
+    ) -> http_types::Result<Response> {
+        let guard = self.shutdown.guard();
+        let forward = self.forward();
+        let client = forward.client(&req, peer);
+        let mut req = req;
+        // async-h1 takes host from absolute-form target, or else Host,
+        // normalized so it matches domain_name
+        if let Some(host) = req.url().host_str().and_then(host::normalize) {
+            if req.url().host_str() != Some(host.as_str()) {
+                let _ = req.url_mut().set_host(Some(&host));
+            }
+        }
+        if let Some(resp) = self.acme.as_ref().and_then(|i| i.respond(&req)) {
+            return Ok(resp);
+        }
+        let id = forward.request_id(&req, peer);
+        req.ext_mut().insert(peer);
+        req.ext_mut().insert(ClientAddr(client));
+        req.ext_mut().insert(RequestId(id.clone()));
+        if let Some(server_name) = server_name {
+            req.ext_mut().insert(server_name);
+        }
+        let server_timing = forward.config().server_timing;
+        let timing = if server_timing || log_enabled!(log::Level::Debug) {
+            Some(Timing::new())
+        } else {
+            None
+        };
+        if let Some(timing) = &timing {
+            req.ext_mut().insert(timing.clone());
+        }
+        let page = req.url().clone();
+        let accept_encoding = req.header("accept-encoding").map(|i| i.as_str().to_string());
+        let mut resp = Next::new(&self.layers, &forward).run(req).await?;
+        if let Some(hook) = resp.ext().get::<prefetch::Hook>() {
+            let forward = forward.clone();
+            hook.set(move |html| {
+                let task = Task::spawn(async move {
+                    forward.prefetch(page, accept_encoding, html).await;
+                });
+                task.detach();
+            });
+        }
+        if let Some(refresh) = resp.ext_mut().remove::<cache::Refresh>() {
+            let forward = forward.clone();
+            let task = Task::spawn(async move {
+                forward.refresh(refresh).await;
+            });
+            task.detach();
+        }
+        resp.insert_header(forward.config().request_id.header.as_str(), id.as_str());
+        if let Some(bandwidth) = &forward.bandwidth {
+            bandwidth.throttle(client.ip(), &mut resp);
+        }
+        if let Some(timing) = timing {
+            if server_timing {
+                resp.insert_header("server-timing", timing.header());
+            }
+            timing.attach(&mut resp, id);
+        }
+        if self.shutdown.is_started() {
+            resp.insert_header("connection", "close");
+        }
+        limit::budget(&mut resp);
+        guard.attach(&mut resp);
+        Ok(resp)
+    }
+
+    async fn accept<S: Stream + 'static>(
+        self: Arc<Self>,
+        mut stream: S,
+        peer: Peer,
+        server_name: Option<ServerName>,
+    ) {
+        let _slot = match self.connections.as_ref().map(|i| i.acquire()) {
+            Some(None) => {
+                let _ = stream.write_all(BUSY).await;
+                return;
+            }
+            slot => slot,
+        };
+        // an upgrade may come with any request of the connection
+        let slot = trailers::Slot::default();
+        let stream = Upgrades::new(Trailing::new(stream, slot.clone()));
+        let upgrade = stream.upgrade();
+        let stream = async_dup::Arc::new(async_dup::Mutex::new(stream));
+        let serve = |req| {
+            let server = self.clone();
+            let server_name = server_name.clone();
+            let slot = slot.clone();
+            async move {
+                let mut resp = server.serve(req, peer, server_name).await?;
+                trailers::relay(&mut resp, &slot);
+                Ok(resp)
+            }
+        };
+        if let Err(err) = async_h1::accept(stream.clone(), serve).await {
+            error!("Connection error: {:#?}", err);
+            return;
+        }
+        let head = upgrade.lock().unwrap().take();
+        if let Some(head) = head {
+            let _guard = self.shutdown.guard();
+            let forward = self.forward();
+            if let Err(err) = forward.tunnel(head, stream, peer, server_name).await {
+                error!("WebSocket error: {}", err);
+            }
+        }
+    }
+
+    // client address told by PROXY protocol if enabled, None if the header is bad
+    async fn client_addr<S>(&self, stream: &mut S, peer: SocketAddr) -> Option<SocketAddr>
+    where
+        S: AsyncRead + Unpin,
+    {
+        if !self.config.proxy_protocol {
+            return Some(peer);
+        }
+        match proxy_protocol::read(stream).await {
+            Ok(addr) => Some(addr.unwrap_or(peer)),
+            Err(err) => {
+                error!("PROXY protocol error: {}", err);
+                None
+            }
+        }
+    }
+
+    async fn listen(self: Arc<Self>, listener: Async<TcpListener>) -> Result<()> {
+        while let Some(accepted) = self.or_shutdown(listener.accept()).await {
+            let (mut stream, peer) = accepted?;
+            if let Err(err) = tcp::configure(stream.get_ref(), &self.config.tcp) {
+                error!("Socket option error: {}", err);
+            }
+            let server = self.clone();
+            let task = Task::spawn(async move {
+                if let Some(addr) = server.client_addr(&mut stream, peer).await {
+                    server.accept(stream, Peer { addr, tls: false }, None).await;
+                }
+            });
+
+            task.detach();
+        }
+        Ok(())
+    }
+
+    async fn listen_redirect(self: Arc<Self>, listener: Async<TcpListener>) -> Result<()> {
+        while let Some(accepted) = self.or_shutdown(listener.accept()).await {
+            let (mut stream, peer) = accepted?;
+            if let Err(err) = tcp::configure(stream.get_ref(), &self.config.tcp) {
+                error!("Socket option error: {}", err);
+            }
+            let server = self.clone();
+            let task = Task::spawn(async move {
+                if server.client_addr(&mut stream, peer).await.is_none() {
+                    return;
+                }
+                let stream = async_dup::Arc::new(stream);
+                let endpoint = |req| {
+                    let server = server.clone();
+                    async move { Ok(server.redirect(req)) }
+                };
+                if let Err(err) = async_h1::accept(stream, endpoint).await {
+                    error!("Connection error: {:#?}", err);
+                }
+            });
+
+            task.detach();
+        }
+        Ok(())
+    }
+
+    // to the same host, path and query over https, but acme challenges
+    fn redirect(&self, req: Request) -> Response {
+        if let Some(resp) = self.acme.as_ref().and_then(|i| i.respond(&req)) {
+            return resp;
+        }
+        let redirect = match &self.config.https_redirect {
+            Some(redirect) => redirect,
+            None => return Response::new(StatusCode::NotFound),
+        };
+        let port = redirect.port.or_else(|| {
+            let addr = self.config.listen_tls_address.as_ref()?.as_slice().first()?;
+            addr.parse::<SocketAddr>().ok().map(|i| i.port())
+        });
+        let mut url = req.url().clone();
+        if url.set_scheme("https").is_err() || url.set_port(port.filter(|i| *i != 443)).is_err()
+        {
+            return Response::new(StatusCode::BadRequest);
+        }
+        let status = StatusCode::try_from(redirect.status).unwrap_or(StatusCode::MovedPermanently);
+        let mut resp = Response::new(status);
+        resp.insert_header("location", url.as_str());
+        resp
+    }
+
+    async fn listen_unix(self: Arc<Self>, config: UnixConfig) -> Result<()> {
+        // left behind by a previous run
+        if let Ok(meta) = fs::symlink_metadata(&config.path) {
+            if meta.file_type().is_socket() {
+                fs::remove_file(&config.path)?;
+            }
+        }
+        let listener = Async::<UnixListener>::bind(&config.path)?;
+        fs::set_permissions(&config.path, Permissions::from_mode(config.mode()?))?;
+        // unix peers have no address, seen as loopback by allow, deny and trusted_proxies
+        let peer = SocketAddr::from(([127, 0, 0, 1], 0));
+        while let Some(accepted) = self.or_shutdown(listener.accept()).await {
+            let (mut stream, _) = accepted?;
+            let server = self.clone();
+            let task = Task::spawn(async move {
+                if let Some(addr) = server.client_addr(&mut stream, peer).await {
+                    server.accept(stream, Peer { addr, tls: false }, None).await;
+                }
+            });
+
+            task.detach();
+        }
+        let _ = fs::remove_file(&config.path);
+        Ok(())
+    }
+
+    async fn listen_tls(self: Arc<Self>, listener: Async<TcpListener>) -> Result<()> {
+        while let Some(accepted) = self.or_shutdown(listener.accept()).await {
+            let (mut stream, peer) = accepted?;
+            if let Err(err) = tcp::configure(stream.get_ref(), &self.config.tcp) {
+                error!("Socket option error: {}", err);
+            }
+            let server = self.clone();
+            let task = Task::spawn(async move {
+                let peer = match server.client_addr(&mut stream, peer).await {
+                    Some(peer) => peer,
+                    None => return,
+                };
+                // certificate by server name of ClientHello, read before the handshake,
+                // requests are then routed to it
+                let (hello, name) = match tls::read_hello(&mut stream).await {
+                    Ok(hello) => hello,
+                    Err(_) => return,
+                };
+                let name = name.as_deref().and_then(host::normalize);
+                let acceptor = name
+                    .as_ref()
+                    .and_then(|i| server.certs.get(i))
+                    .or_else(|| server.acceptor.read().unwrap().clone());
+                let acceptor = match acceptor {
+                    Some(acceptor) => acceptor,
+                    None => return,
+                };
+                let stream = match acceptor.accept(Rewind::new(hello, stream)).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        error!("TLS handshake error: {}", err);
+                        return;
+                    }
+                };
+                let peer = Peer { addr: peer, tls: true };
+                server.accept(stream, peer, name.map(ServerName)).await;
+            });
+
+            task.detach();
+        }
+        Ok(())
+    }
+}
+
+// sent as it is to connections over max_connections
+const BUSY: &[u8] =
+    b"HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+
+// builds a Server from a config file, or option by option when embedded
+pub struct Builder {
+    config: Config,
+    config_file: Option<String>,
+    layers: Vec<Arc<dyn Layer>>,
+}
+
+impl Builder {
+    fn new() -> Builder {
+        Builder {
+            config: Config::empty(),
+            config_file: None,
+            layers: Vec::new(),
+        }
+    }
+
+    // for overriding options of a config file
+    pub fn config_mut(&mut self) -> &mut Config {
+        &mut self.config
+    }
+
+    // replaces options set so far
+    pub fn config(mut self, config: Config) -> Builder {
+        self.config = config;
+        self
+    }
+
+    // replaces options set so far, the file is read again on SIGHUP
+    pub fn config_file(mut self, path: &str) -> Result<Builder> {
+        self.config = Config::from_file(path)?;
+        self.config_file = Some(path.to_string());
+        Ok(self)
+    }
+
+    // mirror domain to target, like domain_name of config file
+    pub fn domain(mut self, mirror: &str, target: &str) -> Builder {
+        let target = DomainName::Target(Targets::One(target.to_string()));
+        self.config.domain_name.insert(mirror.to_string(), target);
+        self
+    }
+
+    pub fn domains<I, K, V>(self, domains: I) -> Builder
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        domains
+            .into_iter()
+            .fold(self, |builder, (k, v)| builder.domain(k.as_ref(), v.as_ref()))
+    }
+
+    // may be called many times
+    pub fn listen(mut self, addr: &str) -> Builder {
+        let mut addrs = self.config.listen_address.as_slice().to_vec();
+        addrs.push(addr.to_string());
+        self.config.listen_address = Targets::Many(addrs);
+        self
+    }
+
+    pub fn socks5(mut self, server: &str) -> Builder {
+        self.config.socks5_server = Some(server.to_string());
+        self
+    }
+
+    // `http://` or `socks5://` url
+    pub fn proxy(mut self, url: &str) -> Builder {
+        self.config.proxy = Some(url.to_string());
+        self
+    }
+
+    pub fn admin(mut self, addr: &str) -> Builder {
+        self.config.admin_address = Some(addr.to_string());
+        self
+    }
+
+    // run after access log and error pages, before forwarding, in the order added
+    pub fn layer<L: Layer>(mut self, layer: L) -> Builder {
+        self.layers.push(Arc::new(layer));
+        self
+    }
+
+    // options are valid and every domain can be set up
+    pub fn check(&self) -> Result<()> {
+        self.config.validate()?;
+        Forward::new(&self.config)?;
+        for site in self.config.sites.values() {
+            Forward::new(site)?;
+        }
+        Ok(())
+    }
+
+    pub fn build(self) -> Result<Arc<Server>> {
+        self.build_with(Arc::new(Shutdown::new()))
+    }
+
+    // sites get the layers added
+    fn build_with(self, shutdown: Arc<Shutdown>) -> Result<Arc<Server>> {
+        let config = self.config;
+        config.validate()?;
+        let mut sites = Vec::new();
+        for (name, site) in &config.sites {
+            let builder = Builder {
+                config: site.clone(),
+                config_file: None,
+                layers: self.layers.clone(),
+            };
+            sites.push((name.clone(), builder.build_with(shutdown.clone())?));
+        }
+        let mut layers: Vec<Arc<dyn Layer>> = Vec::new();
+        if let Some(access_log) = &config.access_log {
+            layers.push(Arc::new(middleware::AccessLog::new(access_log)?));
+        }
+        layers.push(Arc::new(middleware::ErrorPages));
+        layers.extend(self.layers);
+        let acme = match (&config.acme, &config.listen_tls_address) {
+            (Some(acme), Some(_)) => Some(Arc::new(Acme::new(&config, acme)?)),
+            _ => None,
+        };
+        let connections = config
+            .concurrency
+            .max_connections
+            .map(|i| Arc::new(Slots::new(i)));
+        Ok(Arc::new(Server {
+            forward: RwLock::new(Arc::new(Forward::new(&config)?)),
+            config,
+            layers,
+            shutdown,
+            connections,
+            started: Instant::now(),
+            update: Mutex::new(()),
+            config_file: self.config_file,
+            sites,
+            acme,
+            acceptor: Arc::new(RwLock::new(None)),
+            certs: Certs::new(&config.certificates)?,
+        }))
+    }
+}
+
+fn bind(addr: &str, config: &TcpConfig) -> Result<Async<TcpListener>> {
+    let addr: SocketAddr = addr.parse()?;
+    Ok(Async::new(tcp::bind(addr, config)?)?)
+}
+
+// sockets passed by systemd socket activation, served like listen_address
+fn inherited() -> Result<Vec<Async<TcpListener>>> {
+    let pid = std::env::var("LISTEN_PID").ok().and_then(|i| i.parse().ok());
+    if pid != Some(std::process::id()) {
+        return Ok(Vec::new());
+    }
+    let fds: RawFd = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|i| i.parse().ok())
+        .unwrap_or_default();
+    // passed fds start from 3
+    (3..3 + fds)
+        .map(|fd| {
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            Ok(Async::new(listener)?)
+        })
+        .collect()
 }
@@ -0,0 +1,133 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use event_listener::Event;
+use futures::{
+    future::{select, Either},
+    io::AsyncRead,
+};
+use http_types::{Body, Response};
+use signal_hook::{iterator::Signals, SIGINT, SIGTERM};
+
+// shutdown state of a server
+pub struct Shutdown {
+    started: AtomicBool,
+    in_flight: AtomicUsize,
+    event: Event,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown {
+            started: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
+            event: Event::new(),
+        }
+    }
+
+    // stop accepting, true if it was already started
+    pub fn start(&self) -> bool {
+        let started = self.started.swap(true, Ordering::SeqCst);
+        self.event.notify(usize::MAX);
+        started
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.started.load(Ordering::SeqCst)
+    }
+
+    // None if shutdown started before fut completes
+    pub async fn or_shutdown<F: Future>(&self, fut: F) -> Option<F::Output> {
+        let listener = self.event.listen();
+        if self.is_started() {
+            return None;
+        }
+        futures::pin_mut!(fut);
+        match select(fut, listener).await {
+            Either::Left((output, _)) => Some(output),
+            Either::Right(_) => None,
+        }
+    }
+
+    // wait for requests in flight, false if timed out
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while self.in_flight() > 0 {
+            if start.elapsed() > timeout {
+                return false;
+            }
+            async_std::task::sleep(Duration::from_millis(100)).await;
+        }
+        true
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    pub fn guard(self: &Arc<Self>) -> Guard {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        Guard(self.clone())
+    }
+}
+
+// start shutdown on SIGINT or SIGTERM, exit at once on the second one
+pub fn watch(shutdown: Arc<Shutdown>) -> Result<()> {
+    let signals = Signals::new(&[SIGINT, SIGTERM])?;
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            if shutdown.start() {
+                std::process::exit(1);
+            }
+            info!("shutting down");
+        }
+    });
+    Ok(())
+}
+
+// a request in flight
+pub struct Guard(Arc<Shutdown>);
+
+impl Guard {
+    // keep the guard until the body of response is sent
+    pub fn attach(self, resp: &mut Response) {
+        let body = resp.take_body();
+        let len = body.len();
+        let body = Guarded {
+            inner: body,
+            _guard: self,
+        };
+        let body = async_std::io::BufReader::new(body);
+        resp.set_body(Body::from_reader(body, len));
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct Guarded<R> {
+    inner: R,
+    _guard: Guard,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Guarded<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
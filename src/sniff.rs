@@ -0,0 +1,56 @@
+use async_std::io::BufReader;
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use futures::io::{AsyncReadExt, Cursor};
+use http_types::{Body, Response};
+
+// bytes looked at to tell text from binary, and for <meta charset>
+pub const HEAD_SIZE: usize = 1024;
+
+const MAGIC: &[&[u8]] = &[
+    b"\x89PNG",
+    b"\xff\xd8\xff",
+    b"GIF8",
+    b"RIFF",
+    b"%PDF",
+    b"PK\x03\x04",
+    b"\x1f\x8b",
+    b"\x28\xb5\x2f\xfd",
+    b"BZh",
+    b"wOFF",
+    b"wOF2",
+    b"OggS",
+    b"ID3",
+    b"\x7fELF",
+];
+
+// first bytes of body, which is left as it was
+pub async fn peek(resp: &mut Response, n: usize) -> std::io::Result<Vec<u8>> {
+    let mut body = resp.take_body();
+    let len = body.len();
+    let mut head = Vec::with_capacity(n);
+    (&mut body).take(n as u64).read_to_end(&mut head).await?;
+    let reader = Cursor::new(head.clone()).chain(body);
+    resp.set_body(Body::from_reader(BufReader::new(reader), len));
+    Ok(head)
+}
+
+// binary, or not text of encoding
+pub fn is_binary(head: &[u8], encoding: &'static Encoding) -> bool {
+    if MAGIC.iter().any(|i| head.starts_with(i)) {
+        return true;
+    }
+    if encoding == UTF_16LE || encoding == UTF_16BE {
+        return false;
+    }
+    if head.contains(&0) {
+        return true;
+    }
+    if encoding != UTF_8 {
+        return false;
+    }
+    // a char may be cut at the end of head
+    match std::str::from_utf8(head) {
+        Ok(_) => false,
+        Err(err) => err.error_len().is_some(),
+    }
+}
@@ -0,0 +1,39 @@
+use std::{
+    io,
+    mem::ManuallyDrop,
+    net::{SocketAddr, TcpListener, TcpStream},
+    os::unix::io::{AsRawFd, FromRawFd},
+    time::Duration,
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::config::TcpConfig;
+
+// a listener with backlog and SO_REUSEPORT of config
+pub fn bind(addr: SocketAddr, config: &TcpConfig) -> io::Result<TcpListener> {
+    let domain = if addr.is_ipv4() {
+        Domain::ipv4()
+    } else {
+        Domain::ipv6()
+    };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    socket.set_reuse_address(true)?;
+    if config.reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(config.backlog)?;
+    Ok(socket.into_tcp_listener())
+}
+
+// options of a connection of client or to target
+pub fn configure(stream: &TcpStream, config: &TcpConfig) -> io::Result<()> {
+    stream.set_nodelay(config.nodelay)?;
+    if let Some(keepalive) = config.keepalive {
+        // borrows the socket, it's closed by stream
+        let socket = ManuallyDrop::new(unsafe { Socket::from_raw_fd(stream.as_raw_fd()) });
+        socket.set_keepalive(Some(Duration::from_secs(keepalive)))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::io::{AsyncRead, AsyncWrite};
+use http_types::{Error as HttpError, StatusCode};
+use smol::Timer;
+
+use crate::config::TimeoutConfig;
+
+#[derive(Default, Clone, Copy)]
+pub struct Timeouts {
+    pub connect: Option<Duration>,
+    pub read: Option<Duration>,
+    pub write: Option<Duration>,
+    pub total: Option<Duration>,
+}
+
+impl Timeouts {
+    // unset ones of domain fall back to global ones, 0 is no timeout
+    pub fn new(domain: Option<&TimeoutConfig>, global: &TimeoutConfig) -> Timeouts {
+        let pick = |f: fn(&TimeoutConfig) -> Option<u64>, default: u64| {
+            let secs = domain.and_then(f).or_else(|| f(global)).unwrap_or(default);
+            Some(Duration::from_secs(secs)).filter(|i| *i > Duration::from_secs(0))
+        };
+        Timeouts {
+            connect: pick(|i| i.connect, 10),
+            read: pick(|i| i.read, 60),
+            write: pick(|i| i.write, 60),
+            total: pick(|i| i.total, 0),
+        }
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "timed out")
+}
+
+pub async fn within<T, E, F>(duration: Option<Duration>, fut: F) -> Result<T, E>
+where
+    E: From<io::Error>,
+    F: Future<Output = Result<T, E>>,
+{
+    match duration {
+        Some(duration) => async_std::future::timeout(duration, fut)
+            .await
+            .map_err(|_| timed_out())?,
+        None => fut.await,
+    }
+}
+
+// 504 if it is caused by a timeout
+pub fn gateway_timeout(err: HttpError) -> HttpError {
+    let timeout = err
+        .downcast_ref::<io::Error>()
+        .map_or(false, |i| i.kind() == io::ErrorKind::TimedOut);
+    if timeout {
+        HttpError::from_str(StatusCode::GatewayTimeout, "target timed out")
+    } else {
+        err
+    }
+}
+
+// fails a read or write pending longer than its timeout
+pub struct IoTimeout<S> {
+    inner: S,
+    read: Option<Duration>,
+    write: Option<Duration>,
+    read_timer: Option<Timer>,
+    write_timer: Option<Timer>,
+}
+
+impl<S> IoTimeout<S> {
+    pub fn new(inner: S, read: Option<Duration>, write: Option<Duration>) -> IoTimeout<S> {
+        IoTimeout {
+            inner,
+            read,
+            write,
+            read_timer: None,
+            write_timer: None,
+        }
+    }
+}
+
+// timer starts when inner is pending, and is reset once it makes progress
+fn poll_timer<T>(
+    poll: Poll<io::Result<T>>,
+    duration: Option<Duration>,
+    timer: &mut Option<Timer>,
+    cx: &mut Context,
+) -> Poll<io::Result<T>> {
+    if poll.is_ready() {
+        *timer = None;
+        return poll;
+    }
+    if let Some(duration) = duration {
+        let timer = timer.get_or_insert_with(|| Timer::after(duration));
+        if Pin::new(timer).poll(cx).is_ready() {
+            return Poll::Ready(Err(timed_out()));
+        }
+    }
+    Poll::Pending
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IoTimeout<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        poll_timer(poll, this.read, &mut this.read_timer, cx)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IoTimeout<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        poll_timer(poll, this.write, &mut this.write_timer, cx)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_flush(cx);
+        poll_timer(poll, this.write, &mut this.write_timer, cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
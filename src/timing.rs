@@ -0,0 +1,163 @@
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::io::AsyncRead;
+use http_types::{Body, Response};
+
+// durations of the stages of a request, inserted into extensions of request
+// and response, clones share the stages
+#[derive(Clone)]
+pub struct Timing {
+    start: Instant,
+    inner: Arc<Mutex<Stages>>,
+}
+
+#[derive(Default)]
+struct Stages {
+    // in the order first entered, a stage entered again, e.g. by retries, adds up
+    durations: Vec<(&'static str, Duration)>,
+    // sum of what timers of body recorded, to tell the time of a timer
+    // from that of those it reads from
+    body: Duration,
+}
+
+impl Timing {
+    pub fn new() -> Timing {
+        Timing {
+            start: Instant::now(),
+            inner: Arc::new(Mutex::new(Stages::default())),
+        }
+    }
+
+    pub fn record(&self, stage: &'static str, duration: Duration) {
+        self.inner.lock().unwrap().add(stage, duration);
+    }
+
+    pub fn since(&self, stage: &'static str, start: Instant) {
+        self.record(stage, start.elapsed());
+    }
+
+    // value of Server-Timing, with stages known so far and the total
+    pub fn header(&self) -> String {
+        let mut stages = self.stages();
+        stages.push(("total", self.start.elapsed()));
+        stages
+            .iter()
+            .map(|(stage, duration)| format!("{};dur={}", stage, millis(*duration)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    // time spent reading from a decoder, rewriter or encoder, without the
+    // time of timers it reads from
+    pub fn body<R>(&self, stage: &'static str, inner: R) -> Timer<R> {
+        Timer {
+            inner,
+            stage,
+            timing: self.clone(),
+        }
+    }
+
+    // records time to the first byte of body, logs the stages once it is sent,
+    // or the client went away
+    pub fn attach(&self, resp: &mut Response, id: String) {
+        let body = resp.take_body();
+        let len = body.len();
+        let logger = Logger {
+            inner: body,
+            timing: self.clone(),
+            id,
+            first_byte: false,
+        };
+        let logger = async_std::io::BufReader::new(logger);
+        resp.set_body(Body::from_reader(logger, len));
+    }
+
+    fn stages(&self) -> Vec<(&'static str, Duration)> {
+        self.inner.lock().unwrap().durations.clone()
+    }
+}
+
+impl Stages {
+    fn add(&mut self, stage: &'static str, duration: Duration) {
+        match self.durations.iter_mut().find(|(i, _)| *i == stage) {
+            Some((_, total)) => *total += duration,
+            None => self.durations.push((stage, duration)),
+        }
+    }
+}
+
+pub struct Timer<R> {
+    inner: R,
+    stage: &'static str,
+    timing: Timing,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Timer<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let before = self.timing.inner.lock().unwrap().body;
+        let start = Instant::now();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let elapsed = start.elapsed();
+        let mut stages = self.timing.inner.lock().unwrap();
+        let own = elapsed
+            .checked_sub(stages.body - before)
+            .unwrap_or_default();
+        stages.body += own;
+        stages.add(self.stage, own);
+        result
+    }
+}
+
+struct Logger<R> {
+    inner: R,
+    timing: Timing,
+    id: String,
+    first_byte: bool,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Logger<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            if n > 0 && !self.first_byte {
+                self.first_byte = true;
+                self.timing.since("first_byte", self.timing.start);
+            }
+        }
+        result
+    }
+}
+
+impl<R> Drop for Logger<R> {
+    fn drop(&mut self) {
+        let timing = &self.timing;
+        let stages: Vec<_> = timing
+            .stages()
+            .iter()
+            .map(|(stage, duration)| format!("{}={}ms", stage, millis(*duration)))
+            .collect();
+        debug!(
+            "{} {} total={}ms",
+            self.id,
+            stages.join(" "),
+            millis(timing.start.elapsed())
+        );
+    }
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.1}", duration.as_secs_f64() * 1000.0)
+}
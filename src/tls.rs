@@ -0,0 +1,166 @@
+use std::{collections::HashMap, fs, io, time::Duration};
+
+use anyhow::{anyhow, Result};
+use async_native_tls::{Certificate, TlsAcceptor, TlsConnector};
+use futures::io::{AsyncRead, AsyncReadExt};
+use native_tls::Identity;
+
+use crate::{
+    config::{CertificateConfig, TlsConfig},
+    timeout,
+};
+
+// a client not done with its ClientHello by then is dropped
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+// acceptors by server name, picked by ClientHello before the handshake
+#[derive(Default)]
+pub struct Certs {
+    names: HashMap<String, TlsAcceptor>,
+}
+
+impl Certs {
+    pub fn new(certificates: &HashMap<String, CertificateConfig>) -> Result<Certs> {
+        let mut names = HashMap::new();
+        for (name, config) in certificates {
+            let acceptor = acceptor(&config.cert_file, &config.key_file)
+                .map_err(|err| anyhow!("certificate of {}: {}", name, err))?;
+            names.insert(name.to_ascii_lowercase(), acceptor);
+        }
+        Ok(Certs { names })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    // of the name, or else of `*.` and its parent domain
+    pub fn get(&self, name: &str) -> Option<TlsAcceptor> {
+        if let Some(acceptor) = self.names.get(name) {
+            return Some(acceptor.clone());
+        }
+        let parent = name.splitn(2, '.').nth(1)?;
+        self.names.get(&format!("*.{}", parent)).cloned()
+    }
+}
+
+// cert_file and key_file are PEM encoded, key_file must be PKCS #8
+fn identity(cert_file: &str, key_file: &str) -> Result<Identity> {
+    let cert = fs::read(cert_file)?;
+    let key = fs::read(key_file)?;
+    Ok(Identity::from_pkcs8(&cert, &key)?)
+}
+
+pub fn acceptor(cert_file: &str, key_file: &str) -> Result<TlsAcceptor> {
+    let identity = identity(cert_file, key_file)?;
+    let acceptor = native_tls::TlsAcceptor::new(identity)?;
+    Ok(acceptor.into())
+}
+
+// of a certificate chain and PKCS #8 key in memory, PEM encoded
+pub fn acceptor_pem(cert: &str, key: &str) -> Result<TlsAcceptor> {
+    let identity = Identity::from_pkcs8(cert.as_bytes(), key.as_bytes())?;
+    let acceptor = native_tls::TlsAcceptor::new(identity)?;
+    Ok(acceptor.into())
+}
+
+// connector to https targets of a domain
+pub fn connector(config: &TlsConfig) -> Result<TlsConnector> {
+    let mut connector = TlsConnector::new();
+    if let Some(ca_file) = &config.ca_file {
+        let pem = fs::read_to_string(ca_file)?;
+        let certs = pem_certificates(&pem);
+        if certs.is_empty() {
+            return Err(anyhow!("no certificate in {}", ca_file));
+        }
+        for cert in certs {
+            connector = connector.add_root_certificate(Certificate::from_pem(cert.as_bytes())?);
+        }
+    }
+    if let (Some(cert_file), Some(key_file)) = (&config.cert_file, &config.key_file) {
+        connector = connector.identity(identity(cert_file, key_file)?);
+    }
+    if config.insecure {
+        connector = connector
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true);
+    }
+    Ok(connector)
+}
+
+// a bundle has many certificates, Certificate::from_pem takes only the first one
+fn pem_certificates(pem: &str) -> Vec<&str> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+    let mut certs = Vec::new();
+    let mut rest = pem;
+    while let Some(start) = rest.find(BEGIN) {
+        let end = match rest[start..].find(END) {
+            Some(end) => start + end + END.len(),
+            None => break,
+        };
+        certs.push(&rest[start..end]);
+        rest = &rest[end..];
+    }
+    certs
+}
+
+// the first record of a connection, to be replayed, and the server name of
+// ClientHello in it
+pub async fn read_hello<S>(stream: &mut S) -> io::Result<(Vec<u8>, Option<String>)>
+where
+    S: AsyncRead + Unpin,
+{
+    timeout::within(Some(HELLO_TIMEOUT), async {
+        let mut record = vec![0; 5];
+        stream.read_exact(&mut record).await?;
+        // not a handshake, left to the acceptor to fail
+        if record[0] != 0x16 {
+            return Ok((record, None));
+        }
+        let len = u16::from_be_bytes([record[3], record[4]]) as usize;
+        record.resize(5 + len, 0);
+        stream.read_exact(&mut record[5..]).await?;
+        let name = server_name(&record[5..]);
+        Ok((record, name))
+    })
+    .await
+}
+
+// of the host_name entry of server_name extension
+fn server_name(hello: &[u8]) -> Option<String> {
+    if *hello.first()? != 1 {
+        return None;
+    }
+    // type, length, version and random
+    let data = hello.get(4 + 2 + 32..)?;
+    let (_, data) = vector(data, 1)?;
+    let (_, data) = vector(data, 2)?;
+    let (_, data) = vector(data, 1)?;
+    let (mut extensions, _) = vector(data, 2)?;
+    while !extensions.is_empty() {
+        let kind = u16::from_be_bytes([*extensions.get(0)?, *extensions.get(1)?]);
+        let (extension, rest) = vector(extensions.get(2..)?, 2)?;
+        extensions = rest;
+        if kind != 0 {
+            continue;
+        }
+        let (list, _) = vector(extension, 2)?;
+        if *list.first()? != 0 {
+            return None;
+        }
+        let (name, _) = vector(&list[1..], 2)?;
+        return std::str::from_utf8(name).ok().map(|i| i.to_ascii_lowercase());
+    }
+    None
+}
+
+// bytes of a vector with a length of size bytes in front, and what follows
+fn vector(data: &[u8], size: usize) -> Option<(&[u8], &[u8])> {
+    let len = data
+        .get(..size)?
+        .iter()
+        .fold(0, |len, i| len << 8 | *i as usize);
+    let end = size + len;
+    Some((data.get(size..end)?, &data[end..]))
+}
@@ -0,0 +1,193 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures::{
+    future::FutureExt,
+    io::{AsyncRead, AsyncWrite},
+    ready,
+};
+use http_types::{trailers::Receiver, Response};
+
+// trailers of target for a response, in its extensions until it's written
+pub struct Pending(Mutex<Receiver>);
+
+impl Pending {
+    pub fn new(receiver: Receiver) -> Pending {
+        Pending(Mutex::new(receiver))
+    }
+}
+
+// trailers of the response async-h1 is about to write to a connection
+pub type Slot = Arc<Mutex<Option<Receiver>>>;
+
+pub fn relay(resp: &mut Response, slot: &Slot) {
+    if let Some(Pending(receiver)) = resp.ext_mut().remove::<Pending>() {
+        *slot.lock().unwrap() = Some(receiver.into_inner().unwrap());
+    }
+}
+
+// a client connection, async-h1 ends a chunked body without trailers, they're
+// put after its last chunk, by then target has sent them or there are none
+pub struct Trailing<S> {
+    inner: S,
+    slot: Slot,
+    // the response of slot is being written and its framing followed
+    framing: Option<Framing>,
+    line: Vec<u8>,
+    // written by us, not yet by inner
+    out: Vec<u8>,
+}
+
+#[derive(Clone, Copy)]
+enum Framing {
+    Head,
+    Size,
+    // bytes of chunk and its CRLF left
+    Data(usize),
+    Last,
+}
+
+impl<S> Trailing<S> {
+    pub fn new(inner: S, slot: Slot) -> Trailing<S> {
+        Trailing {
+            inner,
+            slot,
+            framing: None,
+            line: Vec::new(),
+            out: Vec::new(),
+        }
+    }
+
+    // bytes following the response are written as they are
+    fn pass(&mut self, bytes: &[u8]) {
+        self.framing = None;
+        self.out.append(&mut self.line);
+        self.out.extend_from_slice(bytes);
+    }
+
+    fn follow(&mut self, buf: &[u8]) {
+        let mut i = 0;
+        while i < buf.len() {
+            let framing = match self.framing {
+                Some(framing) => framing,
+                None => return self.out.extend_from_slice(&buf[i..]),
+            };
+            if let Framing::Data(left) = framing {
+                let n = left.min(buf.len() - i);
+                self.out.extend_from_slice(&buf[i..i + n]);
+                i += n;
+                self.framing = Some(match left - n {
+                    0 => Framing::Size,
+                    left => Framing::Data(left),
+                });
+                continue;
+            }
+            self.line.push(buf[i]);
+            i += 1;
+            match framing {
+                Framing::Head if self.line.ends_with(b"\r\n\r\n") => {
+                    let head = String::from_utf8_lossy(&self.line).to_lowercase();
+                    if !head.contains("\r\ntransfer-encoding: chunked\r\n") {
+                        self.slot.lock().unwrap().take();
+                        return self.pass(&buf[i..]);
+                    }
+                    self.out.append(&mut self.line);
+                    self.framing = Some(Framing::Size);
+                }
+                Framing::Size if self.line.ends_with(b"\r\n") => {
+                    let size = String::from_utf8_lossy(&self.line);
+                    let size = size.trim_end().split(';').next().unwrap_or("");
+                    match usize::from_str_radix(size.trim(), 16) {
+                        Ok(0) => self.framing = Some(Framing::Last),
+                        Ok(size) => {
+                            self.out.append(&mut self.line);
+                            self.framing = Some(Framing::Data(size + 2));
+                        }
+                        Err(_) => {
+                            self.slot.lock().unwrap().take();
+                            return self.pass(&buf[i..]);
+                        }
+                    }
+                }
+                Framing::Last if self.line.ends_with(b"\r\n\r\n") => {
+                    let receiver = self.slot.lock().unwrap().take();
+                    let trailers = receiver.and_then(|mut i| (&mut i).now_or_never());
+                    let last = self.line.split_off(self.line.len() - 2);
+                    self.out.append(&mut self.line);
+                    for (name, values) in trailers.flatten().iter().flat_map(|i| i.iter()) {
+                        for value in values.iter() {
+                            let line = format!("{}: {}\r\n", name, value);
+                            self.out.extend_from_slice(line.as_bytes());
+                        }
+                    }
+                    self.out.extend_from_slice(&last);
+                    self.framing = None;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Trailing<S> {
+    fn poll_out(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.out.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.out))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.out.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Trailing<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Trailing<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_out(cx))?;
+        if this.framing.is_none() && this.slot.lock().unwrap().is_some() {
+            this.framing = Some(Framing::Head);
+            this.line.clear();
+        }
+        if this.framing.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        this.follow(buf);
+        // the rest is written with the next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_out(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_out(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_out(cx))?;
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
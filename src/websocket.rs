@@ -0,0 +1,175 @@
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures::io::{AsyncRead, AsyncWrite};
+
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+pub fn is_upgrade(head: &[u8]) -> bool {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut req = httparse::Request::new(&mut headers);
+    match req.parse(head) {
+        Ok(httparse::Status::Complete(_)) => req.headers.iter().any(|h| {
+            h.name.eq_ignore_ascii_case("upgrade") && h.value.eq_ignore_ascii_case(b"websocket")
+        }),
+        _ => false,
+    }
+}
+
+// replay bytes read ahead before reading from the inner stream
+pub struct Rewind<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Rewind<S> {
+        Rewind {
+            prefix,
+            pos: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if self.pos < self.prefix.len() {
+            let n = buf.len().min(self.prefix.len() - self.pos);
+            buf[..n].copy_from_slice(&self.prefix[self.pos..self.pos + n]);
+            self.pos += n;
+            return Poll::Ready(Ok(n));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+// a connection served by async-h1, the head of each request coming after a
+// response is read ahead, an upgrade ends the stream for async-h1 and is kept
+// for the tunnel, as async-h1 can't hand over a connection
+pub struct Upgrades<S> {
+    inner: S,
+    // bytes read ahead and not yet passed on
+    prefix: Vec<u8>,
+    pos: usize,
+    // a response was written since the last request, the next read starts one
+    boundary: bool,
+    head: Vec<u8>,
+    upgrade: Arc<Mutex<Option<Vec<u8>>>>,
+    // bytes pass through as they are once upgraded
+    upgraded: bool,
+}
+
+impl<S> Upgrades<S> {
+    pub fn new(inner: S) -> Upgrades<S> {
+        Upgrades {
+            inner,
+            prefix: Vec::new(),
+            pos: 0,
+            boundary: true,
+            head: Vec::new(),
+            upgrade: Arc::new(Mutex::new(None)),
+            upgraded: false,
+        }
+    }
+
+    // head of the upgrade request, once async-h1 is done with the connection
+    pub fn upgrade(&self) -> Arc<Mutex<Option<Vec<u8>>>> {
+        self.upgrade.clone()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Upgrades<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        loop {
+            if self.pos < self.prefix.len() {
+                let n = buf.len().min(self.prefix.len() - self.pos);
+                buf[..n].copy_from_slice(&self.prefix[self.pos..self.pos + n]);
+                self.pos += n;
+                return Poll::Ready(Ok(n));
+            }
+            if !self.boundary {
+                return Pin::new(&mut self.inner).poll_read(cx, buf);
+            }
+            let mut chunk = [0; 4096];
+            let n = match Pin::new(&mut self.inner).poll_read(cx, &mut chunk) {
+                Poll::Ready(Ok(n)) => n,
+                other => return other,
+            };
+            self.head.extend_from_slice(&chunk[..n]);
+            // upgrades are GET, heads of others are not waited for
+            let len = self.head.len().min(4);
+            let get = self.head[..len] == b"GET "[..len];
+            let complete = self.head.windows(4).any(|i| i == b"\r\n\r\n");
+            if n > 0 && get && !complete && self.head.len() <= MAX_HEAD_SIZE {
+                continue;
+            }
+            self.boundary = false;
+            let head = std::mem::take(&mut self.head);
+            if complete && is_upgrade(&head) {
+                *self.upgrade.lock().unwrap() = Some(head);
+                self.upgraded = true;
+                return Poll::Ready(Ok(0));
+            }
+            if head.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            self.prefix = head;
+            self.pos = 0;
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Upgrades<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            if n > 0 && !self.upgraded {
+                self.boundary = true;
+            }
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
@@ -0,0 +1,215 @@
+// an origin and mirrors of it on ephemeral ports, each served by threads of
+// their own, and a client sending requests to mirrors as `mirror.test`
+#![allow(dead_code)]
+
+use std::{
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+use async_compression::futures::bufread::{GzipDecoder, GzipEncoder};
+use futures::io::{AsyncReadExt, Cursor};
+use http_types::{Method, Request, Response, StatusCode, Url};
+use smol::{Async, Task};
+use web_jingzi::{
+    config::{Config, DomainName},
+    server::Server,
+};
+
+pub const MIRROR: &str = "mirror.test";
+
+// set once origin has read the start of an upload to `/upload`
+pub static UPLOAD_STARTED: AtomicBool = AtomicBool::new(false);
+
+pub struct Origin {
+    // `http://127.0.0.1:port` or `https://`
+    pub url: String,
+}
+
+// a page linking back to origin, the same gzipped, a redirect to the page,
+// cookies, a binary body, and bytes of an upload with how many times it names
+// origin and mirror
+async fn respond(mut req: Request, origin: &str) -> Response {
+    let page = format!(
+        "<html><body><a href=\"{}/next\">next</a></body></html>",
+        origin
+    );
+    let mut resp = Response::new(StatusCode::Ok);
+    match req.url().path() {
+        "/page" => {
+            resp.insert_header("content-type", "text/html; charset=utf-8");
+            resp.set_body(page);
+        }
+        "/gzip" => {
+            let mut gzipped = Vec::new();
+            let mut encoder = GzipEncoder::new(Cursor::new(page.into_bytes()));
+            encoder.read_to_end(&mut gzipped).await.unwrap();
+            resp.insert_header("content-type", "text/html; charset=utf-8");
+            resp.insert_header("content-encoding", "gzip");
+            resp.set_body(gzipped);
+        }
+        "/redirect" => {
+            resp = Response::new(StatusCode::Found);
+            resp.insert_header("location", format!("{}/page", origin));
+        }
+        "/cookie" => {
+            resp.append_header("set-cookie", "a=1; Domain=127.0.0.1; Path=/");
+            resp.append_header("set-cookie", "b=2; Secure; Path=/");
+        }
+        "/blob" => {
+            resp.insert_header("content-type", "application/octet-stream");
+            resp.set_body(blob(origin));
+        }
+        "/upload" => {
+            let mut body = req.take_body();
+            let mut upload = Vec::new();
+            let mut buf = vec![0; 64 * 1024];
+            loop {
+                let n = body.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                UPLOAD_STARTED.store(true, Ordering::SeqCst);
+                upload.extend_from_slice(&buf[..n]);
+            }
+            let upload = String::from_utf8(upload).unwrap();
+            resp.insert_header("content-type", "text/plain");
+            resp.set_body(format!(
+                "{} {} {}",
+                upload.len(),
+                upload.matches(origin).count(),
+                upload.matches(MIRROR).count()
+            ));
+        }
+        _ => resp = Response::new(StatusCode::NotFound),
+    }
+    resp
+}
+
+// names origin, and is passed through as it is
+pub fn blob(origin: &str) -> Vec<u8> {
+    let mut blob = vec![0u8; 1024];
+    blob.extend_from_slice(origin.as_bytes());
+    blob
+}
+
+pub fn origin(tls: bool) -> Origin {
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let listener = Async::<TcpListener>::bind(addr).unwrap();
+    let addr = listener.get_ref().local_addr().unwrap();
+    let scheme = if tls { "https" } else { "http" };
+    let url = format!("{}://{}", scheme, addr);
+    let acceptor = if tls { Some(acceptor()) } else { None };
+    let origin = url.clone();
+    thread::spawn(move || {
+        smol::run(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                let origin = origin.clone();
+                let task = Task::spawn(async move {
+                    let endpoint = |req| {
+                        let origin = origin.clone();
+                        async move { Ok(respond(req, &origin).await) }
+                    };
+                    match acceptor {
+                        Some(acceptor) => {
+                            let stream = match acceptor.accept(stream).await {
+                                Ok(stream) => stream,
+                                Err(_) => return,
+                            };
+                            let stream = async_dup::Arc::new(async_dup::Mutex::new(stream));
+                            let _ = async_h1::accept(stream, endpoint).await;
+                        }
+                        None => {
+                            let _ = async_h1::accept(async_dup::Arc::new(stream), endpoint).await;
+                        }
+                    }
+                });
+                task.detach();
+            }
+        })
+    });
+    Origin { url }
+}
+
+// of a self-signed certificate, targets need `insecure` of tls
+fn acceptor() -> async_native_tls::TlsAcceptor {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let identity = native_tls::Identity::from_pkcs8(
+        cert.serialize_pem().unwrap().as_bytes(),
+        cert.serialize_private_key_pem().as_bytes(),
+    )
+    .unwrap();
+    native_tls::TlsAcceptor::new(identity).unwrap().into()
+}
+
+// a mirror of origin, options are yaml of the domain besides target, the
+// address it listens on is returned once it accepts connections
+pub fn mirror(origin: &Origin, options: &str) -> SocketAddr {
+    mirror_with(origin, options, |_| {})
+}
+
+// the same with global options set by configure
+pub fn mirror_with<F: FnOnce(&mut Config)>(
+    origin: &Origin,
+    options: &str,
+    configure: F,
+) -> SocketAddr {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .and_then(|i| i.local_addr())
+        .unwrap();
+    let domain = format!("target: {}\n{}", origin.url, options);
+    let domain: DomainName = serde_yaml::from_str(&domain).unwrap();
+    let mut builder = Server::builder().listen(&addr.to_string());
+    builder
+        .config_mut()
+        .domain_name
+        .insert(MIRROR.to_string(), domain);
+    configure(builder.config_mut());
+    let server = builder.build().unwrap();
+    thread::spawn(move || smol::run(server.start()).unwrap());
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    addr
+}
+
+// response and its whole body, which must match Content-Length if there is one
+pub fn get(mirror: SocketAddr, path: &str, accept_encoding: &str) -> (Response, Vec<u8>) {
+    smol::run(async {
+        let stream = Async::<TcpStream>::connect(mirror).await.unwrap();
+        let url = Url::parse(&format!("http://{}{}", MIRROR, path)).unwrap();
+        let mut req = Request::new(Method::Get, url);
+        req.insert_header("accept-encoding", accept_encoding);
+        let mut resp = async_h1::connect(stream, req).await.unwrap();
+        let body = resp.body_bytes().await.unwrap();
+        if let Some(len) = resp.header("content-length") {
+            assert!(resp.header("transfer-encoding").is_none());
+            assert_eq!(len.as_str().parse::<usize>().unwrap(), body.len());
+        }
+        (resp, body)
+    })
+}
+
+// response to HEAD, its body is not read as there is none
+pub fn head(mirror: SocketAddr, path: &str) -> Response {
+    smol::run(async {
+        let stream = Async::<TcpStream>::connect(mirror).await.unwrap();
+        let url = Url::parse(&format!("http://{}{}", MIRROR, path)).unwrap();
+        let mut req = Request::new(Method::Head, url);
+        req.insert_header("accept-encoding", "identity");
+        async_h1::connect(stream, req).await.unwrap()
+    })
+}
+
+pub fn gunzip(body: &[u8]) -> Vec<u8> {
+    smol::run(async {
+        let mut decoded = Vec::new();
+        let mut decoder = GzipDecoder::new(Cursor::new(body));
+        decoder.read_to_end(&mut decoded).await.unwrap();
+        decoded
+    })
+}
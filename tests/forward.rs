@@ -0,0 +1,381 @@
+mod common;
+
+use std::{
+    future::Future,
+    io::{ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    pin::Pin,
+    sync::atomic::Ordering,
+    task::{Context, Poll},
+    thread,
+    time::{Duration, Instant},
+};
+
+use futures::io::AsyncRead;
+use http_types::{Body, Method, Request, StatusCode, Url};
+use smol::{Async, Timer};
+
+use common::{
+    blob, get, gunzip, head, mirror, mirror_with, origin, Origin, MIRROR, UPLOAD_STARTED,
+};
+
+fn text(body: &[u8]) -> String {
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+// whatever the mirror sends back to a raw request until it closes, or is
+// idle for a second on a connection kept alive
+fn raw(mirror: SocketAddr, head: &str) -> String {
+    let mut stream = TcpStream::connect(mirror).unwrap();
+    stream.write_all(head.as_bytes()).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    let mut resp = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => resp.extend_from_slice(&buf[..n]),
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+            Err(err) => panic!("{}", err),
+        }
+    }
+    String::from_utf8_lossy(&resp).to_string()
+}
+
+// an origin answering each request head with what `respond` makes of it
+fn raw_origin(respond: fn(&str) -> String) -> Origin {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut head = Vec::new();
+            let mut buf = [0; 4096];
+            while !head.ends_with(b"\r\n\r\n") {
+                match stream.read(&mut buf[..1]) {
+                    Ok(1) => head.push(buf[0]),
+                    _ => break,
+                }
+            }
+            let resp = respond(&String::from_utf8_lossy(&head).to_lowercase());
+            let _ = stream.write_all(resp.as_bytes());
+        }
+    });
+    Origin { url }
+}
+
+#[test]
+fn rewrites_html() {
+    let origin = origin(false);
+    let mirror = mirror(&origin, "");
+    let (resp, body) = get(mirror, "/page", "identity");
+    assert_eq!(resp.status(), StatusCode::Ok);
+    let body = text(&body);
+    assert!(body.contains("\"http://mirror.test/next\""), "{}", body);
+    assert!(!body.contains("127.0.0.1"), "{}", body);
+}
+
+#[test]
+fn rewrites_compressed_html() {
+    let origin = origin(false);
+    let mirror = mirror(&origin, "");
+    let (resp, body) = get(mirror, "/gzip", "gzip");
+    assert_eq!(resp.status(), StatusCode::Ok);
+    assert_eq!(resp.header("content-encoding").unwrap().as_str(), "gzip");
+    let body = text(&gunzip(&body));
+    assert!(body.contains("\"http://mirror.test/next\""), "{}", body);
+    assert!(!body.contains("127.0.0.1"), "{}", body);
+}
+
+#[test]
+fn rewrites_redirects() {
+    let origin = origin(false);
+    let mirror = mirror(&origin, "");
+    let (resp, _) = get(mirror, "/redirect", "identity");
+    assert_eq!(resp.status(), StatusCode::Found);
+    let location = resp.header("location").unwrap().as_str();
+    assert_eq!(location, "http://mirror.test/page");
+}
+
+#[test]
+fn rewrites_cookies() {
+    let origin = origin(false);
+    let mirror = mirror(&origin, "");
+    let (resp, _) = get(mirror, "/cookie", "identity");
+    let cookies: Vec<_> = resp
+        .header("set-cookie")
+        .unwrap()
+        .iter()
+        .map(|i| i.as_str().to_string())
+        .collect();
+    assert_eq!(cookies.len(), 2, "{:?}", cookies);
+    assert!(cookies.contains(&"a=1; Domain=mirror.test; Path=/".to_string()));
+    // over http browsers would drop it
+    assert!(cookies.contains(&"b=2; Path=/".to_string()));
+}
+
+#[test]
+fn passes_binary_through() {
+    let origin = origin(false);
+    let mirror = mirror(&origin, "");
+    let (resp, body) = get(mirror, "/blob", "identity");
+    assert_eq!(resp.status(), StatusCode::Ok);
+    assert_eq!(body, blob(&origin.url));
+    assert!(resp.header("content-length").is_some());
+}
+
+#[test]
+fn forwards_to_https() {
+    let origin = origin(true);
+    let mirror = mirror(&origin, "tls: { insecure: true }");
+    let (resp, body) = get(mirror, "/page", "identity");
+    assert_eq!(resp.status(), StatusCode::Ok);
+    let body = text(&body);
+    assert!(body.contains("://mirror.test/next\""), "{}", body);
+    assert!(!body.contains("127.0.0.1"), "{}", body);
+}
+
+#[test]
+fn checks_websocket_upgrades() {
+    let origin = origin(false);
+    let mirror = mirror(&origin, "deny_paths: [\"/admin*\"]\nblocked_status: 403");
+    let resp = raw(
+        mirror,
+        "GET /admin/socket HTTP/1.1\r\nHost: mirror.test\r\n\
+         Connection: Upgrade\r\nUpgrade: websocket\r\n\r\n",
+    );
+    assert!(resp.starts_with("HTTP/1.1 403 "), "{}", resp);
+}
+
+#[test]
+fn routes_websocket_upgrades() {
+    // the path target got, as it was taken by the default target otherwise
+    let routed = raw_origin(|head| {
+        let path = head.split(' ').nth(1).unwrap_or("").to_string();
+        format!(
+            "HTTP/1.1 101 Switching Protocols\r\nupgrade: websocket\r\n\
+             connection: upgrade\r\nx-path: {}\r\n\r\n",
+            path
+        )
+    });
+    let origin = origin(false);
+    let mirror = mirror_with(&origin, "", |config| {
+        let domain = format!("target: {}", routed.url);
+        let domain = serde_yaml::from_str(&domain).unwrap();
+        config
+            .domain_name
+            .insert(format!("{}/app/*", MIRROR), domain);
+    });
+    let resp = raw(
+        mirror,
+        "GET /app/socket?a=1 HTTP/1.1\r\nHost: mirror.test\r\n\
+         Connection: Upgrade\r\nUpgrade: websocket\r\n\r\n",
+    );
+    assert!(resp.starts_with("HTTP/1.1 101 "), "{}", resp);
+    assert!(resp.contains("\r\nx-path: /socket?a=1\r\n"), "{}", resp);
+}
+
+#[test]
+fn denies_encoded_paths() {
+    let origin = origin(false);
+    let mirror = mirror(&origin, "deny_paths: [\"/admin*\"]\nblocked_status: 403");
+    for path in &[
+        "/admin",
+        "/%61dmin",
+        "/%61%64min/x",
+        "/page/../admin",
+        "/./%2e%2e/admin",
+    ] {
+        let (resp, _) = get(mirror, path, "identity");
+        assert_eq!(resp.status(), StatusCode::Forbidden, "{}", path);
+    }
+    let (resp, _) = get(mirror, "/page", "identity");
+    assert_eq!(resp.status(), StatusCode::Ok);
+}
+
+// lines naming mirror, the second half is held back until origin has read
+// some of the first, which it can't if the mirror buffers the whole body
+struct Upload {
+    line: &'static [u8],
+    size: usize,
+    sent: usize,
+    since: Instant,
+    wait: Option<Timer>,
+}
+
+impl AsyncRead for Upload {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        while self.sent >= self.size / 2 && !UPLOAD_STARTED.load(Ordering::SeqCst) {
+            if self.since.elapsed() > Duration::from_secs(10) {
+                let err = "origin got nothing of the first half";
+                return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::Other, err)));
+            }
+            let wait = self
+                .wait
+                .get_or_insert_with(|| Timer::after(Duration::from_millis(10)));
+            match Pin::new(wait).poll(cx) {
+                Poll::Ready(_) => self.wait = None,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let n = buf.len().min(self.size - self.sent);
+        for (i, b) in buf[..n].iter_mut().enumerate() {
+            *b = self.line[(self.sent + i) % self.line.len()];
+        }
+        self.sent += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+#[test]
+fn streams_large_uploads() {
+    let origin = origin(false);
+    let mirror = mirror_with(&origin, "", |config| {
+        config.rewrite_request_content_types = vec!["text/plain".to_string()];
+    });
+    let line: &[u8] = b"<a href=\"http://mirror.test/next\">next</a>\n";
+    let lines = 512 * 1024;
+    let upload = Upload {
+        line,
+        size: line.len() * lines,
+        sent: 0,
+        since: Instant::now(),
+        wait: None,
+    };
+    let body = smol::run(async {
+        let stream = Async::<TcpStream>::connect(mirror).await.unwrap();
+        let url = Url::parse(&format!("http://{}/upload", MIRROR)).unwrap();
+        let mut req = Request::new(Method::Post, url);
+        req.insert_header("content-type", "text/plain");
+        // chunked, its length is not known
+        let upload = async_std::io::BufReader::new(upload);
+        req.set_body(Body::from_reader(upload, None));
+        let mut resp = async_h1::connect(stream, req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::Ok);
+        resp.body_string().await.unwrap()
+    });
+    let counts: Vec<usize> = body.split(' ').map(|i| i.parse().unwrap()).collect();
+    let rewritten = line.len() - MIRROR.len() + origin.url.len() - "http://".len();
+    assert_eq!(counts, vec![rewritten * lines, lines, 0], "{}", body);
+}
+
+#[test]
+fn ignores_forwarded_host() {
+    let origin = origin(false);
+    let mirror = mirror(&origin, "");
+    let resp = smol::run(async {
+        let stream = Async::<TcpStream>::connect(mirror).await.unwrap();
+        let url = Url::parse(&format!("http://{}/page", MIRROR)).unwrap();
+        let mut req = Request::new(Method::Get, url);
+        req.insert_header("x-forwarded-host", "other.test");
+        async_h1::connect(stream, req).await.unwrap()
+    });
+    assert_eq!(resp.status(), StatusCode::Ok);
+}
+
+// rewritten bodies are of another length than that of target
+fn assert_chunked(resp: &http_types::Response) {
+    assert!(resp.header("content-length").is_none());
+    let encoding = resp.header("transfer-encoding").map(|i| i.as_str());
+    assert_eq!(encoding, Some("chunked"));
+}
+
+#[test]
+fn frames_rewritten_bodies_chunked() {
+    let origin = origin(false);
+    let mirror = mirror(&origin, "");
+    let (resp, body) = get(mirror, "/page", "identity");
+    assert_chunked(&resp);
+    assert!(text(&body).ends_with("</html>"), "{}", text(&body));
+}
+
+#[test]
+fn frames_recompressed_bodies_chunked() {
+    let origin = origin(false);
+    let mirror = mirror(&origin, "");
+    let (resp, body) = get(mirror, "/gzip", "gzip");
+    assert_eq!(resp.header("content-encoding").unwrap().as_str(), "gzip");
+    assert_chunked(&resp);
+    let body = text(&gunzip(&body));
+    assert!(body.ends_with("</html>"), "{}", body);
+}
+
+#[test]
+fn keeps_length_of_head() {
+    let origin = origin(false);
+    let mirror = mirror(&origin, "");
+    let resp = head(mirror, "/blob");
+    assert_eq!(resp.status(), StatusCode::Ok);
+    let len = resp.header("content-length").unwrap().as_str();
+    assert_eq!(len, blob(&origin.url).len().to_string());
+    assert!(resp.header("transfer-encoding").is_none());
+    let resp = raw(mirror, "HEAD /blob HTTP/1.1\r\nHost: mirror.test\r\n\r\n");
+    assert!(resp.ends_with("\r\n\r\n"), "{}", resp);
+}
+
+#[test]
+fn upgrades_kept_alive_connections() {
+    let origin = origin(false);
+    let mirror = mirror(&origin, "deny_paths: [\"/admin*\"]\nblocked_status: 403");
+    let mut stream = TcpStream::connect(mirror).unwrap();
+    let page = "GET /page HTTP/1.1\r\nHost: mirror.test\r\nAccept-Encoding: identity\r\n\r\n";
+    stream.write_all(page.as_bytes()).unwrap();
+    let mut resp = Vec::new();
+    let mut buf = [0; 4096];
+    // the rewritten page is chunked
+    while !resp.ends_with(b"0\r\n\r\n") {
+        let n = stream.read(&mut buf).unwrap();
+        assert!(n > 0, "{}", String::from_utf8_lossy(&resp));
+        resp.extend_from_slice(&buf[..n]);
+    }
+    let upgrade = "GET /admin/socket HTTP/1.1\r\nHost: mirror.test\r\n\
+                   Connection: Upgrade\r\nUpgrade: websocket\r\n\r\n";
+    stream.write_all(upgrade.as_bytes()).unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 403 "), "{}", resp);
+}
+
+#[test]
+fn forwards_expect_continue() {
+    let origin = raw_origin(|head| {
+        if head.contains("\r\nexpect: 100-continue\r\n") {
+            "HTTP/1.1 417 Expectation Failed\r\ncontent-length: 0\r\n\r\n".to_string()
+        } else {
+            "HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n".to_string()
+        }
+    });
+    let mirror = mirror(&origin, "");
+    // the body is never sent, as target refuses it
+    let resp = raw(
+        mirror,
+        "POST /upload HTTP/1.1\r\nHost: mirror.test\r\n\
+         Expect: 100-continue\r\nContent-Length: 5\r\n\r\n",
+    );
+    assert!(resp.starts_with("HTTP/1.1 417 "), "{}", resp);
+    assert!(!resp.contains("100 Continue"), "{}", resp);
+}
+
+#[test]
+fn relays_trailers() {
+    let origin = raw_origin(|_| {
+        "HTTP/1.1 200 OK\r\ncontent-type: application/octet-stream\r\n\
+         transfer-encoding: chunked\r\ntrailer: x-checksum\r\n\r\n\
+         5\r\nhello\r\n0\r\nx-checksum: abc\r\n\r\n"
+            .to_string()
+    });
+    let mirror = mirror(&origin, "");
+    let resp = raw(mirror, "GET /blob HTTP/1.1\r\nHost: mirror.test\r\n\r\n");
+    assert!(resp.contains("\r\ntrailer: x-checksum\r\n"), "{}", resp);
+    assert!(
+        resp.ends_with("\r\n0\r\nx-checksum: abc\r\n\r\n"),
+        "{}",
+        resp
+    );
+}
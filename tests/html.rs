@@ -0,0 +1,35 @@
+use std::sync::Arc;
+
+use futures::io::{AsyncReadExt, Cursor};
+use web_jingzi::{html::HtmlRewriter, rewrite::Replacements};
+
+fn rewrite(html: &str) -> String {
+    let replacements = Replacements::new(vec![(
+        "https://origin.test".to_string(),
+        "https://mirror.test".to_string(),
+    )]);
+    let mut rewriter = HtmlRewriter::new(Cursor::new(html.as_bytes().to_vec()), |url| {
+        Some(url.replace("https://origin.test", "https://mirror.test"))
+    })
+    .json_replacements(Arc::new(replacements));
+    let mut output = String::new();
+    smol::run(rewriter.read_to_string(&mut output)).unwrap();
+    output
+}
+
+#[test]
+fn rewrites_around_multibyte_tags() {
+    // the 8th byte of each is within a character
+    let html = "<!-- 中文 --><a 中文 href=\"https://origin.test/a\">链接</a>";
+    let output = rewrite(html);
+    assert!(output.contains("<!-- 中文 -->"), "{}", output);
+    assert!(output.contains("https://mirror.test/a"), "{}", output);
+}
+
+#[test]
+fn rewrites_json_scripts() {
+    let html = "<script type=\"application/ld+json\">\
+                {\"url\":\"https:\\u002F\\u002Forigin.test\\u002Fa\"}</script>";
+    let output = rewrite(html);
+    assert!(!output.contains("origin.test"), "{}", output);
+}
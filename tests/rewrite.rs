@@ -0,0 +1,38 @@
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures::{
+    future::FutureExt,
+    io::{AsyncRead, AsyncReadExt, Cursor},
+};
+use web_jingzi::rewrite::{Replacements, Rewriter};
+
+// yields its bytes, then never ends, like a stream of events
+struct Endless(Cursor<Vec<u8>>);
+
+impl AsyncRead for Endless {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match Pin::new(&mut self.0).poll_read(cx, buf) {
+            Poll::Ready(Ok(0)) => Poll::Pending,
+            other => other,
+        }
+    }
+}
+
+#[test]
+fn sends_long_lines_in_parts() {
+    let replacements = Replacements::new(vec![("a".to_string(), "b".to_string())]);
+    let line = vec![b'a'; 100 * 1024];
+    let mut rewriter = Rewriter::by_line(Endless(Cursor::new(line)), Arc::new(replacements));
+    let mut buf = [0; 4096];
+    let n = rewriter.read(&mut buf).now_or_never().unwrap().unwrap();
+    assert!(n > 0);
+    assert!(buf[..n].iter().all(|i| *i == b'b'));
+}